@@ -0,0 +1,118 @@
+//! Inserts into a store per client from several clients at once, against the same number of
+//! inserts from a single client. Stores are locked independently, so given as many cores as
+//! clients, the concurrent inserts should take close to 1/`CLIENTS` of the time.
+//!
+//!     cargo bench --bench concurrent_insert
+//!
+//! Runs the `tectonic-server` cargo builds along with the bench, on a free port and a fresh
+//! `dtf_folder` in the temp dir.
+
+#![feature(test)]
+
+extern crate libtectonic;
+extern crate test;
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use libtectonic::client::Cxn;
+use test::Bencher;
+
+const CLIENTS: usize = 4;
+/// inserts per iteration, split among the clients
+const INSERTS: usize = 4_000;
+const T0: u64 = 1_514_764_800_000;
+
+/// a running `tectonic-server`, killed once the bench is done
+struct Server {
+    child: Child,
+    port: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn start_server(name: &str, threads: usize) -> Server {
+    // target/<profile>/deps/<bench> -> target/<profile>/tectonic-server
+    let exe = env::current_exe().unwrap();
+    let bin = exe.parent().and_then(Path::parent).unwrap().join("tectonic-server");
+
+    let folder = env::temp_dir().join(format!("tectonic-{}-bench", name));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir(&folder).unwrap();
+    let folder = folder.to_str().unwrap().to_owned();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+
+    let child = Command::new(&bin)
+        .args(&["-h", "127.0.0.1", "-p", &port, "-f", &folder])
+        .args(&["-l", &format!("{}/tectonic.log", folder), "-t", &threads.to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|err| panic!("Unable to start {:?}: {:?}", bin, err));
+    let server = Server { child, port };
+
+    for _ in 0..100 {
+        if Cxn::new("127.0.0.1", &server.port).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("tectonic-server isn't listening on {}", server.port);
+}
+
+/// Every client inserts `INSERTS / clients` updates into a store of its own, on a thread of
+/// its own.
+fn bench_clients(b: &mut Bencher, clients: usize) {
+    let server = start_server(&format!("insert-{}", clients), CLIENTS);
+    let mut cxns: Vec<(Cxn, String)> = (0..clients)
+        .map(|i| {
+            let mut cxn = Cxn::new("127.0.0.1", &server.port).unwrap();
+            let dbname = format!("bnc_bench{}_btc", i);
+            cxn.create_db(&dbname).unwrap();
+            (cxn, dbname)
+        })
+        .collect();
+
+    let per_client = INSERTS / clients;
+    let mut ts = T0;
+    b.iter(|| {
+        let handles: Vec<_> = cxns
+            .drain(..)
+            .map(|(mut cxn, dbname)| {
+                thread::spawn(move || {
+                    for i in 0..per_client {
+                        let cmd = format!(
+                            "ADD {}, {}, f, t, 0.001939, 22.85; INTO {}\n",
+                            ts + i as u64,
+                            i,
+                            dbname
+                        );
+                        cxn.cmd(&cmd).unwrap();
+                    }
+                    (cxn, dbname)
+                })
+            })
+            .collect();
+        cxns.extend(handles.into_iter().map(|handle| handle.join().unwrap()));
+        ts += per_client as u64;
+    });
+}
+
+#[bench]
+fn bench_insert_one_client(b: &mut Bencher) {
+    bench_clients(b, 1);
+}
+
+#[bench]
+fn bench_insert_concurrent_clients(b: &mut Bencher) {
+    bench_clients(b, CLIENTS);
+}
@@ -0,0 +1,72 @@
+//! Reads a range spanning a folder of synthetic hourly files.
+//!
+//!     cargo bench --bench range_read
+//!     cargo bench --bench range_read --features parallel
+
+#![feature(test)]
+
+extern crate libtectonic;
+extern crate test;
+
+use std::env;
+use std::fs;
+
+use libtectonic::dtf::{self, Update};
+use libtectonic::storage::utils::{read_files_for_range_seq, scan_files_for_range};
+#[cfg(feature = "parallel")]
+use libtectonic::storage::utils::read_files_for_range_par;
+use test::Bencher;
+
+const N_FILES: u64 = 24;
+const UPS_PER_FILE: u64 = 20_000;
+const HOUR: u64 = 3_600_000;
+const T0: u64 = 1_514_764_800_000;
+
+/// one file per hour, an update every 180ms
+fn synthetic_folder() -> (String, Vec<String>) {
+    let folder = env::temp_dir().join("tectonic-range-bench");
+    let folder = folder.to_str().unwrap().to_owned();
+    let fnames: Vec<String> = (0..N_FILES)
+        .map(|i| format!("{}/bnc_eth_btc-{:02}.dtf", folder, i))
+        .collect();
+    if fnames.iter().all(|fname| fs::metadata(fname).is_ok()) {
+        return (folder, fnames);
+    }
+
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir(&folder).unwrap();
+    for (i, fname) in fnames.iter().enumerate() {
+        let start = T0 + i as u64 * HOUR;
+        let ups: Vec<Update> = (0..UPS_PER_FILE)
+            .map(|j| Update {
+                ts: start + j * HOUR / UPS_PER_FILE,
+                seq: j as u32,
+                is_trade: j % 10 == 0,
+                is_bid: j % 2 == 0,
+                price: 0.07 + (j % 100) as f32 * 0.0001,
+                size: 1. + (j % 7) as f32,
+            })
+            .collect();
+        dtf::encode(fname, "bnc_eth_btc", &ups).unwrap();
+    }
+    (folder, fnames)
+}
+
+#[bench]
+fn bench_scan_folder(b: &mut Bencher) {
+    let (folder, _) = synthetic_folder();
+    b.iter(|| scan_files_for_range(&folder, "bnc_eth_btc", T0, T0 + N_FILES * HOUR).unwrap());
+}
+
+#[bench]
+fn bench_read_files_seq(b: &mut Bencher) {
+    let (_, fnames) = synthetic_folder();
+    b.iter(|| read_files_for_range_seq(&fnames, T0, T0 + N_FILES * HOUR).unwrap());
+}
+
+#[cfg(feature = "parallel")]
+#[bench]
+fn bench_read_files_par(b: &mut Bencher) {
+    let (_, fnames) = synthetic_folder();
+    b.iter(|| read_files_for_range_par(&fnames, T0, T0 + N_FILES * HOUR).unwrap());
+}
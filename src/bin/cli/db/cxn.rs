@@ -1,6 +1,7 @@
 use db::TectonicError;
 use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use byteorder::{BigEndian, /*WriteBytesExt, */ ReadBytesExt};
 use db::insert_command::InsertCommand;
 use std::sync::mpsc::{Receiver, channel};
@@ -12,9 +13,70 @@ use std::time;
 use dtf::{self, UpdateVecInto};
 use std::str;
 
+use native_tls::{Certificate, TlsConnector, TlsStream};
+
+/// TLS options used when the server terminates TLS itself.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// PEM file with an extra trusted root, e.g. a self-signed server certificate
+    pub ca_file: Option<String>,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn tls_connector(tls: &TlsConfig) -> Result<TlsConnector, TectonicError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ref ca_file) = tls.ca_file {
+        let mut pem = vec![];
+        let cert = File::open(ca_file)
+            .and_then(|mut f| f.read_to_end(&mut pem))
+            .ok()
+            .and_then(|_| Certificate::from_pem(&pem).ok());
+        match cert {
+            Some(cert) => { builder.add_root_certificate(cert); }
+            None => {
+                error!("Unable to load CA certificate {}", ca_file);
+                return Err(TectonicError::ConnectionError);
+            }
+        }
+    }
+    builder.build().map_err(|err| {
+        error!("Unable to build TLS connector: {:?}", err);
+        TectonicError::ConnectionError
+    })
+}
+
 
 struct CxnStream {
-    stream: TcpStream,
+    stream: Stream,
 }
 
 impl CxnStream {
@@ -55,7 +117,7 @@ impl CxnStream {
         }
     }
 
-    fn new(stream : TcpStream) -> Self {
+    fn new(stream : Stream) -> Self {
         CxnStream { stream }
     }
 }
@@ -68,6 +130,10 @@ pub struct Cxn {
 
 impl Cxn {
     pub fn new(host: &str, port: &str) -> Result<Cxn, TectonicError> {
+        Cxn::connect(host, port, None)
+    }
+
+    pub fn connect(host: &str, port: &str, tls: Option<&TlsConfig>) -> Result<Cxn, TectonicError> {
         let addr = format!("{}:{}", host, port);
 
         info!("Connecting to {}", addr);
@@ -77,6 +143,20 @@ impl Cxn {
             Err(_) => return Err(TectonicError::ConnectionError)
         };
 
+        let stream = match tls {
+            Some(tls) => {
+                let connector = tls_connector(tls)?;
+                match connector.connect(host, stream) {
+                    Ok(stm) => Stream::Tls(stm),
+                    Err(err) => {
+                        error!("TLS handshake with {} failed: {:?}", addr, err);
+                        return Err(TectonicError::ConnectionError);
+                    }
+                }
+            }
+            None => Stream::Plain(stream),
+        };
+
         Ok(Cxn {
             stream: Arc::new(RwLock::new(CxnStream::new(stream))),
             subscription: None,
@@ -6,7 +6,7 @@ mod cxn;
 mod pool;
 
 pub use self::error::TectonicError;
-pub use db::cxn::Cxn;
+pub use db::cxn::{Cxn, TlsConfig};
 pub use db::pool::CxnPool;
 pub use db::insert_command::InsertCommand;
 
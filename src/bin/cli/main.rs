@@ -4,9 +4,10 @@ extern crate libtectonic;
 #[macro_use] extern crate log;
 extern crate fern;
 extern crate chrono;
+extern crate native_tls;
 
 use clap::{Arg, App};
-use std::{time, str};
+use std::{thread, time, str};
 use std::io::{self, Write};
 
 pub use libtectonic::dtf;
@@ -55,6 +56,18 @@ fn main() {
                 .help("Sets the port to connect to (default 9001)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Connect using TLS"),
+        )
+        .arg(
+            Arg::with_name("ca_file")
+                .long("ca_file")
+                .value_name("CA_FILE")
+                .help("Trust this PEM certificate, e.g. a self-signed server cert (implies --tls)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("s")
                 .short("s")
@@ -71,12 +84,28 @@ fn main() {
                 .help("Benchmark network latency")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("c")
+                .short("c")
+                .long("concurrency")
+                .value_name("CLIENTS")
+                .help("With -b, run that many clients in parallel, each inserting into its own store")
+                .takes_value(true),
+        )
         .get_matches();
 
     let host = matches.value_of("host").unwrap_or("0.0.0.0");
     let port = matches.value_of("port").unwrap_or("9001");
 
-    let mut cxn = db::Cxn::new(host, port).unwrap();
+    let tls = if matches.is_present("tls") || matches.is_present("ca_file") {
+        Some(db::TlsConfig {
+            ca_file: matches.value_of("ca_file").map(String::from),
+        })
+    } else {
+        None
+    };
+
+    let mut cxn = db::Cxn::connect(host, port, tls.as_ref()).unwrap();
 
     if matches.is_present("b") {
         let times = matches
@@ -84,7 +113,10 @@ fn main() {
             .unwrap_or("10")
             .parse::<usize>()
             .unwrap_or(10) + 1;
-        benchmark(&mut cxn, times);
+        match matches.value_of("c").and_then(|c| c.parse::<usize>().ok()) {
+            Some(clients) => benchmark_concurrent(host, port, tls, times, clients),
+            None => benchmark(&mut cxn, times),
+        }
     } else if matches.is_present("s") {
         let dbname = matches.value_of("s").unwrap_or("");
         subscribe(&mut cxn, dbname);
@@ -114,6 +146,35 @@ fn benchmark(cxn: &mut db::Cxn, times: usize) {
     println!("AVG inserts/s: {}", 1. / (avg_ns / 1_000_000_000.));
 }
 
+/// Every client inserts `times` rows into a store of its own, so the total throughput shows
+/// how well inserts into different stores scale on the server.
+fn benchmark_concurrent(host: &str, port: &str, tls: Option<db::TlsConfig>, times: usize, clients: usize) {
+    let t = time::SystemTime::now();
+
+    let handles: Vec<_> = (0..clients).map(|i| {
+        let (host, port, tls) = (host.to_owned(), port.to_owned(), tls.clone());
+        thread::spawn(move || {
+            let mut cxn = db::Cxn::connect(&host, &port, tls.as_ref()).unwrap();
+            let dbname = format!("bnc_bench{}_btc", i);
+            let _create = cxn.cmd(&format!("CREATE {}\n", dbname));
+            for _ in 1..times {
+                let _res = cxn.cmd(&format!(
+                    "ADD 1513922718770, 0, t, f, 0.001939, 22.85; INTO {}\n", dbname
+                ));
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let elapsed = t.elapsed().unwrap();
+    let secs = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.;
+    let total = (clients * (times - 1)) as f32;
+    println!("Clients: {}", clients);
+    println!("Total inserts: {}", total);
+    println!("AVG inserts/s: {}", total / secs);
+}
 
 fn handle_query(cxn: &mut db::Cxn) {
     loop {
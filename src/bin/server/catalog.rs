@@ -0,0 +1,284 @@
+/// Catalog of the dtf files in `dtf_folder`, so that neither queries nor startup have to
+/// open every file's header.
+///
+/// It's kept in memory and mirrored to `<dtf_folder>/catalog.json` at most once a second
+/// while files are written or removed, and on `FLUSH ALL`. When opened, entries are reused
+/// for files whose size and modification time haven't changed, and only the headers of the
+/// other files are read, so a manifest that fell behind only costs reading a few headers.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::{fs, io};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use libtectonic::dtf;
+use serde_json;
+
+/// name of the manifest within `dtf_folder`
+pub const MANIFEST: &str = "catalog.json";
+
+/// least time between two writes of the manifest, in secs
+const SAVE_INTERVAL: u64 = 1;
+
+/// What the catalog knows about a dtf file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub symbol: String,
+    pub count: u64,
+    pub min_ts: u64,
+    pub max_ts: u64,
+    /// size in bytes, to tell whether the entry went stale
+    pub size: u64,
+    /// modification time in ns, to tell whether the entry went stale
+    pub modified: u64,
+}
+
+impl Entry {
+    fn read(fname: &str) -> Result<Entry, io::Error> {
+        let meta = dtf::read_meta(fname)?;
+        let (size, modified) = stat(fname)?;
+        Ok(Entry {
+            symbol: meta.symbol,
+            count: meta.nums,
+            min_ts: meta.min_ts,
+            max_ts: meta.max_ts,
+            size,
+            modified,
+        })
+    }
+}
+
+/// (size, modification time in ns)
+fn stat(fname: &str) -> Result<(u64, u64), io::Error> {
+    let meta = fs::metadata(fname)?;
+    let modified = meta.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs() * 1_000_000_000 + u64::from(t.subsec_nanos()))
+        .unwrap_or(0);
+    Ok((meta.len(), modified))
+}
+
+/// A dtf file holding part of a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub fname: String,
+    pub count: u64,
+    pub min_ts: u64,
+    pub max_ts: u64,
+}
+
+impl Segment {
+    fn new(fname: String, entry: &Entry) -> Segment {
+        Segment {
+            fname,
+            count: entry.count,
+            min_ts: entry.min_ts,
+            max_ts: entry.max_ts,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    folder: String,
+    /// file name within `folder` -> entry
+    entries: Mutex<BTreeMap<String, Entry>>,
+    /// entries changed since the manifest was last written
+    dirty: AtomicBool,
+    /// held while writing the manifest, when it was last written
+    saved: Mutex<Instant>,
+}
+
+impl Catalog {
+    /// Catalogs every dtf file in `folder`, reusing the manifest's entries of unchanged files,
+    /// and saves the manifest.
+    pub fn open(folder: &str) -> Catalog {
+        let manifest = format!("{}/{}", folder, MANIFEST);
+        let cached: BTreeMap<String, Entry> = fs::File::open(&manifest)
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+
+        let mut entries = BTreeMap::new();
+        if let Ok(dir) = fs::read_dir(folder) {
+            for dir_entry in dir.filter_map(Result::ok) {
+                let path = dir_entry.path();
+                if path.extension().map_or(true, |ext| ext != "dtf") {
+                    continue;
+                }
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name.to_owned(),
+                    None => {
+                        error!("Unable to convert filename {:?}", path);
+                        continue;
+                    }
+                };
+                let fname = format!("{}/{}", folder, name);
+                let entry = match cached.get(&name) {
+                    Some(entry) if stat(&fname).ok() == Some((entry.size, entry.modified)) => Ok(entry.clone()),
+                    _ => Entry::read(&fname),
+                };
+                match entry {
+                    Ok(entry) => {
+                        entries.insert(name, entry);
+                    }
+                    Err(err) => warn!("Error parsing metadata for DTF file {}: {:?}", fname, err),
+                }
+            }
+        }
+
+        let catalog = Catalog {
+            folder: folder.to_owned(),
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(true),
+            saved: Mutex::new(Instant::now()),
+        };
+        catalog.sync();
+        catalog
+    }
+
+    /// every symbol with a file
+    pub fn symbols(&self) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        let symbols: BTreeSet<&String> = entries.values().map(|entry| &entry.symbol).collect();
+        symbols.into_iter().cloned().collect()
+    }
+
+    /// files holding `symbol`, sorted by min_ts
+    pub fn segments(&self, symbol: &str) -> Vec<Segment> {
+        let entries = self.entries.lock().unwrap();
+        let mut segments: Vec<Segment> = entries
+            .iter()
+            .filter(|&(_, entry)| entry.symbol == symbol)
+            .map(|(name, entry)| Segment::new(format!("{}/{}", self.folder, name), entry))
+            .collect();
+        segments.sort_by_key(|seg| seg.min_ts);
+        segments
+    }
+
+    /// Re-reads the header of `fname` after it was written or removed, updating the store's
+    /// `segments`. The manifest is written too, unless it was less than `SAVE_INTERVAL` ago
+    /// or another thread is writing it.
+    pub fn refresh(&self, segments: &mut Vec<Segment>, fname: &str) {
+        segments.retain(|seg| seg.fname != fname);
+        let name = match Path::new(fname).file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => return,
+        };
+
+        let entry = Entry::read(fname);
+        {
+            let mut entries = self.entries.lock().unwrap();
+            match entry {
+                Ok(entry) => {
+                    segments.push(Segment::new(fname.to_owned(), &entry));
+                    entries.insert(name, entry);
+                }
+                Err(_) => {
+                    entries.remove(&name);
+                }
+            }
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        segments.sort_by_key(|seg| seg.min_ts);
+
+        if let Ok(saved) = self.saved.try_lock() {
+            if saved.elapsed() >= Duration::from_secs(SAVE_INTERVAL) {
+                self.save(saved);
+            }
+        }
+    }
+
+    /// Writes the manifest if the entries changed since it was last written.
+    pub fn sync(&self) {
+        if let Ok(saved) = self.saved.lock() {
+            self.save(saved);
+        }
+    }
+
+    /// Writes a snapshot of the entries, replacing the old manifest only once it's complete.
+    /// The entries are only locked to take the snapshot, `saved` orders the writes.
+    fn save(&self, mut saved: MutexGuard<Instant>) {
+        // the folder doesn't exist until the first flush
+        if !Path::new(&self.folder).is_dir() {
+            return;
+        }
+        let entries = {
+            let entries = self.entries.lock().unwrap();
+            if !self.dirty.swap(false, Ordering::SeqCst) {
+                return;
+            }
+            entries.clone()
+        };
+        *saved = Instant::now();
+
+        let manifest = format!("{}/{}", self.folder, MANIFEST);
+        let tmp = format!("{}.tmp", manifest);
+        let result = fs::File::create(&tmp)
+            .and_then(|file| serde_json::to_writer(file, &entries).map_err(io::Error::from))
+            .and_then(|_| fs::rename(&tmp, &manifest));
+        if let Err(err) = result {
+            error!("Unable to save the catalog {}: {:?}", manifest, err);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for Catalog {
+    fn drop(&mut self) {
+        self.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use libtectonic::dtf::Update;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: 0, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_keep_manifest_in_sync() {
+        let folder = env::temp_dir().join("tectonic-catalog-test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir(&folder).unwrap();
+        let folder = folder.to_str().unwrap().to_owned();
+        let fname = format!("{}/bnc_eth_btc.dtf", folder);
+        dtf::encode(&fname, "bnc_eth_btc", &[up(1), up(2)]).unwrap();
+
+        let catalog = Catalog::open(&folder);
+        assert_eq!(vec!["bnc_eth_btc".to_owned()], catalog.symbols());
+        let mut segments = catalog.segments("bnc_eth_btc");
+        assert_eq!(vec![Segment { fname: fname.clone(), count: 2, min_ts: 1, max_ts: 2 }], segments);
+
+        dtf::append(&fname, &[up(3)]).unwrap();
+        catalog.refresh(&mut segments, &fname);
+        assert_eq!(3, segments[0].count);
+
+        // the manifest was just written by `open`, so it's only written again on sync
+        let manifest = format!("{}/{}", folder, MANIFEST);
+        let read_manifest = || -> BTreeMap<String, Entry> {
+            serde_json::from_reader(fs::File::open(&manifest).unwrap()).unwrap()
+        };
+        assert_eq!(2, read_manifest()["bnc_eth_btc.dtf"].count);
+        catalog.sync();
+        let mut cached = read_manifest();
+        assert_eq!(3, cached["bnc_eth_btc.dtf"].count);
+
+        // a stale manifest entry is read again from the file
+        cached.get_mut("bnc_eth_btc.dtf").unwrap().size = 0;
+        serde_json::to_writer(fs::File::create(&manifest).unwrap(), &cached).unwrap();
+        assert_eq!(3, Catalog::open(&folder).segments("bnc_eth_btc")[0].count);
+
+        fs::remove_file(&fname).unwrap();
+        catalog.refresh(&mut segments, &fname);
+        assert!(segments.is_empty());
+        assert!(catalog.symbols().is_empty());
+        assert!(Catalog::open(&folder).symbols().is_empty());
+    }
+}
@@ -1,17 +1,37 @@
 use state::*;
 use parser;
-use libtectonic::dtf::{UpdateVecInto, Update};
+use libtectonic::dtf::Update;
 use std::borrow::{Cow, Borrow};
-
-// BUG: subscribe, add, deadlock!!!
+use std::{fmt, ptr};
+use subscription::Filter;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ReturnType<'thread> {
     String(Cow<'thread, str>),
     Bytes(Vec<u8>),
     Error(Cow<'thread, str>),
+    /// written as one frame per chunk, then an empty frame
+    Chunks(Chunks),
+}
+
+/// A response produced lazily. A failing chunk is written as an error frame and ends it.
+pub struct Chunks(pub Box<Iterator<Item = Result<Vec<u8>, String>>>);
+
+impl fmt::Debug for Chunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chunks")
+    }
+}
+
+/// chunks can only be taken once, so only the same response is equal
+impl PartialEq for Chunks {
+    fn eq(&self, other: &Chunks) -> bool {
+        ptr::eq(self, other)
+    }
 }
 
+impl Eq for Chunks {}
+
 impl<'thread> ReturnType<'thread> {
     pub fn string<S>(string: S) -> ReturnType<'thread>
         where S: Into<Cow<'thread, str>>
@@ -58,26 +78,48 @@ enum Command<'a> {
     Perf,
     BulkAdd,
     BulkAddInto(DbName<'a>),
+    BulkAddRow(Option<Update>),
     BulkAddEnd,
     Get(ReqCount, GetFormat, Range, Loc),
+    GetStream(ReqCount, GetFormat, Range, Loc),
     Count(ReqCount, Loc),
     Clear(ReqCount),
     Flush(ReqCount),
+    FlushStatus,
     AutoFlush(bool),
     Insert(Option<Update>, Option<DbName<'a>>),
     Create(DbName<'a>),
-    Subscribe(DbName<'a>),
-    Unsubscribe(ReqCount),
+    Delete(DbName<'a>, Range),
+    OpenCursor(DbName<'a>, Range),
+    Fetch(Option<(u64, u32)>, GetFormat),
+    CloseCursor(Option<u64>),
+    SetDedupe(DbName<'a>, bool),
+    SetRejectMalformed(DbName<'a>, bool),
+    SetLatePolicy(DbName<'a>, Option<LatePolicy>),
+    SetValidation(DbName<'a>, ValidationRule, Option<Option<u64>>),
+    ShowDatabases,
+    Describe(DbName<'a>),
+    Drop(DbName<'a>, bool),
+    Rename(DbName<'a>, DbName<'a>),
+    Copy(DbName<'a>, DbName<'a>),
+    Subscribe(Vec<DbName<'a>>, Option<Option<u64>>, Option<Filter>),
+    Unsubscribe(Option<DbName<'a>>),
     Subscription,
     Use(DbName<'a>),
     Exists(DbName<'a>),
     Unknown,
 }
 
-static HELP_STR: &str = "PING, INFO, USE [db], CREATE [db],
+static HELP_STR: &str = "PING, INFO, SHOW DATABASES, DESCRIBE [db], USE [db], CREATE [db],
+DELETE FROM [db] FROM [ts] TO [ts], DROP [db] [WITH FILE], RENAME [db] [new db], COPY [db] [new db],
+SET [db] DEDUPE ON|OFF, SET [db] LATE KEEP|DROP|ACCEPT|REJECT, SET [db] REJECT_MALFORMED ON|OFF,
+SET [db] MAX_FUTURE|MAX_PAST [secs]|OFF, SET [db] MAX_SEQ_REGRESSION [n]|OFF,
 ADD [ts],[seq],[is_trade],[is_bid],[price],[size];
 BULKADD ...; DDAKLUB
-FLUSH, FLUSHALL, GETALL, GET [count], CLEAR
+SUBSCRIBE [db|pattern] ... [FROM [ts]] [WHERE is_trade = t AND side = bid AND price >= [x] AND size < [x]]
+UNSUBSCRIBE [db|pattern], UNSUBSCRIBE ALL
+OPEN CURSOR [db] FROM [ts] TO [ts], FETCH [id] [n] [AS JSON|AS CSV], CLOSE [id]
+FLUSH, FLUSHALL, FLUSH STATUS, GETALL, GET [count] ... [STREAM], CLEAR
 ";
 
 /// sometimes returns string, sometimes bytes, error string
@@ -88,9 +130,12 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
     {
     use self::Command::*;
 
+    // another client may have dropped or renamed the current store
+    state.follow_store_changes();
+
     let command: Command = match line.borrow() {
         "" => {
-            if state.is_subscribed {
+            if state.is_subscribed() {
                 Subscription
             } else {
                 Nothing
@@ -100,10 +145,11 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
         "HELP" => Help,
         "INFO" => Info,
         "PERF" => Perf,
+        "SHOW DATABASES" => ShowDatabases,
         "BULKADD" => BulkAdd,
         "DDAKLUB" => BulkAddEnd,
-        "UNSUBSCRIBE" => Unsubscribe(ReqCount::Count(0)),
-        "UNSUBSCRIBE ALL" => Unsubscribe(ReqCount::All),
+        "UNSUBSCRIBE" => Unsubscribe(None),
+        "UNSUBSCRIBE ALL" => Unsubscribe(None),
         "COUNT" => Count(ReqCount::Count(1), Loc::Fs), 
         "COUNT ALL" => Count(ReqCount::All, Loc::Fs),
         "COUNT ALL IN MEM" => Count(ReqCount::All, Loc::Mem),
@@ -114,24 +160,121 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
         "GET ALL" => Get(ReqCount::All, GetFormat::Dtf, None, Loc::Mem),
         "FLUSH" => Flush(ReqCount::Count(1)),
         "FLUSH ALL" => Flush(ReqCount::All),
+        "FLUSH STATUS" => FlushStatus,
         "AUTOFLUSH ON" => AutoFlush(true),
         "AUTOFLUSH Off" => AutoFlush(false),
         _ => {
             // is in bulkadd
-            if state.get_bulkadding() {
-                let parsed = parser::parse_line(&line);
-                let current_db = state.bulkadd_db.clone();
-                let dbname = current_db.unwrap_or(state.current_store_name.clone().into());
-                Insert(parsed, Some(dbname.into()))
+            if state.is_bulkadding() {
+                BulkAddRow(parser::parse_line(&line))
             } else if line.starts_with("BULKADD INTO ") {
                 let (_index, dbname) = parser::parse_dbname(&line);
                 BulkAddInto(dbname.into())
             } else if line.starts_with("SUBSCRIBE ") {
-                let dbname: &str = &line[10..];
-                Subscribe(dbname.into())
+                let args: &str = &line[10..];
+                let (args, filter) = match args.find(" WHERE ") {
+                    Some(index) => (&args[..index], parser::parse_filter(&args[(index + 7)..])),
+                    None => (args, Some(Filter::default())),
+                };
+                // FROM [ts] in seconds, like GET
+                let (dbnames, from_ts) = match args.find(" FROM ") {
+                    Some(index) => (
+                        &args[..index],
+                        Some(args[(index + 6)..].trim().parse::<u64>().ok().map(|ts| ts * 1000)),
+                    ),
+                    None => (args, None),
+                };
+                Subscribe(dbnames.split_whitespace().map(|d| d.into()).collect(), from_ts, filter)
+            } else if line.starts_with("UNSUBSCRIBE ") {
+                let dbname: &str = &line[12..];
+                Unsubscribe(Some(dbname.into()))
             } else if line.starts_with("CREATE ") {
                 let dbname: &str = &line[7..];
                 Create(dbname.into())
+            } else if line.starts_with("DESCRIBE ") {
+                let dbname: &str = &line[9..];
+                Describe(dbname.into())
+            } else if line.starts_with("DROP ") {
+                let dbname: &str = &line[5..];
+                if dbname.ends_with(" WITH FILE") {
+                    Drop(dbname[..(dbname.len() - 10)].into(), true)
+                } else {
+                    Drop(dbname.into(), false)
+                }
+            } else if line.starts_with("DELETE FROM ") {
+                // DELETE FROM [db] FROM [ts] TO [ts], in seconds like GET
+                let args: Vec<&str> = line.split_whitespace().collect();
+                match (args.len(), args.get(3), args.get(5)) {
+                    (7, Some(&"FROM"), Some(&"TO")) => {
+                        let range = match (args[4].parse::<u64>(), args[6].parse::<u64>()) {
+                            (Ok(min_ts), Ok(max_ts)) => Some((min_ts * 1000, max_ts * 1000)),
+                            _ => None,
+                        };
+                        Delete(args[2].into(), range)
+                    }
+                    _ => Unknown,
+                }
+            } else if line.starts_with("OPEN CURSOR ") {
+                // OPEN CURSOR [db] FROM [ts] TO [ts], in seconds like GET
+                let args: Vec<&str> = line.split_whitespace().collect();
+                match (args.len(), args.get(3), args.get(5)) {
+                    (7, Some(&"FROM"), Some(&"TO")) => {
+                        let range = match (args[4].parse::<u64>(), args[6].parse::<u64>()) {
+                            (Ok(min_ts), Ok(max_ts)) => Some((min_ts * 1000, max_ts * 1000)),
+                            _ => None,
+                        };
+                        OpenCursor(args[2].into(), range)
+                    }
+                    _ => Unknown,
+                }
+            } else if line.starts_with("FETCH ") {
+                // FETCH [id] [n] [AS JSON|AS CSV]
+                let args: Vec<&str> = line.split_whitespace().collect();
+                let format = match (args.len(), args.get(3), args.get(4)) {
+                    (3, _, _) => Some(GetFormat::Dtf),
+                    (5, Some(&"AS"), Some(&"JSON")) => Some(GetFormat::Json),
+                    (5, Some(&"AS"), Some(&"CSV")) => Some(GetFormat::Csv),
+                    _ => None,
+                };
+                match format {
+                    Some(format) => {
+                        let args = match (args[1].parse::<u64>(), args[2].parse::<u32>()) {
+                            (Ok(id), Ok(n)) => Some((id, n)),
+                            _ => None,
+                        };
+                        Fetch(args, format)
+                    }
+                    None => Unknown,
+                }
+            } else if line.starts_with("CLOSE ") {
+                let id: &str = &line[6..];
+                CloseCursor(id.trim().parse().ok())
+            } else if line.starts_with("SET ") {
+                // SET [db] DEDUPE ON|OFF, SET [db] LATE KEEP|DROP|ACCEPT|REJECT
+                let args: Vec<&str> = line.split_whitespace().collect();
+                match (args.len(), args.get(2), args.get(3)) {
+                    (4, Some(&"DEDUPE"), Some(&"ON")) => SetDedupe(args[1].into(), true),
+                    (4, Some(&"DEDUPE"), Some(&"OFF")) => SetDedupe(args[1].into(), false),
+                    (4, Some(&"REJECT_MALFORMED"), Some(&"ON")) => SetRejectMalformed(args[1].into(), true),
+                    (4, Some(&"REJECT_MALFORMED"), Some(&"OFF")) => SetRejectMalformed(args[1].into(), false),
+                    (4, Some(&"LATE"), Some(policy)) => {
+                        SetLatePolicy(args[1].into(), policy.to_lowercase().parse().ok())
+                    }
+                    (4, Some(rule), Some(&"OFF")) if rule.parse::<ValidationRule>().is_ok() => {
+                        SetValidation(args[1].into(), rule.parse().unwrap(), Some(None))
+                    }
+                    (4, Some(rule), Some(value)) if rule.parse::<ValidationRule>().is_ok() => {
+                        SetValidation(args[1].into(), rule.parse().unwrap(), value.parse().ok().map(Some))
+                    }
+                    _ => Unknown,
+                }
+            } else if line.starts_with("RENAME ") || line.starts_with("COPY ") {
+                let args: Vec<&str> = line.split_whitespace().collect();
+                match (args[0], args.len()) {
+                    ("RENAME", 3) => Rename(args[1].into(), args[2].into()),
+                    ("COPY", 3) => Copy(args[1].into(), args[2].into()),
+                    _ => Unknown,
+                }
             } else if line.starts_with("USE ") {
                 let dbname: &str = &line[4..];
                 Use(dbname.into())
@@ -174,7 +317,11 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
                 };
                 let loc = if line.contains(" IN MEM") { Loc::Mem } else { Loc::Fs };
 
-                Get(count, format, range, loc)
+                if line.ends_with(" STREAM") {
+                    GetStream(count, format, range, loc)
+                } else {
+                    Get(count, format, range, loc)
+                }
             } else {
                 Unknown
             }
@@ -188,19 +335,24 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
         Info => ReturnType::string(state.info()),
         Perf => ReturnType::string(state.perf()),
         BulkAdd => {
-            state.set_bulkadding(true);
-            state.bulkadd_db = Some(state.current_store_name.clone().into());
+            let dbname = state.current_store_name.clone();
+            state.begin_bulkadd(&dbname);
             ReturnType::string("")
         }
         BulkAddInto(dbname) => {
-            state.bulkadd_db = Some(dbname.into());
-            state.set_bulkadding(true);
+            state.begin_bulkadd(&dbname);
             ReturnType::string("")
         }
+        BulkAddRow(Some(up)) => {
+            state.stage(up);
+            ReturnType::string("")
+        }
+        BulkAddRow(None) => ReturnType::error("Unable to parse line"),
         BulkAddEnd => {
-            state.set_bulkadding(false);
-            state.bulkadd_db = None;
-            ReturnType::string("1")
+            match state.end_bulkadd() {
+                Ok(_) => ReturnType::string("1"),
+                Err(err) => ReturnType::error(err),
+            }
         }
         Count(ReqCount::Count(_), Loc::Fs) => ReturnType::string(format!("{}", state.count())),
         Count(ReqCount::Count(_), Loc::Mem) => ReturnType::string(format!("{}", state.count_in_mem())),
@@ -214,14 +366,15 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
             state.clearall();
             ReturnType::string("1")
         }
-        Flush(ReqCount::Count(_)) => {
-            state.flush();
-            ReturnType::string("1")
-        }
-        Flush(ReqCount::All) => {
-            state.flushall();
-            ReturnType::string("1")
-        }
+        Flush(ReqCount::Count(_)) => match state.flush() {
+            Ok(()) => ReturnType::string("1"),
+            Err(err) => ReturnType::error(err),
+        },
+        Flush(ReqCount::All) => match state.flushall() {
+            Ok(()) => ReturnType::string("1"),
+            Err(err) => ReturnType::error(err),
+        },
+        FlushStatus => ReturnType::string(state.flush_status()),
 
         AutoFlush(is_autoflush) =>  {
             state.set_autoflush(is_autoflush);
@@ -231,13 +384,15 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
         // update, dbname
         Insert(Some(up), Some(dbname)) => {
             match state.insert(up, &dbname) {
-                Some(()) => ReturnType::string(""),
-                None => ReturnType::error(format!("DB {} not found.", dbname)),
+                Ok(()) => ReturnType::string(""),
+                Err(err) => ReturnType::error(err),
             }
         }
         Insert(Some(up), None) => {
-            state.add(up);
-            ReturnType::string("")
+            match state.add(up) {
+                Ok(()) => ReturnType::string(""),
+                Err(err) => ReturnType::error(err),
+            }
         }
         Insert(None, _) => ReturnType::error("Unable to parse line"),
 
@@ -246,29 +401,133 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
             ReturnType::string(format!("Created DB `{}`.", &dbname))
         }
 
-        Subscribe(dbname) => {
-            state.sub(&dbname);
-            ReturnType::string(format!("Subscribed to {}", dbname))
+        Delete(dbname, range) => {
+            let (min_ts, max_ts) = match range {
+                Some(range) => range,
+                None => return ReturnType::error("Unable to parse range."),
+            };
+            match state.delete_range(&dbname, min_ts, max_ts) {
+                Ok(removed) => ReturnType::string(format!("Deleted {} updates from `{}`.", removed, &dbname)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        OpenCursor(dbname, range) => {
+            let (min_ts, max_ts) = match range {
+                Some(range) => range,
+                None => return ReturnType::error("Unable to parse range."),
+            };
+            match state.open_cursor(&dbname, min_ts, max_ts) {
+                Ok(id) => ReturnType::string(id.to_string()),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        Fetch(Some((id, n)), format) => match state.fetch(id, n, format) {
+            Ok(ret) => ret,
+            Err(err) => ReturnType::error(err),
+        },
+        Fetch(None, _) => ReturnType::error("Unable to parse cursor id or count."),
+        CloseCursor(Some(id)) => {
+            if state.close_cursor(id) {
+                ReturnType::string(format!("Closed cursor {}.", id))
+            } else {
+                ReturnType::error(format!("No cursor {}.", id))
+            }
+        }
+        CloseCursor(None) => ReturnType::error("Unable to parse cursor id."),
+        SetDedupe(dbname, dedupe) => {
+            match state.set_dedupe(&dbname, dedupe) {
+                Ok(()) => ReturnType::string(format!("Set DEDUPE {} for `{}`.", if dedupe { "ON" } else { "OFF" }, &dbname)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        SetRejectMalformed(dbname, reject_malformed) => {
+            match state.set_reject_malformed(&dbname, reject_malformed) {
+                Ok(()) => ReturnType::string(format!(
+                    "Set REJECT_MALFORMED {} for `{}`.",
+                    if reject_malformed { "ON" } else { "OFF" },
+                    &dbname
+                )),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        SetLatePolicy(dbname, Some(late_policy)) => {
+            match state.set_late_policy(&dbname, late_policy) {
+                Ok(()) => ReturnType::string(format!("Set LATE {} for `{}`.", late_policy.to_string().to_uppercase(), &dbname)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        SetLatePolicy(_, None) => ReturnType::error("Late policy must be one of KEEP, DROP, ACCEPT or REJECT."),
+        SetValidation(dbname, rule, Some(value)) => {
+            match state.set_validation(&dbname, rule, value) {
+                Ok(()) => ReturnType::string(format!("Set {} for `{}`.", rule, &dbname)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        SetValidation(_, _, None) => ReturnType::error("Limit must be a number or OFF."),
+        ShowDatabases => ReturnType::string(state.show_databases()),
+        Describe(dbname) => {
+            match state.describe(&dbname) {
+                Some(description) => ReturnType::string(description),
+                None => ReturnType::error(format!("DB {} not found.", dbname)),
+            }
+        }
+
+        Drop(dbname, with_file) => {
+            match state.drop_db(&dbname, with_file) {
+                Ok(()) => ReturnType::string(format!("Dropped DB `{}`.", &dbname)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        Rename(from, to) => {
+            match state.rename(&from, &to) {
+                Ok(()) => ReturnType::string(format!("Renamed DB `{}` to `{}`.", &from, &to)),
+                Err(err) => ReturnType::error(err),
+            }
+        }
+        Copy(from, to) => {
+            match state.copy(&from, &to) {
+                Ok(()) => ReturnType::string(format!("Copied DB `{}` to `{}`.", &from, &to)),
+                Err(err) => ReturnType::error(err),
+            }
         }
 
-        Subscription => {
-            let rxlocked = state.rx.clone().unwrap();
-            let message = rxlocked.lock().unwrap().try_recv();
-            match message {
-                Ok(msg) => ReturnType::string(vec![msg].into_json()),
-                _ => ReturnType::string("NONE"),
+        Subscribe(ref dbnames, _, _) if dbnames.is_empty() => ReturnType::error("No db to subscribe to"),
+        Subscribe(_, _, None) => ReturnType::error("Unable to parse filter"),
+        Subscribe(_, Some(None), _) => ReturnType::error("Unable to parse timestamp"),
+        Subscribe(dbnames, None, Some(filter)) => {
+            for dbname in &dbnames {
+                state.sub(dbname, filter.clone());
             }
+            ReturnType::string(format!("Subscribed to {}", dbnames.join(", ")))
         }
+        Subscribe(dbnames, Some(Some(from_ts)), Some(filter)) => {
+            // replay needs concrete stores, a pattern can match stores that don't exist yet
+            if let Some(pattern) = dbnames.iter().find(|d| d.contains('*') || d.contains('?')) {
+                return ReturnType::error(format!("Cannot replay pattern {}", pattern));
+            }
+            if let Some(dbname) = dbnames.iter().find(|d| !state.exists(d)) {
+                return ReturnType::error(format!("DB {} not found.", dbname));
+            }
+            for dbname in &dbnames {
+                state.sub_from(dbname, from_ts, filter.clone());
+            }
+            ReturnType::string(format!("Subscribed to {}", dbnames.join(", ")))
+        }
+
+        // updates are pushed to the client as they are inserted, there is nothing to poll
+        Subscription => ReturnType::string("NONE"),
 
-        Unsubscribe(ReqCount::All) => {
+        Unsubscribe(None) => {
             state.unsub_all();
             ReturnType::string("Unsubscribed everything!")
         }
 
-        Unsubscribe(ReqCount::Count(_)) => {
-            let old_dbname = state.subscribed_db.clone().unwrap();
-            state.unsub();
-            ReturnType::string(format!("Unsubscribed from {}", old_dbname))
+        Unsubscribe(Some(dbname)) => {
+            if state.unsub(&dbname) {
+                ReturnType::string(format!("Unsubscribed from {}", dbname))
+            } else {
+                ReturnType::error(format!("Not subscribed to {}", dbname))
+            }
         }
 
         Use(dbname) => {
@@ -289,6 +548,11 @@ pub fn gen_response<'a: 'b, 'b, 'c>(line: &'b str,
             state.get(cnt, fmt, rng, loc)
             .unwrap_or(ReturnType::error("Not enough items to return")),
 
+        GetStream(cnt, fmt, rng, loc) => match state.get_chunks(cnt, fmt, rng, loc) {
+            Some(chunks) => ReturnType::Chunks(Chunks(Box::new(chunks))),
+            None => ReturnType::error("DB not found."),
+        },
+
         Unknown => ReturnType::error("Unknown command."),
     }
 }
@@ -299,13 +563,12 @@ mod tests {
     use settings::Settings;
     use std::sync::{Arc, RwLock};
     use std::collections::HashMap;
-    use futures;
 
     fn gen_state<'thr, 'store>() -> ThreadState<'thr, 'store> {
         let settings: Settings = Default::default();
         let global = Arc::new(RwLock::new(SharedState::new(settings)));
         let store = Arc::new(RwLock::new(HashMap::new()));
-        let (tx, _) = futures::sync::mpsc::unbounded::<Update>();
+        let (tx, _) = global.read().unwrap().subs.queue();
         ThreadState::new(global, store, tx)
     }
 
@@ -344,4 +607,189 @@ mod tests {
         assert_eq!(ReturnType::String("".into()), resp);
     }
 
+    #[test]
+    fn should_open_fetch_and_close_cursor() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        gen_response("ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        gen_response("ADD 1513749531.585,1,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+
+        let resp = gen_response("OPEN CURSOR bnc_btc_eth FROM 1513749530 TO 1513749600", &mut state);
+        assert_eq!(ReturnType::String("1".into()), resp);
+        let resp = gen_response("FETCH 1 1 AS CSV", &mut state);
+        assert_eq!(ReturnType::String("1513749530.585,0,true,true,0.046832,0.189\n".into()), resp);
+        let resp = gen_response("FETCH 1 5 AS CSV", &mut state);
+        assert_eq!(ReturnType::String("1513749531.585,1,true,true,0.046832,0.189\n".into()), resp);
+        let resp = gen_response("FETCH 1 5 AS JSON", &mut state);
+        assert_eq!(ReturnType::String("[]\n".into()), resp);
+
+        let resp = gen_response("CLOSE 1", &mut state);
+        assert_eq!(ReturnType::String("Closed cursor 1.".into()), resp);
+        let resp = gen_response("FETCH 1 5", &mut state);
+        assert_eq!(ReturnType::Error("No cursor 1.".into()), resp);
+        let resp = gen_response("OPEN CURSOR bnc_btc_eth FROM x TO 1", &mut state);
+        assert_eq!(ReturnType::Error("Unable to parse range.".into()), resp);
+    }
+
+    #[test]
+    fn should_commit_bulkadd_on_ddaklub() {
+        let mut state = gen_state();
+        let (tx, _) = state.global.read().unwrap().subs.queue();
+        let mut other = ThreadState::new(state.global.clone(), state.store.clone(), tx);
+
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        gen_response("BULKADD INTO bnc_btc_eth", &mut state);
+        gen_response("1513749530.585,0,t,t,0.04683200,0.18900000;", &mut state);
+        gen_response("1513749531.585,1,t,t,0.04683200,0.18900000;", &mut state);
+        assert!(!other.is_bulkadding());
+
+        gen_response("USE bnc_btc_eth", &mut other);
+        let resp = gen_response("COUNT", &mut other);
+        assert_eq!(ReturnType::String("0".into()), resp);
+
+        let resp = gen_response("DDAKLUB", &mut state);
+        assert_eq!(ReturnType::String("1".into()), resp);
+        let resp = gen_response("COUNT", &mut other);
+        assert_eq!(ReturnType::String("2".into()), resp);
+    }
+
+    #[test]
+    fn should_discard_aborted_bulkadd() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        gen_response("BULKADD INTO bnc_btc_eth", &mut state);
+        gen_response("1513749530.585,0,t,t,0.04683200,0.18900000;", &mut state);
+        state.abort_bulkadd();
+
+        gen_response("USE bnc_btc_eth", &mut state);
+        let resp = gen_response("COUNT", &mut state);
+        assert_eq!(ReturnType::String("0".into()), resp);
+    }
+
+    #[test]
+    fn should_subscribe_to_many_and_unsubscribe_one() {
+        let mut state = gen_state();
+        let resp = gen_response("SUBSCRIBE bnc_*_btc gdx_eth_usd WHERE is_trade = t", &mut state);
+        assert_eq!(ReturnType::String("Subscribed to bnc_*_btc, gdx_eth_usd".into()), resp);
+        assert_eq!(2, state.subscriptions.len());
+
+        let resp = gen_response("UNSUBSCRIBE gdx_eth_usd", &mut state);
+        assert_eq!(ReturnType::String("Unsubscribed from gdx_eth_usd".into()), resp);
+        assert!(state.subscriptions.contains_key("bnc_*_btc"));
+
+        let resp = gen_response("UNSUBSCRIBE gdx_eth_usd", &mut state);
+        assert_eq!(ReturnType::Error("Not subscribed to gdx_eth_usd".into()), resp);
+
+        gen_response("UNSUBSCRIBE ALL", &mut state);
+        assert!(!state.is_subscribed());
+    }
+
+    #[test]
+    fn should_copy_rename_and_drop() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        gen_response("ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        gen_response("USE bnc_btc_eth", &mut state);
+
+        let resp = gen_response("COPY bnc_btc_eth bnc_btc_eth2", &mut state);
+        assert_eq!(ReturnType::String("Copied DB `bnc_btc_eth` to `bnc_btc_eth2`.".into()), resp);
+        let resp = gen_response("COPY bnc_btc_eth bnc_btc_eth2", &mut state);
+        assert_eq!(ReturnType::Error("DB bnc_btc_eth2 already exists.".into()), resp);
+
+        let resp = gen_response("RENAME bnc_btc_eth bnc_btc_xrp", &mut state);
+        assert_eq!(ReturnType::String("Renamed DB `bnc_btc_eth` to `bnc_btc_xrp`.".into()), resp);
+        assert_eq!(ReturnType::Error("No db named `bnc_btc_eth`".into()), gen_response("EXISTS bnc_btc_eth", &mut state));
+        // the current store was renamed away
+        assert_eq!("default", state.current_store_name);
+
+        gen_response("USE bnc_btc_xrp", &mut state);
+        assert_eq!(ReturnType::String("1".into()), gen_response("COUNT", &mut state));
+        gen_response("USE bnc_btc_eth2", &mut state);
+        assert_eq!(ReturnType::String("1".into()), gen_response("COUNT", &mut state));
+
+        let resp = gen_response("DROP bnc_btc_eth2", &mut state);
+        assert_eq!(ReturnType::String("Dropped DB `bnc_btc_eth2`.".into()), resp);
+        assert_eq!(ReturnType::Error("No db named `bnc_btc_eth2`".into()), gen_response("EXISTS bnc_btc_eth2", &mut state));
+        let resp = gen_response("DROP default", &mut state);
+        assert_eq!(ReturnType::Error("Cannot drop the default store.".into()), resp);
+    }
+
+    #[test]
+    fn should_show_and_describe_databases() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        let resp = gen_response("SHOW DATABASES", &mut state);
+        assert_eq!(ReturnType::String("[\"bnc_btc_eth\",\"default\"]\n".into()), resp);
+
+        match gen_response("DESCRIBE bnc_btc_eth", &mut state) {
+            ReturnType::String(desc) => assert!(desc.contains("\"name\": \"bnc_btc_eth\"")),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+        let resp = gen_response("DESCRIBE bnc_btc_xrp", &mut state);
+        assert_eq!(ReturnType::Error("DB bnc_btc_xrp not found.".into()), resp);
+    }
+
+    #[test]
+    fn should_delete_range() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        for ts in &["1513749530.585", "1513749531.585", "1513749532.585", "1513749533.585"] {
+            let cmd = format!("ADD {},0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", ts);
+            gen_response(&cmd, &mut state);
+        }
+
+        let resp = gen_response("DELETE FROM bnc_btc_eth FROM 1513749531 TO 1513749533", &mut state);
+        assert_eq!(ReturnType::String("Deleted 2 updates from `bnc_btc_eth`.".into()), resp);
+        gen_response("USE bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::String("2".into()), gen_response("COUNT", &mut state));
+
+        let resp = gen_response("DELETE FROM bnc_btc_eth FROM yesterday TO 1513749532", &mut state);
+        assert_eq!(ReturnType::Error("Unable to parse range.".into()), resp);
+        let resp = gen_response("DELETE FROM bnc_btc_xrp FROM 0 TO 1", &mut state);
+        assert_eq!(ReturnType::Error("DB bnc_btc_xrp not found.".into()), resp);
+    }
+
+    #[test]
+    fn should_set_insert_policies() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        let resp = gen_response("SET bnc_btc_eth LATE REJECT", &mut state);
+        assert_eq!(ReturnType::String("Set LATE REJECT for `bnc_btc_eth`.".into()), resp);
+        let resp = gen_response("SET bnc_btc_eth DEDUPE ON", &mut state);
+        assert_eq!(ReturnType::String("Set DEDUPE ON for `bnc_btc_eth`.".into()), resp);
+
+        gen_response("ADD 1513749531.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        let resp = gen_response("ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::Error("Rejected 1 late updates older than 1513749531585.".into()), resp);
+
+        let resp = gen_response("SET bnc_btc_eth LATE SOMETIMES", &mut state);
+        assert_eq!(ReturnType::Error("Late policy must be one of KEEP, DROP, ACCEPT or REJECT.".into()), resp);
+        let resp = gen_response("SET bnc_btc_xrp DEDUPE ON", &mut state);
+        assert_eq!(ReturnType::Error("DB bnc_btc_xrp not found.".into()), resp);
+    }
+
+    #[test]
+    fn should_reject_invalid_updates() {
+        let mut state = gen_state();
+        gen_response("CREATE bnc_btc_eth", &mut state);
+        let resp = gen_response("ADD 0,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::String("".into()), resp);
+        let resp = gen_response("SET bnc_btc_eth REJECT_MALFORMED ON", &mut state);
+        assert_eq!(ReturnType::String("Set REJECT_MALFORMED ON for `bnc_btc_eth`.".into()), resp);
+        let resp = gen_response("ADD 0,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::Error("Invalid update: ts is 0.".into()), resp);
+
+        let resp = gen_response("SET bnc_btc_eth MAX_PAST 60", &mut state);
+        assert_eq!(ReturnType::String("Set MAX_PAST for `bnc_btc_eth`.".into()), resp);
+        let resp = gen_response("ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::Error("Invalid update: ts 1513749530585 is more than 60s in the past.".into()), resp);
+        gen_response("SET bnc_btc_eth MAX_PAST OFF", &mut state);
+        let resp = gen_response("ADD 1513749530.585,0,t,t,0.04683200,0.18900000; INTO bnc_btc_eth", &mut state);
+        assert_eq!(ReturnType::String("".into()), resp);
+
+        let resp = gen_response("SET bnc_btc_eth MAX_PAST a while", &mut state);
+        assert_eq!(ReturnType::Error("Unknown command.".into()), resp);
+        let resp = gen_response("SET bnc_btc_eth MAX_PAST forever", &mut state);
+        assert_eq!(ReturnType::Error("Limit must be a number or OFF.".into()), resp);
+    }
 }
@@ -5,6 +5,7 @@ extern crate clap;
 extern crate byteorder;
 extern crate chrono;
 extern crate serde;
+extern crate serde_json;
 extern crate time;
 #[macro_use]
 extern crate serde_derive;
@@ -20,20 +21,28 @@ extern crate stackdriver_logging_utils;
 extern crate uuid;
 extern crate circular_queue;
 
+extern crate native_tls;
+extern crate openssl;
+
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_signal;
+extern crate tokio_tls;
 
 mod plugins;
 
 mod server;
 mod state;
+mod catalog;
+mod policies;
+mod memstore;
 mod utils;
 mod parser;
 mod handler;
 mod settings;
 mod subscription;
+mod tls;
 
 use clap::{Arg, App, ArgMatches};
 use stackdriver_logging_utils::init_default_logger;
@@ -71,10 +80,62 @@ fn main() {
             None => cli_setting,
         }
     };
+    let dedupe = {
+        let cli_setting: bool = matches.is_present("dedupe");
+        let env_setting = key_or_none("TECTONICDB_DEDUPE");
+        match env_setting {
+            Some(s) => match s.as_ref() {
+                "true" | "1" => true,
+                "false" => false,
+                _ => cli_setting,
+            },
+            None => cli_setting,
+        }
+    };
+    let reject_malformed = {
+        let cli_setting: bool = matches.is_present("reject_malformed");
+        let env_setting = key_or_none("TECTONICDB_REJECT_MALFORMED");
+        match env_setting {
+            Some(s) => match s.as_ref() {
+                "true" | "1" => true,
+                "false" => false,
+                _ => cli_setting,
+            },
+            None => cli_setting,
+        }
+    };
+    let late_updates = matches
+        .value_of("late_updates")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_LATE_UPDATES", "accept"));
+    let max_future_secs = matches
+        .value_of("max_future_secs")
+        .map(String::from)
+        .or(key_or_none("TECTONICDB_MAX_FUTURE_SECS"));
+    let max_past_secs = matches
+        .value_of("max_past_secs")
+        .map(String::from)
+        .or(key_or_none("TECTONICDB_MAX_PAST_SECS"));
+    let max_seq_regression = matches
+        .value_of("max_seq_regression")
+        .map(String::from)
+        .or(key_or_none("TECTONICDB_MAX_SEQ_REGRESSION"));
+    let memory_budget = matches
+        .value_of("memory_budget")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_MEMORY_BUDGET", "0"));
+    let memory_policy = matches
+        .value_of("memory_policy")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_MEMORY_POLICY", "flush"));
     let flush_interval = matches
         .value_of("flush_interval")
         .map(String::from)
         .unwrap_or(key_or_default("TECTONICDB_FLUSH_INTERVAL", "1000"));
+    let partition = matches
+        .value_of("partition")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_PARTITION", "none"));
     let hist_granularity = matches
         .value_of("hist_granularity")
         .map(String::from)
@@ -87,13 +148,54 @@ fn main() {
         .value_of("log_file")
         .map(String::from)
         .unwrap_or(key_or_default("TECTONICDB_LOG_FILE_NAME", "tectonic.log"));
+    let threads = matches
+        .value_of("threads")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_THREADS", "4"));
+    let flush_threads = matches
+        .value_of("flush_threads")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_FLUSH_THREADS", "2"));
+    let sub_queue_capacity = matches
+        .value_of("sub_queue_capacity")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_SUB_QUEUE_CAPACITY", "10000"));
+    let sub_queue_policy = matches
+        .value_of("sub_queue_policy")
+        .map(String::from)
+        .unwrap_or(key_or_default("TECTONICDB_SUB_QUEUE_POLICY", "drop_oldest"));
+    let tls_cert = matches
+        .value_of("tls_cert")
+        .map(String::from)
+        .or(key_or_none("TECTONICDB_TLS_CERT"));
+    let tls_key = matches
+        .value_of("tls_key")
+        .map(String::from)
+        .or(key_or_none("TECTONICDB_TLS_KEY"));
 
     let settings = settings::Settings {
         autoflush: autoflush,
         dtf_folder: dtf_folder.to_owned(),
         flush_interval: flush_interval.parse().unwrap(),
+        partition: partition.parse().unwrap(),
         hist_granularity: hist_granularity.parse().unwrap(),
         hist_q_capacity: hist_q_capacity.parse().unwrap(),
+        dedupe,
+        late_updates: late_updates.parse().unwrap(),
+        validation: state::Validation {
+            reject_malformed,
+            max_future: max_future_secs.map(|secs| secs.parse::<u64>().unwrap() * 1000),
+            max_past: max_past_secs.map(|secs| secs.parse::<u64>().unwrap() * 1000),
+            max_seq_regression: max_seq_regression.map(|n| n.parse().unwrap()),
+        },
+        memory_budget: memory_budget.parse().unwrap(),
+        memory_policy: memory_policy.parse().unwrap(),
+        threads: threads.parse().unwrap(),
+        flush_threads: flush_threads.parse().unwrap(),
+        sub_queue_capacity: sub_queue_capacity.parse().unwrap(),
+        sub_queue_policy: sub_queue_policy.parse().unwrap(),
+        tls_cert,
+        tls_key,
     };
 
     prepare_logger(verbosity, &log_file);
@@ -176,6 +278,41 @@ fn get_matches<'a>() -> ArgMatches<'a> {
         .arg(Arg::with_name("autoflush").short("a").help(
             "Sets autoflush (default is false)",
         ))
+        .arg(Arg::with_name("dedupe").long("dedupe").help(
+            "Drops inserts identical in ts, seq, side and price to one in memory or on disk (default is false)",
+        ))
+        .arg(
+            Arg::with_name("late_updates")
+                .long("late_updates")
+                .value_name("POLICY")
+                .possible_values(&["keep", "drop", "accept", "reject"])
+                .help("Sets what happens to updates older than the newest in their store (default accept)")
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("reject_malformed").long("reject_malformed").help(
+            "Rejects updates with a zero ts, or a negative, NaN or infinite price or size (default is false)",
+        ))
+        .arg(
+            Arg::with_name("max_future_secs")
+                .long("max_future_secs")
+                .value_name("SECS")
+                .help("Rejects updates this far ahead of the server clock (default unchecked)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_past_secs")
+                .long("max_past_secs")
+                .value_name("SECS")
+                .help("Rejects updates this far behind the server clock (default unchecked)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_seq_regression")
+                .long("max_seq_regression")
+                .value_name("N")
+                .help("Rejects updates whose seq falls more than this below the previous one (default unchecked)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("flush_interval")
                 .short("i")
@@ -183,6 +320,14 @@ fn get_matches<'a>() -> ArgMatches<'a> {
                 .value_name("INTERVAL")
                 .help("Sets autoflush interval (default every 1000 inserts)"),
         )
+        .arg(
+            Arg::with_name("partition")
+                .long("partition")
+                .value_name("PARTITION")
+                .possible_values(&["none", "hourly", "daily"])
+                .help("Sets how stores are split into files by update time (default none)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("hist_granularity")
                 .short("g")
@@ -199,5 +344,64 @@ fn get_matches<'a>() -> ArgMatches<'a> {
                 .value_name("LOG_FILE")
                 .help("Sets the log file to write to"),
         )
+        .arg(
+            Arg::with_name("memory_budget")
+                .long("memory_budget")
+                .value_name("BYTES")
+                .help("Sets how many bytes the updates of all stores may take in memory, 0 is unlimited (default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory_policy")
+                .long("memory_policy")
+                .value_name("POLICY")
+                .possible_values(&["flush", "reject"])
+                .help("Sets what happens to inserts over the memory budget (default flush)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("THREADS")
+                .help("Sets the number of worker threads serving connections (default 4)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flush_threads")
+                .long("flush_threads")
+                .value_name("THREADS")
+                .help("Sets the number of threads writing flushes, shared by every store (default 2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sub_queue_capacity")
+                .long("sub_queue_capacity")
+                .value_name("CAPACITY")
+                .help("Sets how many updates are buffered per subscribed client, 0 is unbounded (default 10000)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sub_queue_policy")
+                .long("sub_queue_policy")
+                .value_name("POLICY")
+                .possible_values(&["drop_oldest", "disconnect", "conflate"])
+                .help("Sets what happens when a subscriber falls behind (default drop_oldest)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("tls_cert")
+                .value_name("CERT")
+                .help("Sets the PEM certificate used to serve TLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("tls_key")
+                .value_name("KEY")
+                .help("Sets the PEM private key used to serve TLS")
+                .takes_value(true),
+        )
         .get_matches()
 }
@@ -0,0 +1,205 @@
+/// The updates a store holds in memory, sorted by (ts, seq).
+///
+/// They're split into chunks of at most `CHUNK_CAP` updates, each starting after the previous
+/// one ends, with an index of the first (ts, seq) of every chunk. An insert or a range lookup
+/// binary searches the index and then one chunk, so an out of order insert only shifts the
+/// updates of its chunk, and flushing never has to sort.
+
+use std::cmp::Ordering;
+use std::mem;
+
+use libtectonic::dtf::update::Update;
+
+/// updates per chunk before it's split
+pub const CHUNK_CAP: usize = 4096;
+
+type Key = (u64, u32);
+
+fn key(up: &Update) -> Key {
+    (up.ts, up.seq)
+}
+
+/// first position in `items` whose key is greater than `key`
+fn upper_bound<T, F: Fn(&T) -> Key>(items: &[T], key: Key, f: F) -> usize {
+    items
+        .binary_search_by(|item| if f(item) <= key { Ordering::Less } else { Ordering::Greater })
+        .unwrap_err()
+}
+
+/// first position in `items` with a ts of at least `ts`
+fn lower_bound<T, F: Fn(&T) -> u64>(items: &[T], ts: u64, f: F) -> usize {
+    items
+        .binary_search_by(|item| if f(item) < ts { Ordering::Less } else { Ordering::Greater })
+        .unwrap_err()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemStore {
+    chunks: Vec<Vec<Update>>,
+    /// key of the first update of every chunk
+    index: Vec<Key>,
+    len: usize,
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts `up` after the updates with the same (ts, seq).
+    pub fn push(&mut self, up: Update) {
+        let up_key = key(&up);
+        self.len += 1;
+        let i = upper_bound(&self.index, up_key, |k| *k);
+        if i == 0 {
+            match self.chunks.first_mut() {
+                Some(chunk) if chunk.len() < CHUNK_CAP => chunk.insert(0, up),
+                _ => {
+                    self.chunks.insert(0, vec![up]);
+                    self.index.insert(0, up_key);
+                }
+            }
+            self.index[0] = up_key;
+            return;
+        }
+
+        let i = i - 1;
+        // in order inserts fill the last chunk up and start a new one
+        if i == self.chunks.len() - 1 && self.chunks[i].len() >= CHUNK_CAP
+            && self.chunks[i].last().map_or(true, |last| key(last) <= up_key)
+        {
+            self.chunks.push(vec![up]);
+            self.index.push(up_key);
+            return;
+        }
+        let chunk = &mut self.chunks[i];
+        let pos = upper_bound(chunk, up_key, key);
+        chunk.insert(pos, up);
+        if chunk.len() > CHUNK_CAP {
+            let tail = chunk.split_off(CHUNK_CAP / 2);
+            self.index.insert(i + 1, key(&tail[0]));
+            self.chunks.insert(i + 1, tail);
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, ups: &[Update]) {
+        for up in ups {
+            self.push(*up);
+        }
+    }
+
+    /// Moves every update of `ups` in, leaving it empty.
+    pub fn append(&mut self, ups: &mut Vec<Update>) {
+        for up in ups.drain(..) {
+            self.push(up);
+        }
+    }
+
+    /// bytes allocated, including spare capacity
+    pub fn heap_size(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.capacity() * mem::size_of::<Update>()).sum::<usize>()
+            + self.chunks.capacity() * mem::size_of::<Vec<Update>>()
+            + self.index.capacity() * mem::size_of::<Key>()
+    }
+
+    /// Removes every update, releasing the memory they took.
+    pub fn clear(&mut self) {
+        *self = MemStore::new();
+    }
+
+    pub fn retain<F: FnMut(&Update) -> bool>(&mut self, mut f: F) {
+        for chunk in &mut self.chunks {
+            chunk.retain(|up| f(up));
+        }
+        self.chunks.retain(|chunk| !chunk.is_empty());
+        self.index = self.chunks.iter().map(|chunk| key(&chunk[0])).collect();
+        self.len = self.chunks.iter().map(Vec::len).sum();
+    }
+
+    pub fn first(&self) -> Option<&Update> {
+        self.chunks.first().and_then(|chunk| chunk.first())
+    }
+
+    pub fn last(&self) -> Option<&Update> {
+        self.chunks.last().and_then(|chunk| chunk.last())
+    }
+
+    /// every update, in (ts, seq) order
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Update> + 'a {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    /// the updates with `min_ts <= ts <= max_ts`, in (ts, seq) order
+    pub fn range<'a>(&'a self, min_ts: u64, max_ts: u64) -> impl Iterator<Item = &'a Update> + 'a {
+        // the chunk before the first one starting at min_ts may hold some as well
+        let i = lower_bound(&self.index, min_ts, |k| k.0).saturating_sub(1);
+        let start = self.chunks.get(i).map_or(0, |chunk| lower_bound(chunk, min_ts, |up| up.ts));
+        self.chunks
+            .iter()
+            .skip(i)
+            .enumerate()
+            .flat_map(move |(j, chunk)| chunk[if j == 0 { start } else { 0 }..].iter())
+            .take_while(move |up| up.ts <= max_ts)
+    }
+
+    pub fn to_vec(&self) -> Vec<Update> {
+        self.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64, seq: u32) -> Update {
+        Update { ts, seq, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_keep_order_and_index_across_chunks() {
+        let mut mem = MemStore::new();
+        // in order, then late inserts falling in every chunk
+        for ts in 0..3 * CHUNK_CAP as u64 {
+            mem.push(up(ts * 2, 0));
+        }
+        for ts in (0..3 * CHUNK_CAP as u64).rev() {
+            mem.push(up(ts * 2 + 1, 0));
+        }
+        mem.push(up(4, 0));
+        assert_eq!(6 * CHUNK_CAP + 1, mem.len());
+        assert!(mem.chunks.iter().all(|chunk| chunk.len() <= CHUNK_CAP));
+
+        let keys: Vec<Key> = mem.iter().map(key).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+        assert_eq!(mem.index, mem.chunks.iter().map(|chunk| key(&chunk[0])).collect::<Vec<_>>());
+
+        let ts: Vec<u64> = mem.range(3, 6).map(|up| up.ts).collect();
+        assert_eq!(vec![3, 4, 4, 5, 6], ts);
+        let n = mem.range(CHUNK_CAP as u64, 3 * CHUNK_CAP as u64 - 1).count();
+        assert_eq!(2 * CHUNK_CAP, n);
+        assert_eq!(0, mem.range(7 * CHUNK_CAP as u64, 8 * CHUNK_CAP as u64).count());
+
+        mem.retain(|up| up.ts >= 10);
+        assert_eq!(Some(10), mem.first().map(|up| up.ts));
+        assert_eq!(Some(6 * CHUNK_CAP as u64 - 1), mem.last().map(|up| up.ts));
+        assert_eq!(6 * CHUNK_CAP + 1 - 11, mem.len());
+    }
+
+    #[test]
+    fn should_order_by_seq_within_ts() {
+        let mut mem = MemStore::new();
+        mem.extend_from_slice(&[up(1, 2), up(1, 0), up(0, 5), up(1, 1)]);
+        let keys: Vec<Key> = mem.iter().map(key).collect();
+        assert_eq!(vec![(0, 5), (1, 0), (1, 1), (1, 2)], keys);
+    }
+}
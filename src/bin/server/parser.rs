@@ -1,5 +1,6 @@
 use libtectonic::utils;
 use libtectonic::dtf::update::Update;
+use subscription::{Cmp, Cond, Filter};
 
 
 /// Parses a line that looks like
@@ -125,6 +126,52 @@ pub fn parse_get_range(string: &str) -> Option<(u64, u64)> {
     }
 }
 
+/// Parses the conditions after `WHERE` in
+///
+/// SUBSCRIBE bnc_btc_eth WHERE is_trade = t AND side = bid AND price >= 0.01 AND size < 10
+///
+/// `is_trade` and `is_bid` take `t`/`f`, `side` takes `bid`/`ask`, `price` and `size` take
+/// one of `<`, `<=`, `>`, `>=`, `=`.
+pub fn parse_filter(string: &str) -> Option<Filter> {
+    let mut conds = vec![];
+    for cond in string.split(" AND ") {
+        let tokens: Vec<&str> = cond.split_whitespace().collect();
+        if tokens.len() != 3 {
+            return None;
+        }
+        let (field, op, value) = (tokens[0], tokens[1], tokens[2]);
+
+        let cmp = match op {
+            "<" => Cmp::Lt,
+            "<=" => Cmp::Le,
+            ">" => Cmp::Gt,
+            ">=" => Cmp::Ge,
+            "=" => Cmp::Eq,
+            _ => return None,
+        };
+        let flag = match value {
+            "t" | "true" => Some(true),
+            "f" | "false" => Some(false),
+            _ => None,
+        };
+
+        let cond = match (field, cmp) {
+            ("is_trade", Cmp::Eq) => Cond::IsTrade(flag?),
+            ("is_bid", Cmp::Eq) => Cond::IsBid(flag?),
+            ("side", Cmp::Eq) => match value {
+                "bid" => Cond::IsBid(true),
+                "ask" => Cond::IsBid(false),
+                _ => return None,
+            },
+            ("price", cmp) => Cond::Price(cmp, value.parse().ok()?),
+            ("size", cmp) => Cond::Size(cmp, value.parse().ok()?),
+            _ => return None,
+        };
+        conds.push(cond);
+    }
+    Some(Filter(conds))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,4 +253,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn should_parse_filter_ok() {
+        let filter = parse_filter("is_trade = t AND side = ask AND price >= 0.01 AND size < 10");
+        assert_eq!(
+            Some(Filter(vec![
+                Cond::IsTrade(true),
+                Cond::IsBid(false),
+                Cond::Price(Cmp::Ge, 0.01),
+                Cond::Size(Cmp::Lt, 10.),
+            ])),
+            filter
+        );
+    }
+
+    #[test]
+    fn should_parse_filter_not_okay() {
+        assert!(parse_filter("is_trade > t").is_none());
+        assert!(parse_filter("price >= abc").is_none());
+        assert!(parse_filter("volume = 1").is_none());
+        assert!(parse_filter("price>=1").is_none());
+    }
 }
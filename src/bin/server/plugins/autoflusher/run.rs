@@ -20,8 +20,10 @@ pub fn run(mut threadstate: ThreadState<'static, 'static>) {
         loop {
             thread::sleep(sleep_dur);
             info!("[AUTOFLUSHER] Flushing all stores to disk...");
-            threadstate.flushall();
-            info!("[AUTOFLUSHER] All stores flushed.");
+            match threadstate.flushall() {
+                Ok(()) => info!("[AUTOFLUSHER] All stores flushed."),
+                Err(err) => error!("[AUTOFLUSHER] Unable to flush every store: {}", err),
+            }
         }
     });
 }
@@ -15,7 +15,7 @@ extern crate tempdir;
 use self::tempdir::TempDir;
 use uuid::Uuid;
 
-use state::{SharedState, ThreadState};
+use state::{Global, SharedState, ThreadState};
 use plugins::gstorage::GStorageConfig;
 use plugins::gstorage::upload::{self, GStorageFile};
 
@@ -99,9 +99,42 @@ lazy_static! {
         .expect("Unable to create temporary directory!");
 }
 
+/// Moves `fname` out of the db directory to `dst_path`, dropping it from the catalog and from
+/// the segments of the store it belongs to.
+///
+/// The move happens under the store's files lock, so that it's never in the middle of a flush
+/// or a read. The segments are refreshed from the file system afterwards, a flush that creates
+/// the file again meanwhile is kept.
+fn move_file(global: &Global, fname: &str, dst_path: &Path) -> Result<(), io::Error> {
+    let (stores, catalog) = {
+        let rdr = global.read().unwrap();
+        (rdr.vec_store.values().cloned().collect::<Vec<_>>(), rdr.catalog.clone())
+    };
+    let owner = stores
+        .into_iter()
+        .find(|vecs| vecs.read().unwrap().segments.iter().any(|seg| seg.fname == fname));
+    match owner {
+        Some(vecs) => {
+            let files = vecs.read().unwrap().flushing.files.clone();
+            {
+                let _writing = files.write().unwrap();
+                fs::rename(fname, dst_path)?;
+            }
+            let mut vecs = vecs.write().unwrap();
+            catalog.refresh(&mut vecs.segments, fname);
+        }
+        None => {
+            fs::rename(fname, dst_path)?;
+            catalog.refresh(&mut vec![], fname);
+        }
+    }
+    Ok(())
+}
+
 /// Move all DTF files in the db directory to the temporary directory for uploading
-fn copy_files(dtf_directory: &str, min_file_size_bytes: Option<u64>) {
-    let files_to_copy: Vec<Result<DirEntry, io::Error>> = match list_files(dtf_directory) {
+fn copy_files(global: &Global, min_file_size_bytes: Option<u64>) {
+    let dtf_directory = { global.read().unwrap().settings.dtf_folder.clone() };
+    let files_to_copy: Vec<Result<DirEntry, io::Error>> = match list_files(&dtf_directory) {
         Ok(files) => files,
         Err(err) => {
             error!("{}", err);
@@ -111,12 +144,16 @@ fn copy_files(dtf_directory: &str, min_file_size_bytes: Option<u64>) {
 
     files_to_copy.into_par_iter().for_each(|path_res| {
         match path_res {
+            // the catalog manifest stays with the server
+            Ok(ref entry) if entry.path().extension().map_or(true, |ext| ext != "dtf") => {}
             Ok(entry) => {
                 let src_path = entry.path();
                 let dtf_file_name = src_path.file_name()
                     .unwrap()
                     .to_str()
                     .unwrap();
+                // as the catalog and the segments name it
+                let fname = format!("{}/{}", dtf_directory, dtf_file_name);
                 let dtf_file_name = format!("{}-{}", Uuid::new_v4(), dtf_file_name);
                 let metadata = match entry.metadata() {
                     Ok(metadata) => metadata,
@@ -130,7 +167,7 @@ fn copy_files(dtf_directory: &str, min_file_size_bytes: Option<u64>) {
                 if file_size_bytes >= min_file_size_bytes.unwrap_or(0) {
                     // move the file to the temporary directory to be uploaded
                     let dst_path = TMP_DIR.path().join(dtf_file_name);
-                    match fs::rename(src_path.clone(), dst_path) {
+                    match move_file(global, &fname, &dst_path) {
                         Ok(_) => (),
                         Err(err) => error!(
                             "Error while moving DTF file for upload: {:?}",
@@ -150,14 +187,13 @@ pub fn run(global: Arc<RwLock<SharedState>>) {
         let conf = GStorageConfig::new().unwrap();
         let min_file_size_bytes = conf.min_file_size;
         info!("Initializing GStorage plugin with config: {:?}", conf);
-        let dtf_directory = { global_copy.read().unwrap().settings.dtf_folder.clone() };
 
         loop {
             thread::sleep(Duration::from_secs(conf.upload_interval_secs));
             info!("Gstorage checking to see if any files need upload...");
 
             // Copy all files over the size threshhold into the temporary directory for uploading
-            copy_files(&dtf_directory, Some(min_file_size_bytes));
+            copy_files(&global_copy, Some(min_file_size_bytes));
 
             // Upload all files in the temporary directory
             upload_all_files(TMP_DIR.path());
@@ -167,7 +203,6 @@ pub fn run(global: Arc<RwLock<SharedState>>) {
 
 /// Called when the database is being shut down.  Uploads all files, regardless of size.
 pub fn run_exit_hook(state: &ThreadState<'static, 'static>) {
-    let dtf_dir_path = { &state.global.read().unwrap().settings.dtf_folder.clone() };
-    copy_files(&dtf_dir_path, None);
+    copy_files(&state.global, None);
     upload_all_files(&TMP_DIR.path())
 }
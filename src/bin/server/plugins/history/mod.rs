@@ -20,11 +20,13 @@ pub fn run(global: Arc<RwLock<SharedState>>) {
                 let mut total = 0;
                 let mut sizes: Vec<(String, u64)> = Vec::new();
                 for (name, vec) in rwdr.vec_store.iter() {
-                    let size = vec.1;
-                    total += size;
-                    sizes.push((name.clone(), size));
+                    let vec = vec.read().unwrap();
+                    total += vec.count;
+                    sizes.push((name.clone(), vec.count));
+                    sizes.push((format!("mem_{}", name), vec.mem_bytes as u64));
                 }
                 sizes.push(("total".to_owned(), total));
+                sizes.push(("mem_total".to_owned(), rwdr.mem.used() as u64));
                 (total, sizes)
             };
 
@@ -4,6 +4,8 @@
 pub mod gstorage;
 #[cfg(feature = "autoflusher")]
 pub mod autoflusher;
+#[cfg(feature = "retention")]
+pub mod retention;
 
 // history plugin
 pub mod history;
@@ -18,6 +20,7 @@ pub fn run_plugins(global: Arc<RwLock<SharedState>>, threadstate: ThreadState<'s
     history::run(global.clone());
 
     #[cfg(feature = "gcs")] gstorage::run(global.clone());
+    #[cfg(feature = "retention")] retention::run(global.clone(), threadstate.store.clone());
     #[cfg(feature = "autoflusher")] autoflusher::run(threadstate);
 }
 
@@ -0,0 +1,112 @@
+use std::error::Error;
+
+use settings::{key_or_default_parse, key_or_none};
+use subscription::glob_match;
+
+/// How much history of a store is kept on disk. `None` is unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    /// updates older than this are removed
+    pub max_age_secs: Option<u64>,
+    /// oldest updates are removed until the store's files fit
+    pub max_bytes: Option<u64>,
+}
+
+impl Policy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_secs.is_none() && self.max_bytes.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct RetentionConfig {
+    /// policy for stores not matched by `policies`
+    pub default: Policy,
+    /// per-store policies keyed by store name or glob pattern, the first match wins
+    pub policies: Vec<(String, Policy)>,
+    /// amount of seconds between retention checks
+    pub interval_secs: u64,
+}
+
+impl RetentionConfig {
+    /// Creates a new `RetentionConfig` from environment variables, filling in the remaining values with
+    /// defaults if not available.
+    pub fn new() -> Result<Self, Box<Error>> {
+        Ok(RetentionConfig {
+            default: Policy {
+                max_age_secs: parse_limit(&key_or_none("RETENTION_MAX_AGE_SECS").unwrap_or_default())?,
+                max_bytes: parse_limit(&key_or_none("RETENTION_MAX_BYTES").unwrap_or_default())?,
+            },
+            policies: parse_policies(&key_or_none("RETENTION_POLICIES").unwrap_or_default())?,
+            interval_secs: key_or_default_parse("RETENTION_INTERVAL_SECS", 3600)?,
+        })
+    }
+
+    pub fn policy_for(&self, store: &str) -> &Policy {
+        self.policies
+            .iter()
+            .find(|&&(ref pattern, _)| glob_match(pattern, store))
+            .map(|&(_, ref policy)| policy)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn parse_limit(s: &str) -> Result<Option<u64>, Box<Error>> {
+    let s = s.trim();
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(s.parse()?))
+    }
+}
+
+/// Parses `pattern:max_age_secs:max_bytes` entries separated by commas,
+/// where an empty limit is unlimited, i.e. `bnc_*:604800:,gdax_btc_usd::1073741824`
+pub fn parse_policies(s: &str) -> Result<Vec<(String, Policy)>, Box<Error>> {
+    let mut policies = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let parts: Vec<&str> = entry.split(':').collect();
+        if parts.len() != 3 || parts[0].is_empty() {
+            return Err(format!("Invalid retention policy `{}`, expected `pattern:max_age_secs:max_bytes`", entry).into());
+        }
+        policies.push((
+            parts[0].to_owned(),
+            Policy {
+                max_age_secs: parse_limit(parts[1])?,
+                max_bytes: parse_limit(parts[2])?,
+            },
+        ));
+    }
+    Ok(policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_policies() {
+        let policies = parse_policies("bnc_*:604800:, gdax_btc_usd::1024").unwrap();
+        assert_eq!(
+            vec![
+                ("bnc_*".to_owned(), Policy { max_age_secs: Some(604800), max_bytes: None }),
+                ("gdax_btc_usd".to_owned(), Policy { max_age_secs: None, max_bytes: Some(1024) }),
+            ],
+            policies
+        );
+        assert!(parse_policies("bnc_*:604800").is_err());
+        assert!(parse_policies("bnc_*:a week:").is_err());
+        assert!(parse_policies("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_fall_back_to_default_policy() {
+        let conf = RetentionConfig {
+            default: Policy { max_age_secs: Some(60), max_bytes: None },
+            policies: parse_policies("bnc_*::,bnc_eth_btc:1:").unwrap(),
+            interval_secs: 1,
+        };
+        assert!(conf.policy_for("bnc_eth_btc").is_unlimited());
+        assert_eq!(Some(60), conf.policy_for("gdax_btc_usd").max_age_secs);
+    }
+}
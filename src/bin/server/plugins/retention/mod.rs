@@ -0,0 +1,5 @@
+mod conf;
+pub use self::conf::*;
+
+mod run;
+pub use self::run::*;
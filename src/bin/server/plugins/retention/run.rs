@@ -0,0 +1,374 @@
+/// The retention enforcer is run in a separate thread spawned from server thread.
+///
+/// Every `interval_secs` it goes through the segment files of every store and, oldest file
+/// first, deletes the files that fall entirely outside of the store's policy and rewrites
+/// the one straddling the limit without its expired updates. Updates in memory older than
+/// `max_age_secs` are dropped too.
+///
+/// A store's flush lock is held while its files are changed, so that no flush writes them
+/// meanwhile, but neither inserts nor queries wait for a file to be decoded and rewritten:
+/// only the final removal or rename takes the store's files lock.
+
+use std::{fs, io, thread};
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libtectonic::dtf::{self, Update};
+use catalog::{Catalog, Segment};
+use plugins::retention::{Policy, RetentionConfig};
+use state::{Global, HashMapStore};
+
+/// What a retention pass freed
+#[derive(Debug, Default, PartialEq)]
+pub struct Reclaimed {
+    pub files_removed: usize,
+    pub files_truncated: usize,
+    pub updates: u64,
+    pub bytes: u64,
+}
+
+impl Reclaimed {
+    fn add(&mut self, other: &Reclaimed) {
+        self.files_removed += other.files_removed;
+        self.files_truncated += other.files_truncated;
+        self.updates += other.updates;
+        self.bytes += other.bytes;
+    }
+}
+
+struct DtfFile {
+    fname: String,
+    count: u64,
+    min_ts: u64,
+    max_ts: u64,
+    size: u64,
+}
+
+/// the files of `segments` that are still there, not e.g. moved away for upload
+fn list_files(segments: &[Segment]) -> Result<Vec<DtfFile>, io::Error> {
+    let mut files = Vec::with_capacity(segments.len());
+    for seg in segments {
+        let size = match fs::metadata(&seg.fname) {
+            Ok(meta) => meta.len(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        files.push(DtfFile {
+            fname: seg.fname.clone(),
+            count: seg.count,
+            min_ts: seg.min_ts,
+            max_ts: seg.max_ts,
+            size,
+        });
+    }
+    Ok(files)
+}
+
+/// The files of a store, and what guards them
+struct StoreFiles<'a> {
+    symbol: &'a str,
+    /// held for write while a file is replaced or removed
+    lock: &'a RwLock<()>,
+}
+
+impl<'a> StoreFiles<'a> {
+    fn remove(&self, file: &DtfFile) -> Result<Reclaimed, io::Error> {
+        {
+            let _writing = self.lock.write().unwrap();
+            fs::remove_file(&file.fname)?;
+        }
+        Ok(Reclaimed {
+            files_removed: 1,
+            files_truncated: 0,
+            updates: file.count,
+            bytes: file.size,
+        })
+    }
+
+    /// Rewrites `file` with only the updates selected by `keep`, removing it if none are left.
+    fn truncate<F>(&self, file: &mut DtfFile, keep: F) -> Result<Reclaimed, io::Error>
+    where
+        F: FnOnce(Vec<Update>) -> Vec<Update>,
+    {
+        let ups = {
+            let _reading = self.lock.read().unwrap();
+            dtf::decode(&file.fname, None)?
+        };
+        let ups = keep(ups);
+        if ups.is_empty() {
+            return self.remove(file);
+        }
+        // encoded next to the file, readers only wait for the rename
+        let tmp_fname = format!("{}.tmp", file.fname);
+        if let Err(err) = dtf::encode(&tmp_fname, self.symbol, &ups) {
+            let _ = fs::remove_file(&tmp_fname);
+            return Err(err);
+        }
+        {
+            let _writing = self.lock.write().unwrap();
+            fs::rename(&tmp_fname, &file.fname)?;
+        }
+
+        let old_count = file.count;
+        let old_size = file.size;
+        file.count = ups.len() as u64;
+        file.min_ts = ups.iter().map(|up| up.ts).min().unwrap_or(0);
+        file.size = fs::metadata(&file.fname)?.len();
+        Ok(Reclaimed {
+            files_removed: 0,
+            files_truncated: 1,
+            updates: old_count.saturating_sub(file.count),
+            bytes: old_size.saturating_sub(file.size),
+        })
+    }
+}
+
+/// bytes `ups` take in the main section of a dtf file
+fn encoded_size(ups: &[Update]) -> u64 {
+    let mut buf: Vec<u8> = vec![];
+    let _ = dtf::write_batches(&mut buf, ups);
+    buf.len() as u64
+}
+
+/// The newest of `ups` whose file, with a header of `header` bytes, is at most `max_size`.
+fn newest_within(mut ups: Vec<Update>, header: u64, max_size: u64) -> Vec<Update> {
+    let fits = |ups: &[Update]| header + encoded_size(ups) <= max_size;
+    // dropping updates from the front shrinks the file, give or take a batch header
+    let (mut lo, mut hi) = (0, ups.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if fits(&ups[mid..]) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    while lo < ups.len() && !fits(&ups[lo..]) {
+        lo += 1;
+    }
+    ups.split_off(lo)
+}
+
+/// Applies `policy` to the files of one store. `now` is in ms.
+fn enforce_policy(store: &StoreFiles, mut files: Vec<DtfFile>, policy: &Policy, now: u64) -> Result<Reclaimed, io::Error> {
+    let mut reclaimed = Reclaimed::default();
+    files.sort_by_key(|file| file.min_ts);
+
+    if let Some(max_age_secs) = policy.max_age_secs {
+        let cutoff = now.saturating_sub(max_age_secs * 1000);
+        let mut kept = Vec::with_capacity(files.len());
+        for mut file in files {
+            if file.max_ts < cutoff {
+                reclaimed.add(&store.remove(&file)?);
+                continue;
+            }
+            if file.min_ts < cutoff {
+                let r = store.truncate(&mut file, |ups| ups.into_iter().filter(|up| up.ts >= cutoff).collect())?;
+                reclaimed.add(&r);
+            }
+            kept.push(file);
+        }
+        files = kept;
+    }
+
+    if let Some(max_bytes) = policy.max_bytes {
+        let mut total: u64 = files.iter().map(|file| file.size).sum();
+        for mut file in files {
+            if total <= max_bytes {
+                break;
+            }
+            let excess = total - max_bytes;
+            let r = if file.size <= excess {
+                store.remove(&file)?
+            } else {
+                let size = file.size;
+                store.truncate(&mut file, |ups| {
+                    let header = size.saturating_sub(encoded_size(&ups));
+                    newest_within(ups, header, size - excess)
+                })?
+            };
+            total -= r.bytes;
+            reclaimed.add(&r);
+        }
+    }
+
+    Ok(reclaimed)
+}
+
+/// Applies `policy` to the store `name`: its memory first, then its files. Without a store,
+/// e.g. dropped from memory only, to the files the catalog has for it.
+fn enforce_store(stores: &HashMapStore, catalog: &Catalog, name: &str, policy: &Policy, now: u64) -> Result<Reclaimed, io::Error> {
+    let vecs = {
+        let stores = stores.read().unwrap();
+        match stores.get(name) {
+            Some(store) => {
+                if let Some(max_age_secs) = policy.max_age_secs {
+                    store.expire_memory(now.saturating_sub(max_age_secs * 1000));
+                }
+                store.vecs.clone()
+            }
+            None => {
+                let segments = catalog.segments(name);
+                let files = list_files(&segments)?;
+                let result = enforce_policy(&StoreFiles { symbol: name, lock: &RwLock::new(()) }, files, policy, now);
+                for seg in &segments {
+                    catalog.refresh(&mut vec![], &seg.fname);
+                }
+                return result;
+            }
+        }
+    };
+
+    // no flush, rename or delete changes the files meanwhile
+    let lock = vecs.read().unwrap().flushing.lock.clone();
+    let _writing = lock.lock().unwrap();
+    let (symbol, segments, files_lock) = {
+        let vecs = vecs.read().unwrap();
+        (vecs.flushing.name.clone(), vecs.segments.clone(), vecs.flushing.files.clone())
+    };
+    let files = list_files(&segments)?;
+    let store_files = StoreFiles { symbol: &symbol, lock: &files_lock };
+    let result = enforce_policy(&store_files, files, policy, now);
+
+    let mut vecs = vecs.write().unwrap();
+    for seg in &segments {
+        catalog.refresh(&mut vecs.segments, &seg.fname);
+    }
+    if let Ok(ref r) = result {
+        vecs.count = vecs.count.saturating_sub(r.updates);
+    }
+    result
+}
+
+/// Runs one retention pass over every store.
+pub fn enforce(global: &Global, stores: &HashMapStore, conf: &RetentionConfig) -> Reclaimed {
+    let mut reclaimed = Reclaimed::default();
+    let now = {
+        let t = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+        t.as_secs() * 1000 + u64::from(t.subsec_nanos()) / 1_000_000
+    };
+
+    let catalog = global.read().unwrap().catalog.clone();
+    let mut names: BTreeSet<String> = stores.read().unwrap().keys().cloned().collect();
+    names.extend(catalog.symbols());
+    for name in names {
+        let policy = conf.policy_for(&name);
+        if policy.is_unlimited() {
+            continue;
+        }
+        match enforce_store(stores, &catalog, &name, policy, now) {
+            Ok(r) => {
+                if r.files_removed + r.files_truncated > 0 {
+                    info!(
+                        "[RETENTION] {}: removed {} files, truncated {}, reclaimed {} updates and {} bytes.",
+                        name, r.files_removed, r.files_truncated, r.updates, r.bytes
+                    );
+                }
+                reclaimed.add(&r);
+            }
+            Err(err) => error!("[RETENTION] Error while enforcing retention of {}: {:?}", name, err),
+        }
+    }
+    reclaimed
+}
+pub fn run(global: Global, stores: HashMapStore<'static>) {
+    let conf = match RetentionConfig::new() {
+        Ok(conf) => conf,
+        Err(err) => {
+            error!("Error parsing retention config, retention plugin disabled: {}", err);
+            return;
+        }
+    };
+    info!("Initializing retention plugin with config: {:?}", conf);
+
+    thread::spawn(move || {
+        let dur = Duration::from_secs(conf.interval_secs);
+        loop {
+            thread::sleep(dur);
+            let r = enforce(&global, &stores, &conf);
+            info!(
+                "[RETENTION] Removed {} files, truncated {}, reclaimed {} updates and {} bytes.",
+                r.files_removed, r.files_truncated, r.updates, r.bytes
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use state::test_utils::test_state;
+    use utils::init_dbs;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: ts as u32, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_expire_old_updates() {
+        let (folder, global, store, mut state) = test_state("tectonic-retention-age-test", Default::default());
+        let day = 86_400_000;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000;
+        let old = format!("{}/bnc_eth_btc-old.dtf", folder);
+        let mixed = format!("{}/bnc_eth_btc.dtf", folder);
+        dtf::encode(&old, "bnc_eth_btc", &[up(now - 10 * day), up(now - 9 * day)]).unwrap();
+        dtf::encode(&mixed, "bnc_eth_btc", &[up(now - 8 * day), up(now - day), up(now)]).unwrap();
+        dtf::encode(&format!("{}/bnc_xrp_btc.dtf", folder), "bnc_xrp_btc", &[up(now - 10 * day)]).unwrap();
+        global.write().unwrap().catalog = Arc::new(Catalog::open(&folder));
+        init_dbs(&mut state);
+        state.insert(up(now - 5 * day), "bnc_eth_btc").unwrap();
+        state.insert(up(now + 1), "bnc_eth_btc").unwrap();
+        assert_eq!(7, store.read().unwrap()["bnc_eth_btc"].count());
+
+        let conf = RetentionConfig {
+            default: Policy::default(),
+            policies: vec![("bnc_eth_*".to_owned(), Policy { max_age_secs: Some(2 * 86_400), max_bytes: None })],
+            interval_secs: 1,
+        };
+        let r = enforce(&global, &store, &conf);
+        assert_eq!(1, r.files_removed);
+        assert_eq!(1, r.files_truncated);
+        assert_eq!(3, r.updates);
+        assert!(r.bytes > 0);
+
+        assert!(!fs::metadata(&old).is_ok());
+        let ups = dtf::decode(&mixed, None).unwrap();
+        assert_eq!(vec![now - day, now], ups.iter().map(|up| up.ts).collect::<Vec<_>>());
+        // memory expires too
+        assert_eq!(1, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+        assert_eq!(3, store.read().unwrap()["bnc_eth_btc"].count());
+        let segments = global.read().unwrap().catalog.segments("bnc_eth_btc");
+        assert_eq!(vec![(mixed.clone(), 2)], segments.iter().map(|seg| (seg.fname.clone(), seg.count)).collect::<Vec<_>>());
+        // untouched without a policy
+        assert_eq!(1, dtf::read_meta(&format!("{}/bnc_xrp_btc.dtf", folder)).unwrap().nums);
+    }
+
+    #[test]
+    fn should_keep_newest_updates_within_max_bytes() {
+        let (folder, global, store, mut state) = test_state("tectonic-retention-size-test", Default::default());
+        let fname = format!("{}/bnc_eth_btc.dtf", folder);
+        let ups: Vec<Update> = (0..1000).map(up).collect();
+        dtf::encode(&fname, "bnc_eth_btc", &ups).unwrap();
+        // an 80 byte header, then batches of 15 updates, 15 bytes and 12 per update
+        let size = fs::metadata(&fname).unwrap().len();
+        assert_eq!(80 + 67 * 15 + 1000 * 12, size);
+        global.write().unwrap().catalog = Arc::new(Catalog::open(&folder));
+        init_dbs(&mut state);
+
+        let conf = RetentionConfig {
+            default: Policy { max_age_secs: None, max_bytes: Some(size / 2) },
+            policies: vec![],
+            interval_secs: 1,
+        };
+        let r = enforce(&global, &store, &conf);
+        // the most updates that fit: 80 + 34 * 15 + 496 * 12 = 6542
+        assert_eq!((0, 1, 504), (r.files_removed, r.files_truncated, r.updates));
+        assert_eq!(size / 2, fs::metadata(&fname).unwrap().len());
+        assert_eq!(size - size / 2, r.bytes);
+        let ts: Vec<u64> = dtf::decode(&fname, None).unwrap().iter().map(|up| up.ts).collect();
+        assert_eq!((504..1000).collect::<Vec<_>>(), ts);
+        assert_eq!(496, store.read().unwrap()["bnc_eth_btc"].count());
+    }
+}
@@ -0,0 +1,131 @@
+/// Insert policies set on stores with `SET [db] DEDUPE`, `SET [db] LATE`,
+/// `SET [db] REJECT_MALFORMED` and the `SET [db] MAX_*` limits, so that they outlast a restart.
+///
+/// They're kept in memory and mirrored to `<dtf_folder>/policies.json` whenever one is set,
+/// a store is renamed, copied or dropped with its files.
+
+use std::collections::BTreeMap;
+use std::{fs, io};
+use std::sync::Mutex;
+
+use serde_json;
+use state::{Inserts, LatePolicy, ValidationRule};
+
+/// name of the file within `dtf_folder`
+pub const POLICIES: &str = "policies.json";
+
+/// The policies of a store that differ from the settings'
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub dedupe: Option<bool>,
+    pub late_updates: Option<LatePolicy>,
+    pub reject_malformed: Option<bool>,
+    /// validation limit, as named in `SET [db]`, -> its value as set, `None` is off
+    pub limits: BTreeMap<String, Option<u64>>,
+}
+
+#[derive(Debug)]
+pub struct Policies {
+    folder: String,
+    /// store name -> policy
+    entries: Mutex<BTreeMap<String, Policy>>,
+}
+
+impl Policies {
+    pub fn open(folder: &str) -> Policies {
+        let entries = fs::File::open(format!("{}/{}", folder, POLICIES))
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+        Policies {
+            folder: folder.to_owned(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Applies the policy of `name`, if any, over the defaults in `inserts`.
+    pub fn apply(&self, name: &str, inserts: &mut Inserts) {
+        if let Some(policy) = self.entries.lock().unwrap().get(name) {
+            if let Some(dedupe) = policy.dedupe {
+                inserts.dedupe = dedupe;
+            }
+            if let Some(late_updates) = policy.late_updates {
+                inserts.late_policy = late_updates;
+            }
+            if let Some(reject_malformed) = policy.reject_malformed {
+                inserts.validation.reject_malformed = reject_malformed;
+            }
+            for (rule, &value) in &policy.limits {
+                match rule.parse::<ValidationRule>() {
+                    Ok(rule) => inserts.validation.set(rule, value),
+                    Err(err) => warn!("Ignoring the policy of {}: {}", name, err),
+                }
+            }
+        }
+    }
+
+    pub fn set_dedupe(&self, name: &str, dedupe: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(name.to_owned()).or_insert_with(Policy::default).dedupe = Some(dedupe);
+        self.save(&entries);
+    }
+
+    pub fn set_late_policy(&self, name: &str, late_updates: LatePolicy) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(name.to_owned()).or_insert_with(Policy::default).late_updates = Some(late_updates);
+        self.save(&entries);
+    }
+
+    pub fn set_reject_malformed(&self, name: &str, reject_malformed: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(name.to_owned()).or_insert_with(Policy::default).reject_malformed = Some(reject_malformed);
+        self.save(&entries);
+    }
+
+    pub fn set_limit(&self, name: &str, rule: ValidationRule, value: Option<u64>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .entry(name.to_owned())
+            .or_insert_with(Policy::default)
+            .limits
+            .insert(rule.to_string(), value);
+        self.save(&entries);
+    }
+
+    pub fn rename(&self, from: &str, to: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(policy) = entries.remove(from) {
+            entries.insert(to.to_owned(), policy);
+            self.save(&entries);
+        }
+    }
+
+    pub fn copy(&self, from: &str, to: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(policy) = entries.get(from).cloned() {
+            entries.insert(to.to_owned(), policy);
+            self.save(&entries);
+        }
+    }
+
+    pub fn remove(&self, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(name).is_some() {
+            self.save(&entries);
+        }
+    }
+
+    /// Writes the file, replacing the old one only once it's complete.
+    fn save(&self, entries: &BTreeMap<String, Policy>) {
+        let fname = format!("{}/{}", self.folder, POLICIES);
+        let tmp = format!("{}.tmp", fname);
+        let result = fs::create_dir_all(&self.folder)
+            .and_then(|_| fs::File::create(&tmp))
+            .and_then(|file| serde_json::to_writer(file, entries).map_err(io::Error::from))
+            .and_then(|_| fs::rename(&tmp, &fname));
+        if let Err(err) = result {
+            error!("Unable to save the policies {}: {:?}", fname, err);
+        }
+    }
+}
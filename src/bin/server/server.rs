@@ -8,28 +8,32 @@ use byteorder::{WriteBytesExt, NetworkEndian};
 use std::borrow::{Borrow, Cow};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::cmp;
 use std::io::{BufReader, Write};
-use std::net::SocketAddr;
+use std::net::{self, SocketAddr};
 use std::rc::Rc;
 use std::str;
 use std::sync::{Arc, RwLock};
 use std::process::exit;
+use std::thread;
 
-use state::{Global, SharedState, ThreadState};
+use state::{Global, HashMapStore, SharedState, ThreadState};
 use handler::ReturnType;
-use libtectonic::dtf::{Update, UpdateVecInto};
 use utils;
 use handler;
 use plugins::{run_plugins, run_plugin_exit_hooks};
 use settings::Settings;
+use tls;
 
+use futures::future;
 use futures::prelude::*;
 use futures::sync::mpsc;
-use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
-use tokio_io::AsyncRead;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::io::{lines, write_all};
 use tokio_signal;
+use tokio_tls::TlsAcceptor;
 
 /// Creates a listener for Unix signals that takes care of flushing all stores to file before
 /// shutting down the server.
@@ -42,7 +46,9 @@ fn create_signal_handler(
         .for_each(move |signal| {
             println!("Signal: {}", signal);
             info!("`TERM` signal recieved; flushing all stores...");
-            state.flushall();
+            if let Err(err) = state.flushall() {
+                error!("Unable to flush every store: {}", err);
+            }
             info!("All stores flushed; calling plugin exit hooks...");
             run_plugin_exit_hooks(&state);
             info!("Plugin exit hooks called; exiting...");
@@ -69,10 +75,15 @@ pub fn run_server(host: &str, port: &str, settings: &Settings) {
     );
     info!("History granularity: {}.", settings.hist_granularity);
 
+    let tls_acceptor = tls::acceptor_from_settings(settings);
+    if tls_acceptor.is_some() {
+        info!("TLS is enabled.");
+    }
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let listener = TcpListener::bind(&addr, &handle).expect("failed to bind");
+    let listener = net::TcpListener::bind(&addr).expect("failed to bind");
 
     let dtf_folder = &settings.dtf_folder;
     utils::create_dir_if_not_exist(&dtf_folder);
@@ -84,12 +95,14 @@ pub fn run_server(host: &str, port: &str, settings: &Settings) {
     let store = Arc::new(RwLock::new(HashMap::new()));
 
     // initialize the signal handler
-    let (subscriptions_tx, _) = mpsc::unbounded::<Update>();
-    let signal_handler_threadstate = ThreadState::new(
+    let (subscriptions_tx, _) = global.read().unwrap().subs.queue();
+    let mut signal_handler_threadstate = ThreadState::new(
         Arc::clone(&global),
         Arc::clone(&store),
         subscriptions_tx.clone(),
     );
+    // discover the stores on disk once, every connection shares the registry
+    utils::init_dbs(&mut signal_handler_threadstate);
     let signal_handler = create_signal_handler(signal_handler_threadstate);
     handle.spawn(signal_handler);
 
@@ -100,78 +113,169 @@ pub fn run_server(host: &str, port: &str, settings: &Settings) {
     );
     run_plugins(global.clone(), plugins_threadstate);
 
-    // main loop
-    let done = listener.incoming().for_each(move |(socket, _addr)| {
-        // channel for pushing subscriptions directly from subscriptions thread
-        // to client socket
-        let (subscriptions_tx, subscriptions_rx) = mpsc::unbounded::<Update>();
-
-        let global_copy = global.clone();
-        let state = Rc::new(RefCell::new(
-            ThreadState::new(Arc::clone(&global), Arc::clone(&store), subscriptions_tx.clone())
-        ));
-        let state_clone = state.clone();
-
-        utils::init_dbs(&mut state.borrow_mut());
-        on_connect(&global_copy);
-
-        // map incoming subscription updates to the same format as regular
-        // responses so they can be processed in the same manner.
-        let subscriptions = subscriptions_rx.map(|message| (
-            Cow::from(""), ReturnType::string(vec![message].into_json())
-        ));
-
-        let (rdr, wtr) = socket.split();
-        let lines = lines(BufReader::new(rdr));
-        let responses = lines.map(move |line| {
-            let line: Cow<str> = line.into();
-            let resp = handler::gen_response(line.borrow(), &mut state.borrow_mut());
-            (line, resp)
-        });
-
-        // merge responses and messages pushed directly by subscriptions updates
-        // into a single stream
-        let merged = subscriptions.select(responses.map_err(|_| ()));
-
-        let writes = merged.fold(wtr, |wtr, (line, resp)| {
-            let mut buf: Vec<u8> = vec![];
-            use self::ReturnType::*;
-            match resp {
-                Bytes(bytes) => {
-                    buf.write_u8(0x1).unwrap();
-                    buf.write_u64::<NetworkEndian>(bytes.len() as u64)
-                        .unwrap();
-                    buf.write(&bytes).unwrap();
-                }
-                String(str_resp) => {
-                    buf.write_u8(0x1).unwrap();
-                    buf.write_u64::<NetworkEndian>(str_resp.len() as u64)
-                        .unwrap();
-                    buf.write(str_resp.as_bytes()).unwrap();
+    let n_workers = cmp::max(settings.threads, 1);
+    info!("Serving connections on {} worker threads.", n_workers);
+    let workers: Vec<_> = (0..n_workers)
+        .map(|id| spawn_worker(id, &global, &store, tls_acceptor.clone()))
+        .collect();
+
+    // main loop: accept on a dedicated thread and hand sockets out round-robin
+    thread::spawn(move || {
+        for (i, socket) in listener.incoming().enumerate() {
+            match socket {
+                Ok(socket) => {
+                    if let Err(err) = workers[i % workers.len()].unbounded_send(socket) {
+                        error!("Unable to hand connection to worker: {:?}", err);
+                    }
                 }
-                Error(errmsg) => {
-                    error!("Req: `{}`", line);
-                    error!("Err: `{}`", errmsg.clone());
-                    buf.write_u8(0x0).unwrap();
-                    let ret = format!("ERR: {}\n", errmsg);
-                    buf.write_u64::<NetworkEndian>(ret.len() as u64).unwrap();
-                    buf.write(ret.as_bytes()).unwrap();
+                Err(err) => error!("Error accepting connection: {:?}", err),
+            }
+        }
+    });
+
+    // the main thread only drives the signal handler
+    core.run(future::empty::<(), ()>()).unwrap();
+}
+
+/// Spawns a worker thread with its own event loop. Returns the channel used to hand it sockets.
+fn spawn_worker(
+    id: usize,
+    global: &Global,
+    store: &HashMapStore<'static>,
+    tls_acceptor: Option<TlsAcceptor>,
+) -> mpsc::UnboundedSender<net::TcpStream> {
+    let (tx, rx) = mpsc::unbounded::<net::TcpStream>();
+    let global = global.clone();
+    let store = store.clone();
+
+    thread::Builder::new()
+        .name(format!("tectonic-worker-{}", id))
+        .spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+
+            let done = rx.for_each(move |socket| {
+                let socket = match TcpStream::from_stream(socket, &handle) {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        error!("Unable to register connection: {:?}", err);
+                        return Ok(());
+                    }
+                };
+
+                match tls_acceptor {
+                    Some(ref acceptor) => {
+                        let global = global.clone();
+                        let store = store.clone();
+                        let conn_handle = handle.clone();
+                        let handshake = acceptor
+                            .accept(socket)
+                            .map(move |socket| handle_connection(socket, &global, &store, &conn_handle))
+                            .map_err(|err| error!("TLS handshake failed: {:?}", err));
+                        handle.spawn(handshake);
+                    }
+                    None => handle_connection(socket, &global, &store, &handle),
                 }
-            };
-            write_all(wtr, buf).map(|(w, _)| w).map_err(|_| ())
-        });
 
-        let msg = writes.then(move |_| {
-            state_clone.borrow_mut().unsub();
-            on_disconnect(&global_copy);
-            Ok(())
-        });
-        handle.spawn(msg);
+                Ok(())
+            });
+
+            core.run(done).unwrap();
+        })
+        .expect("Unable to spawn worker thread");
+
+    tx
+}
+
+/// Serves a single client connection, plaintext or TLS.
+fn handle_connection<S>(socket: S, global: &Global, store: &HashMapStore<'static>, handle: &Handle)
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    // bounded queue for pushing subscriptions directly from subscriptions thread
+    // to client socket
+    let (subscriptions_tx, subscriptions_rx) = global.read().unwrap().subs.queue();
+
+    let global_copy = global.clone();
+    let state = Rc::new(RefCell::new(
+        ThreadState::new(Arc::clone(global), Arc::clone(store), subscriptions_tx.clone())
+    ));
+    let state_clone = state.clone();
+
+    on_connect(&global_copy);
+
+    // map incoming subscription updates to the same format as regular
+    // responses so they can be processed in the same manner.
+    let subscriptions = subscriptions_rx.map(|(dbname, up)| (
+        Cow::from(""), ReturnType::string(format!(r#"{{"db":"{}","data":{}}}"#, dbname, up.to_json()))
+    ));
+
+    let (rdr, wtr) = socket.split();
+    let lines = lines(BufReader::new(rdr));
+    let responses = lines.map(move |line| {
+        let line: Cow<str> = line.into();
+        let resp = handler::gen_response(line.borrow(), &mut state.borrow_mut());
+        (line, resp)
+    });
+
+    // merge responses and messages pushed directly by subscriptions updates
+    // into a single stream
+    let merged = subscriptions.select(responses.map_err(|_| ()));
 
+    let writes = merged.fold(wtr, |wtr, (line, resp)| -> Box<Future<Item = _, Error = ()>> {
+        use self::ReturnType::*;
+        let buf = match resp {
+            Bytes(bytes) => frame(0x1, &bytes),
+            String(str_resp) => frame(0x1, str_resp.as_bytes()),
+            Error(errmsg) => {
+                error!("Req: `{}`", line);
+                error!("Err: `{}`", errmsg.clone());
+                frame(0x0, format!("ERR: {}\n", errmsg).as_bytes())
+            }
+            Chunks(chunks) => return write_chunks(wtr, chunks),
+        };
+        Box::new(write_all(wtr, buf).map(|(w, _)| w).map_err(|_| ()))
+    });
+
+    let msg = writes.then(move |_| {
+        state_clone.borrow_mut().abort_bulkadd();
+        state_clone.borrow_mut().unsub_all();
+        on_disconnect(&global_copy);
         Ok(())
     });
+    handle.spawn(msg);
+}
 
-    core.run(done).unwrap();
+/// `[status u8][len u64][payload]`, status is 0x1 on success
+fn frame(status: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::with_capacity(9 + payload.len());
+    buf.write_u8(status).unwrap();
+    buf.write_u64::<NetworkEndian>(payload.len() as u64).unwrap();
+    buf.write(payload).unwrap();
+    buf
+}
+
+/// Writes a frame per chunk as soon as it's produced, then the empty frame ending the response.
+fn write_chunks<W>(wtr: W, chunks: handler::Chunks) -> Box<Future<Item = W, Error = ()>>
+    where W: AsyncWrite + 'static
+{
+    let frames = future::loop_fn((wtr, chunks), |(wtr, mut chunks)| {
+        let (buf, is_last) = match chunks.0.next() {
+            Some(Ok(bytes)) => (frame(0x1, &bytes), false),
+            Some(Err(errmsg)) => {
+                error!("Err: `{}`", errmsg);
+                (frame(0x0, format!("ERR: {}\n", errmsg).as_bytes()), true)
+            }
+            None => (frame(0x1, &[]), true),
+        };
+        write_all(wtr, buf).map(move |(wtr, _)| {
+            if is_last {
+                future::Loop::Break(wtr)
+            } else {
+                future::Loop::Continue((wtr, chunks))
+            }
+        })
+    });
+    Box::new(frames.map_err(|_| ()))
 }
 
 fn on_connect(global: &Global) {
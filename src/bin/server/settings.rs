@@ -1,6 +1,8 @@
 use std::env;
 use std::error::Error;
 use std::str::FromStr;
+use subscription::QueuePolicy;
+use state::{LatePolicy, MemoryPolicy, Partition, Validation};
 
 pub fn key_or_default_parse<
     E: Into<Box<Error>>,
@@ -29,11 +31,35 @@ pub fn key_or_none(key: &str) -> Option<String> {
 /// autoflush: boolean. Flush everything to disk at some interval.
 /// dtf_folder: string. folder to save .dtf files
 /// flush_interval: u32. flush at some regular interval.
+/// partition: how stores are split into segment files by update time.
+/// dedupe: bool. default for new stores, drop inserts identical to one in memory.
+/// late_updates: default for new stores, what to do with updates older than the newest one.
+/// validation: default for new stores, limits inserted updates must be within.
+/// memory_budget: usize. bytes the updates of all stores may take in memory, 0 is unlimited.
+/// memory_policy: what to do with inserts over the memory budget.
+/// threads: usize. number of worker threads serving connections.
+/// flush_threads: usize. number of threads writing flushes, shared by every store.
+/// sub_queue_capacity: usize. updates buffered per subscribed connection, 0 is unbounded.
+/// sub_queue_policy: what to do when a subscriber's queue is full.
+/// tls_cert: optional path to a PEM certificate (chain). TLS is enabled when set with tls_key.
+/// tls_key: optional path to the PEM private key of `tls_cert`.
 #[derive(Clone, Debug, Default)]
 pub struct Settings {
     pub autoflush: bool,
     pub dtf_folder: String,
     pub flush_interval: u32,
+    pub partition: Partition,
     pub hist_granularity: u64,
     pub hist_q_capacity: usize,
+    pub dedupe: bool,
+    pub late_updates: LatePolicy,
+    pub validation: Validation,
+    pub memory_budget: usize,
+    pub memory_policy: MemoryPolicy,
+    pub threads: usize,
+    pub flush_threads: usize,
+    pub sub_queue_capacity: usize,
+    pub sub_queue_policy: QueuePolicy,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
 }
@@ -1,31 +1,34 @@
-macro_rules! catch {
-    ($($code:tt)*) => {
-        (|| { Some({ $($code)* }) })()
-    }
-}
-
 use circular_queue::CircularQueue;
 
-use libtectonic::dtf::{self, UpdateVecInto};
+use libtectonic::dtf::{self, DTFBufReader, UpdateVecInto};
 use libtectonic::dtf::update::Update;
-use libtectonic::storage::utils::scan_files_for_range;
+use libtectonic::storage::utils::read_files_for_range;
 use libtectonic::utils::within_range;
 
+use chrono::{TimeZone, Utc};
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::{fmt, fs, io, mem, thread};
+use std::str::FromStr;
 use utils;
+use catalog::{Catalog, Segment};
+use policies::Policies;
+use memstore::MemStore;
 use std::path::Path;
 use settings::Settings;
-use std::sync::{Arc, RwLock, Mutex, mpsc};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use handler::{GetFormat, ReturnType, ReqCount, Loc, Range};
-use subscription::Subscriptions;
-use futures;
+use subscription::{Event, Filter, PushTX, Subscriptions};
+use serde_json;
 
 /// An atomic reference counter for accessing shared data.
 pub type Global = Arc<RwLock<SharedState>>;
 pub type HashMapStore<'a> = Arc<RwLock<HashMap<String, Store<'a>>>>;
-pub type SubscriptionTX = futures::sync::mpsc::UnboundedSender<Update>;
+pub type SharedVecStore = Arc<RwLock<VecStore>>;
+pub type SubscriptionTX = PushTX;
 
 /// name: *should* be the filename
 /// in_memory: are the updates read into memory?
@@ -50,187 +53,322 @@ pub type SubscriptionTX = futures::sync::mpsc::UnboundedSender<Update>;
 pub struct Store<'a> {
     pub name: Cow<'a, str>,
     pub fname: Cow<'a, str>,
-    pub in_memory: bool,
+    pub in_memory: AtomicBool,
+    /// this store's own entry in `SharedState::vec_store`
+    pub vecs: SharedVecStore,
+    /// `SharedState::mem`, to account for memory without taking the global lock
+    pub mem: Arc<MemoryStats>,
+    /// `SharedState::catalog`, updated whenever a segment is written
+    pub catalog: Arc<Catalog>,
     pub global: Global,
 }
 
 impl<'a> Store<'a> {
+    /// Creates a store backed by the `vec_store` entry for `name`, creating an empty entry if
+    /// there is none yet.
+    ///
+    /// Takes the global write lock, so the caller must not hold it.
+    pub fn new(name: &str, fname: &str, in_memory: bool, global: &Global) -> Store<'a> {
+        let (vecs, mem, catalog) = {
+            let mut wtr = global.write().unwrap();
+            (wtr.get_or_create_vec_store(name), wtr.mem.clone(), wtr.catalog.clone())
+        };
+        Store {
+            name: name.to_owned().into(),
+            fname: fname.to_owned().into(),
+            in_memory: AtomicBool::new(in_memory),
+            vecs,
+            mem,
+            catalog,
+            global: global.clone(),
+        }
+    }
+
     /// push a new `update` into the vec
-    pub fn add(&mut self, new_vec: Update) {
-        let (is_autoflush, is_bulkadding) = {
-            let mut wtr = self.global.write().unwrap();
+    ///
+    /// Only this store's lock is taken for write, so inserts into different stores don't
+    /// block each other.
+    pub fn add(&self, new_vec: Update) -> Result<(), String> {
+        self.add_all(&[new_vec]).map(|_| ())
+    }
+
+    /// push a batch of updates into the vec under a single lock, so readers see either none
+    /// or all of them
+    ///
+    /// Duplicate and late updates are handled by the store's `Inserts` policy. Returns how
+    /// many updates were stored.
+    pub fn add_all(&self, new_vecs: &[Update]) -> Result<usize, String> {
+        if new_vecs.is_empty() {
+            return Ok(0);
+        }
+
+        let (subs, is_autoflush, flush_interval) = {
+            let rdr = self.global.read().unwrap();
+            (
+                rdr.subs.clone(),
+                rdr.settings.autoflush,
+                rdr.settings.flush_interval as usize,
+            )
+        };
 
-            // send to insertion firehose
-            {
-                let tx = wtr.subs.lock().unwrap();
-                let _ = tx.msg(Arc::new(Mutex::new((self.name.to_string(), new_vec))));
+        let (is_autoflush, n_added) = {
+            let mut vecs = self.vecs.write().unwrap();
+            if vecs.inserts.max_ts == 0 {
+                let disk_max_ts = vecs.segments.iter().map(|seg| seg.max_ts).max().unwrap_or(0);
+                let mem_max_ts = in_memory(&vecs).iter().filter_map(|ups| ups.last()).map(|up| up.ts).max().unwrap_or(0);
+                vecs.inserts.max_ts = disk_max_ts.max(mem_max_ts);
+            }
+            if vecs.inserts.dedupe {
+                let vecs = &mut *vecs;
+                vecs.inserts.track_disk(&vecs.segments, &vecs.flushing.files, new_vecs);
             }
+            let new_vecs = vecs.inserts.admit(new_vecs)?;
 
-            let is_autoflush = wtr.settings.autoflush;
-            let is_bulkadding = wtr.is_bulkadding;
-            let flush_interval = wtr.settings.flush_interval;
-            let _folder = wtr.settings.dtf_folder.to_owned();
-            let name: &str = self.name.borrow();
-            let vecs = wtr.vec_store.get_mut(name).expect(
-                "KEY IS NOT IN HASHMAP",
-            );
+            let old_size = vecs.ups.len();
+            vecs.ups.extend_from_slice(&new_vecs);
+            vecs.account(&self.mem);
+            vecs.count += new_vecs.len() as u64;
+            vecs.last_insert = Some(SystemTime::now());
 
-            vecs.0.push(new_vec);
-            vecs.1 += 1;
+            // send to insertion firehose; doesn't block, and keeps subscribers in insertion order
+            for new_vec in &new_vecs {
+                subs.msg((self.name.to_string(), *new_vec));
+            }
 
             // Saves current store into disk after n items is inserted.
-            let size = vecs.0.len(); // using the raw len so won't have race condition with load_size_from_file
-            let is_autoflush = is_autoflush && size != 0 && (size as u32) % flush_interval == 0;
+            let size = vecs.ups.len(); // using the raw len so won't have race condition with load_size_from_file
+            let is_autoflush = is_autoflush && flush_interval != 0
+                && size / flush_interval != old_size / flush_interval;
 
             if is_autoflush {
                 debug!(
                     "AUTOFLUSHING {}! Size: {} Last: {:?}",
                     self.name,
-                    vecs.1,
-                    vecs.0.last().clone().unwrap()
+                    vecs.count,
+                    vecs.ups.last().clone().unwrap()
                 );
             }
 
-            (is_autoflush, is_bulkadding)
+            (is_autoflush, new_vecs.len())
         };
 
-        if is_autoflush && !is_bulkadding {
+        if is_autoflush {
             self.flush();
         }
+        Ok(n_added)
     }
 
     pub fn count(&self) -> u64 {
-        let rdr = self.global.read().unwrap();
-        let name: &str = self.name.borrow();
-        let vecs = rdr.vec_store.get(name).expect(
-            "KEY IS NOT IN HASHMAP",
-        );
-        vecs.1
+        self.vecs.read().unwrap().count
     }
 
     pub fn count_in_mem(&self) -> u64 {
-        let rdr = self.global.read().unwrap();
-        let name: &str = self.name.borrow();
-        let vecs = rdr.vec_store.get(name).expect(
-            "KEY IS NOT IN HASHMAP",
-        );
-        vecs.0.len() as u64
+        let vecs = self.vecs.read().unwrap();
+        in_memory(&vecs).iter().map(|ups| ups.len() as u64).sum()
     }
 
-    /// write items stored in memory into file
-    /// If file exists, use append which only appends a filtered set of updates whose timestamp is larger than the old timestamp
-    /// If file doesn't exists, simply encode.
+    /// write items stored in memory into the segment files of the store
+    /// Updates are grouped by the partition their timestamp falls in, so a flush after the
+    /// period turns over rotates into a new file.
+    /// If a segment exists, use append which only appends a filtered set of updates whose timestamp is larger than the old timestamp
+    /// If it doesn't exists, simply encode.
     ///
-    pub fn flush(&mut self) -> Option<bool> {
-        {
-            let mut rdr = self.global.write().unwrap(); // use a write lock to block write in client processes
-            let folder = rdr.settings.dtf_folder.to_owned();
-            let name: &str = self.name.borrow();
-            let vecs = rdr.vec_store.get_mut(name).expect(
-                "KEY IS NOT IN HASHMAP",
-            );
-            let fullfname = format!("{}/{}.dtf", &folder, self.fname);
-            utils::create_dir_if_not_exist(&folder);
+    /// Memory is only swapped out under the store's lock, the files are written by the
+    /// `FlushPool`, and the returned `PendingFlush` waits for them. Until then queries still
+    /// see the updates. Updates that couldn't be written go back into memory, and the result
+    /// is the error. None if there's nothing to flush.
+    pub fn flush(&self) -> Option<PendingFlush> {
+        let buffer = {
+            let mut vecs = self.vecs.write().unwrap(); // use a write lock to block writes into this store
+            if vecs.ups.is_empty() {
+                return None;
+            }
+            let buffer = Arc::new(Buffer {
+                ups: mem::replace(&mut vecs.ups, MemStore::new()),
+                loaded: self.in_memory.load(Ordering::SeqCst),
+                late_policy: vecs.inserts.late_policy,
+                dedupe: vecs.inserts.dedupe,
+                result: Mutex::new(None),
+                done: Condvar::new(),
+            });
+            vecs.flushing.queue.push_back(buffer.clone());
+            // continue clear
+            self.in_memory.store(false, Ordering::SeqCst);
+            buffer
+        };
 
-            let fpath = Path::new(&fullfname);
-            let result = if fpath.exists() {
-                dtf::append(&fullfname, &vecs.0)
-            } else {
-                dtf::encode(&fullfname, &self.name, &vecs.0)
-            };
-            match result {
-                Ok(_) => debug!("Successfully flushed."),
-                Err(err) => error!("Error flushing file: {:?}", err),
-            };
+        let pool = self.global.read().unwrap().flush_pool.clone();
+        pool.submit(self.flusher());
+        Some(PendingFlush { buffer })
+    }
 
-            // clear
-            vecs.0.clear();
-        }
-
-        // continue clear
-        self.in_memory = false;
-        Some(true)
-    }
-
-    /// load items from dtf file
-    fn load(&mut self) {
-        let folder = self.global.read().unwrap().settings.dtf_folder.to_owned();
-        let fname = format!("{}/{}.dtf", &folder, self.name);
-        if Path::new(&fname).exists() && !self.in_memory {
-            // let file_item_count = dtf::read_meta(&fname).nums;
-            // // when we have more items in memory, don't load
-            // if file_item_count < self.count() {
-            //     warn!("There are more items in memory than in file. Cannot load from file.");
-            //     return;
-            // }
-            let ups = dtf::decode(&fname, None);
-            if ups.is_err() {
-                error!("Unable to decode file during load!");
-                return;
-            } else {
-                let mut ups = ups.unwrap();
-                let mut wtr = self.global.write().unwrap();
-                // let size = ups.len() as u64;
-                let name: &str = self.name.borrow();
-                let vecs = wtr.vec_store.get_mut(name).unwrap();
-                vecs.0.append(&mut ups);
-                // wtr.vec_store.insert(self.name.to_owned(), (ups, size));
-                self.in_memory = true;
-            }
-        }
+    /// Writes what's waiting to be flushed, returning the store locked with nothing left.
+    ///
+    /// For changes to the files, the caller must hold `Flushing::lock` so that no flush
+    /// starts writing meanwhile.
+    fn settle_flushes(&self) -> RwLockWriteGuard<VecStore> {
+        self.flusher().settle(&self.vecs)
     }
 
-    /// load size from file
-    pub fn load_size_from_file(&mut self) {
-        let header_size = {
+    fn flusher(&self) -> Flusher {
+        let (folder, partition) = {
             let rdr = self.global.read().unwrap();
-            let folder = rdr.settings.dtf_folder.to_owned();
-            let fname = format!("{}/{}.dtf", &folder, self.name);
-            dtf::get_size(&fname)
+            (rdr.settings.dtf_folder.to_owned(), rdr.settings.partition)
         };
-        match header_size {
-            Ok(header_size) => {
-                let mut wtr = self.global.write().unwrap();
-                let name: &str = &self.name.borrow();
-                wtr.vec_store
-                    .get_mut(name)
-                    .expect("Key is not in vec_store")
-                    .1 = header_size;
+        Flusher {
+            folder,
+            partition,
+            vecs: self.vecs.clone(),
+            mem: self.mem.clone(),
+            catalog: self.catalog.clone(),
+        }
+    }
+
+    /// Replays every stored update with `ts >= from_ts` that passes `filter` to `push_tx`,
+    /// then the updates pushed live to the subscription it makes to this store.
+    ///
+    /// Inserts publish while holding this store's write lock, so the replay is opened as a
+    /// `Cursor` and subscribed under it: every update is either replayed or pushed live, never
+    /// both and never neither. The cursor reads the files on as the client takes updates, see
+    /// `PushTX::replay`.
+    pub fn replay_and_sub(&self, from_ts: u64, filter: Filter, push_tx: PushTX, subs: &Subscriptions) -> usize {
+        let vecs = self.vecs.write().unwrap();
+        // once loaded, memory holds the segments as well
+        let loaded = self.in_memory.load(Ordering::SeqCst);
+        let cursor = Cursor::open(&vecs, from_ts, u64::max_value(), !loaded, &self.mem);
+        push_tx.replay(Box::new(Replay {
+            name: self.name.to_string(),
+            filter: filter.clone(),
+            cursor,
+            batch: VecDeque::new(),
+        }));
+        subs.sub(self.name.to_string(), filter, push_tx)
+    }
+
+    /// load items from the segment files
+    fn load(&self) {
+        if self.in_memory.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut vecs = self.vecs.write().unwrap();
+        if vecs.segments.is_empty() {
+            return;
+        }
+        let mut ups = vec![];
+        for seg in vecs.segments.iter() {
+            match dtf::decode(&seg.fname, None) {
+                Ok(seg_ups) => ups.extend(seg_ups),
+                Err(_) => {
+                    error!("Unable to decode file during load!");
+                    return;
+                }
+            }
+        }
+        vecs.inserts.track(&ups);
+        vecs.ups.append(&mut ups);
+        vecs.account(&self.mem);
+        self.in_memory.store(true, Ordering::SeqCst);
+    }
+
+    /// load size from the headers of the segment files
+    pub fn load_size_from_file(&self) {
+        let mut vecs = self.vecs.write().unwrap();
+        vecs.count = vecs.segments.iter().map(|seg| seg.count).sum();
+    }
+
+    /// Removes every update with `min_ts <= ts <= max_ts` from memory and from the dtf files
+    /// of this store, returning how many were removed.
+    pub fn delete_range(&self, min_ts: u64, max_ts: u64) -> Result<u64, String> {
+        let lock = self.vecs.read().unwrap().flushing.lock.clone();
+        let _writing = lock.lock().unwrap();
+        let mut vecs = self.settle_flushes(); // block inserts and flushes while rewriting
+
+        let segments = vecs.segments.clone();
+        let disk_max_ts = segments.iter().map(|seg| seg.max_ts).max();
+        let mut removed = 0;
+        for Segment { fname, min_ts: seg_min_ts, max_ts: seg_max_ts, .. } in segments {
+            if !within_range(min_ts, max_ts, seg_min_ts, seg_max_ts) {
+                continue;
             }
-            Err(_) => {
-                error!("Unable to read header size from file");
+            let ups = dtf::decode(&fname, None)
+                .map_err(|err| format!("Unable to read {}: {}", fname, err))?;
+            let len = ups.len();
+            let kept: Vec<Update> = ups.into_iter().filter(|up| up.ts < min_ts || up.ts > max_ts).collect();
+            if kept.len() == len {
+                continue;
             }
+            let result = if kept.is_empty() {
+                fs::remove_file(&fname)
+            } else {
+                dtf::rewrite(&fname, &self.name, &kept)
+            };
+            result.map_err(|err| format!("Unable to rewrite {}: {}", fname, err))?;
+            self.catalog.refresh(&mut vecs.segments, &fname);
+            removed += (len - kept.len()) as u64;
+        }
+
+        removed += self.remove_from_memory(&mut vecs, min_ts, max_ts, disk_max_ts);
+        vecs.count = vecs.count.saturating_sub(removed);
+
+        Ok(removed)
+    }
+
+    /// Removes the updates older than `cutoff` from memory, returning how many of them weren't
+    /// also on disk. Buffers being flushed are left, they're in the files next.
+    pub fn expire_memory(&self, cutoff: u64) -> u64 {
+        let mut vecs = self.vecs.write().unwrap();
+        if cutoff == 0 || vecs.ups.first().map_or(true, |up| up.ts >= cutoff) {
+            return 0;
         }
+        let disk_max_ts = vecs.segments.iter().map(|seg| seg.max_ts).max();
+        let removed = self.remove_from_memory(&mut vecs, 0, cutoff - 1, disk_max_ts);
+        vecs.count = vecs.count.saturating_sub(removed);
+        removed
+    }
+
+    /// Removes the updates with `min_ts <= ts <= max_ts` from memory, returning how many of
+    /// them weren't copies of files up to `disk_max_ts`, which `USE` reads in.
+    fn remove_from_memory(&self, vecs: &mut VecStore, min_ts: u64, max_ts: u64, disk_max_ts: Option<u64>) -> u64 {
+        let is_loaded = self.in_memory.load(Ordering::SeqCst);
+        let mut removed_copies = 0;
+        let len = vecs.ups.len();
+        vecs.ups.retain(|up| {
+            let is_deleted = up.ts >= min_ts && up.ts <= max_ts;
+            if is_deleted && is_loaded && disk_max_ts.map_or(false, |max_ts| up.ts <= max_ts) {
+                removed_copies += 1;
+            }
+            !is_deleted
+        });
+        // the newest update may be gone
+        vecs.inserts.reset();
+        vecs.track_memory();
+        vecs.account(&self.mem);
+        (len - vecs.ups.len() - removed_copies) as u64
     }
 
     /// clear the vector. toggle in_memory. update size
-    pub fn clear(&mut self) {
+    pub fn clear(&self) {
         {
-            let mut rdr = self.global.write().unwrap();
-            let name: &str = self.name.borrow();
-            let vecs = (*rdr).vec_store.get_mut(name).expect(
-                "KEY IS NOT IN HASHMAP",
-            );
-            vecs.0.clear();
-            // vecs.1 = 0;
+            let mut vecs = self.vecs.write().unwrap();
+            vecs.ups.clear();
+            vecs.inserts.reset();
+            vecs.track_memory();
+            vecs.account(&self.mem);
+            // vecs.count = 0;
         }
-        self.in_memory = false;
+        self.in_memory.store(false, Ordering::SeqCst);
         self.load_size_from_file();
     }
 }
 
 /// Each client gets its own ThreadState
 pub struct ThreadState<'thr, 'store> {
-    /// Current selected db using `BULKADD INTO [db]`
+    /// Current selected db using `BULKADD INTO [db]`, set while inside a BULKADD
     pub bulkadd_db: Option<String>,
-    /// Is client subscribe?
-    pub is_subscribed: bool,
-    /// current subscribed db
-    pub subscribed_db: Option<String>,
-    /// current receiver
-    pub sub_id: Option<usize>,
-    /// current receiver
-    pub rx: Option<Arc<Mutex<mpsc::Receiver<Update>>>>,
+    /// rows staged by the current BULKADD, committed on DDAKLUB
+    pub bulkadd_buf: Vec<Update>,
+    /// subscribed store or glob pattern -> subscription id
+    pub subscriptions: HashMap<String, usize>,
 
     pub subscription_tx: SubscriptionTX,
 
@@ -240,6 +378,16 @@ pub struct ThreadState<'thr, 'store> {
     /// the current STORE client is using
     pub current_store_name: Cow<'thr, str>,
 
+    /// cursors opened with `OPEN CURSOR`, by id
+    pub cursors: HashMap<u64, Cursor>,
+
+    /// `SharedState::generation`, and its value when this client last looked at the stores
+    pub generation: Arc<AtomicUsize>,
+    pub seen_generation: usize,
+
+    /// id of the next cursor
+    pub next_cursor_id: u64,
+
     /// shared data
     pub global: Global,
 }
@@ -248,8 +396,8 @@ macro_rules! current_store {
     ($sel:ident, $fun:ident) => {
         {
             let name: &str = $sel.current_store_name.borrow();
-            let mut store = $sel.store.write().unwrap();
-            store.get_mut(name).expect(
+            let store = $sel.store.read().unwrap();
+            store.get(name).expect(
                 "KEY IS NOT IN HASHMAP",
             ).$fun()
         }
@@ -258,8 +406,8 @@ macro_rules! current_store {
     ($sel:ident, $fun:ident, $param:ident) => {
         {
             let name: &str = $sel.current_store_name.borrow();
-            let mut store = $sel.store.write().unwrap();
-            store.get_mut(name).expect(
+            let store = $sel.store.read().unwrap();
+            store.get(name).expect(
                 "KEY IS NOT IN HASHMAP",
             ).$fun($param)
         }
@@ -268,10 +416,10 @@ macro_rules! current_store {
 
 macro_rules! store {
     ($sel:ident, $fun:ident) => {
-        $sel.store.write().unwrap().$fun()
+        $sel.store.read().unwrap().$fun()
     };
     ($sel:ident, $fun:ident, $param:ident) => {
-        $sel.store.write().unwrap().$fun($param)
+        $sel.store.read().unwrap().$fun($param)
     };
 }
 
@@ -298,17 +446,21 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
             .iter()
             .map(|i| {
                 let (key, value) = i;
-                let vecs = &value.0;
-                let size = value.1;
+                let value = value.read().unwrap();
+                let vecs = &value.ups;
+                let size = value.count;
+                let mem_bytes = value.mem_bytes;
                 format!(
                     r#"{{
     "name": "{}",
     "in_memory": {},
-    "count": {}
+    "count": {},
+    "mem_bytes": {}
   }}"#,
                     key,
                     !vecs.is_empty(),
-                    size
+                    size,
+                    mem_bytes
                 )
             })
             .collect();
@@ -321,7 +473,16 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
     "autoflush_enabled": {},
     "autoflush_interval": {},
     "dtf_folder": "{}",
-    "total_count": {}
+    "total_count": {},
+    "sub_queue_capacity": {},
+    "sub_queue_policy": "{}",
+    "sub_dropped": {},
+    "sub_disconnected": {},
+    "mem_used": {},
+    "mem_budget": {},
+    "mem_policy": "{}",
+    "mem_forced_flushes": {},
+    "mem_rejected": {}
   }}"#,
 
             rdr.n_cxns,
@@ -334,8 +495,17 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
             rdr.settings.dtf_folder,
             rdr.vec_store.iter().fold(
                 0,
-                |acc, (_name, tup)| acc + tup.1,
-            )
+                |acc, (_name, tup)| acc + tup.read().unwrap().count,
+            ),
+            rdr.subs.queue_capacity(),
+            rdr.subs.queue_policy(),
+            rdr.subs.stats.dropped.load(Ordering::Relaxed),
+            rdr.subs.stats.disconnected.load(Ordering::Relaxed),
+            rdr.mem.used(),
+            rdr.settings.memory_budget,
+            rdr.settings.memory_policy,
+            rdr.mem.forced_flushes.load(Ordering::Relaxed),
+            rdr.mem.rejected.load(Ordering::Relaxed)
         );
         let mut ret = format!(
             r#"{{
@@ -348,6 +518,128 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         ret.push('\n');
         ret
     }
+    /// Removes the updates of a store within [min_ts, max_ts], in ms
+    pub fn delete_range(&mut self, store_name: &str, min_ts: u64, max_ts: u64) -> Result<u64, String> {
+        let stores = self.store.read().unwrap();
+        let store = stores.get(store_name)
+            .ok_or_else(|| format!("DB {} not found.", store_name))?;
+        store.delete_range(min_ts, max_ts)
+    }
+
+    /// Turns deduplication of inserts into a store on or off
+    pub fn set_dedupe(&mut self, store_name: &str, dedupe: bool) -> Result<(), String> {
+        let stores = self.store.read().unwrap();
+        let store = stores.get(store_name)
+            .ok_or_else(|| format!("DB {} not found.", store_name))?;
+        {
+            let mut vecs = store.vecs.write().unwrap();
+            vecs.inserts.dedupe = dedupe;
+            vecs.track_memory();
+            vecs.account(&store.mem);
+        }
+        self.global.read().unwrap().policies.set_dedupe(store_name, dedupe);
+        Ok(())
+    }
+
+    /// Sets what a store does with late updates
+    pub fn set_late_policy(&mut self, store_name: &str, late_policy: LatePolicy) -> Result<(), String> {
+        let stores = self.store.read().unwrap();
+        let store = stores.get(store_name)
+            .ok_or_else(|| format!("DB {} not found.", store_name))?;
+        store.vecs.write().unwrap().inserts.late_policy = late_policy;
+        self.global.read().unwrap().policies.set_late_policy(store_name, late_policy);
+        Ok(())
+    }
+
+    /// Turns rejecting malformed updates into a store on or off, see `Validation`
+    pub fn set_reject_malformed(&mut self, store_name: &str, reject_malformed: bool) -> Result<(), String> {
+        let stores = self.store.read().unwrap();
+        let store = stores.get(store_name)
+            .ok_or_else(|| format!("DB {} not found.", store_name))?;
+        store.vecs.write().unwrap().inserts.validation.reject_malformed = reject_malformed;
+        self.global.read().unwrap().policies.set_reject_malformed(store_name, reject_malformed);
+        Ok(())
+    }
+
+    /// Sets or, with `None`, removes a validation limit of a store
+    pub fn set_validation(&mut self, store_name: &str, rule: ValidationRule, value: Option<u64>) -> Result<(), String> {
+        let stores = self.store.read().unwrap();
+        let store = stores.get(store_name)
+            .ok_or_else(|| format!("DB {} not found.", store_name))?;
+        store.vecs.write().unwrap().inserts.validation.set(rule, value);
+        self.global.read().unwrap().policies.set_limit(store_name, rule, value);
+        Ok(())
+    }
+
+    /// Returns the names of all stores as a JSON array
+    pub fn show_databases(&self) -> String {
+        let mut names: Vec<String> = self.store.read().unwrap().keys().cloned().collect();
+        names.sort();
+        format!("{}\n", serde_json::to_string(&names).unwrap())
+    }
+
+    /// Returns a JSON object describing a store, None if it doesn't exist
+    pub fn describe(&self, store_name: &str) -> Option<String> {
+        let subscribers = self.global.read().unwrap().subs.count_for(store_name);
+        let (count, in_memory_count, mem_range, last_insert, inserts, segments) = {
+            let stores = self.store.read().unwrap();
+            let vecs = stores.get(store_name)?.vecs.read().unwrap();
+            let mems = in_memory(&vecs);
+            let min_ts = mems.iter().filter_map(|ups| ups.first()).map(|up| up.ts).min();
+            let max_ts = mems.iter().filter_map(|ups| ups.last()).map(|up| up.ts).max();
+            let in_memory_count = mems.iter().map(|ups| ups.len() as u64).sum();
+            (vecs.count, in_memory_count, min_ts.and_then(|min_ts| max_ts.map(|max_ts| (min_ts, max_ts))), vecs.last_insert, vecs.inserts.clone(), vecs.segments.clone())
+        };
+
+        let files: Vec<FileDescription> = segments
+            .into_iter()
+            .map(|seg| FileDescription {
+                size: fs::metadata(&seg.fname).map(|meta| meta.len()).unwrap_or(0),
+                path: seg.fname,
+                count: seg.count,
+                min_ts: seg.min_ts,
+                max_ts: seg.max_ts,
+            })
+            .collect();
+
+        let ranges = files.iter().map(|f| (f.min_ts, f.max_ts)).chain(mem_range);
+        let min_ts = ranges.clone().map(|r| r.0).min();
+        let max_ts = ranges.map(|r| r.1).max();
+
+        let description = DbDescription {
+            name: store_name.to_owned(),
+            count,
+            in_memory_count,
+            on_disk_count: files.iter().map(|f| f.count).sum(),
+            min_ts,
+            max_ts,
+            symbol: dtf::Symbol::from_str(store_name).map(|sym| SymbolDescription {
+                exchange: sym.exchange,
+                currency: sym.currency,
+                asset: sym.asset,
+            }),
+            last_insert: last_insert.map(|t| {
+                let t = t.duration_since(UNIX_EPOCH).expect("Time went backwards");
+                t.as_secs() * 1000 + u64::from(t.subsec_nanos()) / 1_000_000
+            }),
+            subscribers,
+            files,
+            dedupe: inserts.dedupe,
+            late_updates: inserts.late_policy.to_string(),
+            duplicates: inserts.duplicates,
+            late: inserts.late,
+            invalid: inserts.invalid,
+            dropped: inserts.dropped,
+            validation: ValidationDescription {
+                reject_malformed: inserts.validation.reject_malformed,
+                max_future_secs: inserts.validation.max_future.map(|ms| ms / 1000),
+                max_past_secs: inserts.validation.max_past.map(|ms| ms / 1000),
+                max_seq_regression: inserts.validation.max_seq_regression,
+            },
+        };
+        Some(format!("{}\n", serde_json::to_string_pretty(&description).unwrap()))
+    }
+
     /// Returns a JSON object like
     /// [{"total": [1508968738: 0]}, {"default": [1508968738: 0]}]
     pub fn perf(&self) -> String {
@@ -369,13 +661,11 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
     }
 
     /// Insert a row into store
-    pub fn insert(&mut self, up: Update, store_name: &str) -> Option<()> {
-        match store!(self, get_mut, store_name) {
-            Some(store) => {
-                store.add(up);
-                Some(())
-            }
-            None => None,
+    pub fn insert(&mut self, up: Update, store_name: &str) -> Result<(), String> {
+        let _reserved = self.reserve_memory(1)?;
+        match store!(self, get, store_name) {
+            Some(store) => store.add(up),
+            None => Err(format!("DB {} not found.", store_name)),
         }
     }
 
@@ -385,8 +675,52 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
     }
 
     /// Insert a row into current store.
-    pub fn add(&mut self, up: Update) {
-        current_store!(self, add, up);
+    pub fn add(&mut self, up: Update) -> Result<(), String> {
+        let _reserved = self.reserve_memory(1)?;
+        current_store!(self, add, up)
+    }
+
+    /// Makes room for `n` more updates within the memory budget, flushing the stores holding
+    /// the most updates or failing depending on the `MemoryPolicy`.
+    ///
+    /// The room is held until the returned `Reservation` is dropped, so that concurrent
+    /// inserts can't overrun the budget together.
+    pub fn reserve_memory(&mut self, n: usize) -> Result<Reservation, String> {
+        let (budget, policy, mem) = {
+            let rdr = self.global.read().unwrap();
+            (rdr.settings.memory_budget, rdr.settings.memory_policy, rdr.mem.clone())
+        };
+        let needed = n * UPDATE_SIZE;
+        if mem.try_reserve(needed, budget) {
+            return Ok(Reservation { mem, bytes: needed });
+        }
+
+        if policy == MemoryPolicy::Flush {
+            let stores = self.store.read().unwrap();
+            let mut stores: Vec<&Store> = stores.values().collect();
+            stores.sort_by_key(|store| ::std::cmp::Reverse(store.count_in_mem()));
+            for store in stores {
+                if mem.try_reserve(needed, budget) {
+                    return Ok(Reservation { mem, bytes: needed });
+                }
+                if store.count_in_mem() == 0 {
+                    break;
+                }
+                info!("Memory budget of {} bytes exceeded, flushing {}.", budget, store.name);
+                // memory is only freed once written
+                if let Some(flushing) = store.flush() {
+                    if let Err(err) = flushing.wait() {
+                        error!("Unable to flush {}: {}", store.name, err);
+                    }
+                }
+                mem.forced_flushes.fetch_add(1, Ordering::Relaxed);
+            }
+            if mem.try_reserve(needed, budget) {
+                return Ok(Reservation { mem, bytes: needed });
+            }
+        }
+        mem.rejected.fetch_add(n, Ordering::Relaxed);
+        Err(format!("Memory budget of {} bytes exceeded, retry after a flush.", budget))
     }
 
     pub fn set_autoflush(&mut self, is_autoflush: bool) {
@@ -394,38 +728,265 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         global.settings.autoflush = is_autoflush;
     }
 
-    pub fn set_bulkadding(&mut self, is_bulkadding: bool) {
-        let mut global = self.global.write().unwrap();
-        global.is_bulkadding = is_bulkadding;
+    /// Starts staging updates for `store_name`. Nothing is visible until `end_bulkadd`.
+    pub fn begin_bulkadd(&mut self, store_name: &str) {
+        self.bulkadd_db = Some(store_name.to_owned());
+        self.bulkadd_buf.clear();
+    }
+
+    pub fn is_bulkadding(&self) -> bool {
+        self.bulkadd_db.is_some()
+    }
+
+    /// Stages a row of the current BULKADD.
+    pub fn stage(&mut self, up: Update) {
+        self.bulkadd_buf.push(up);
+    }
+
+    /// Commits the staged rows in one go, returning how many were stored. The batch is
+    /// discarded if the store doesn't exist or rejects it.
+    pub fn end_bulkadd(&mut self) -> Result<usize, String> {
+        let store_name = match self.bulkadd_db.take() {
+            Some(store_name) => store_name,
+            None => return Ok(0),
+        };
+        let batch = ::std::mem::replace(&mut self.bulkadd_buf, vec![]);
+        let _reserved = self.reserve_memory(batch.len())?;
+        let store_name: &str = &store_name;
+        match store!(self, get, store_name) {
+            Some(store) => store.add_all(&batch),
+            None => Err(format!("DB {} not found.", store_name)),
+        }
     }
 
-    pub fn get_bulkadding(&mut self) -> bool {
-        let global = self.global.read().unwrap();
-        global.is_bulkadding
+    /// Drops the staged rows, e.g. when the client disconnects mid-BULKADD.
+    pub fn abort_bulkadd(&mut self) {
+        self.bulkadd_db = None;
+        self.bulkadd_buf.clear();
     }
 
     /// Create a new store
     pub fn create(&mut self, store_name: &str) {
-        // insert a vector into shared hashmap
+        // inserts a vector into shared hashmap unless the store already exists
+        let store = Store::new(store_name, store_name, false, &self.global);
+
+        // insert a store into client state hashmap
+        self.store.write().unwrap()
+            .entry(store_name.to_owned())
+            .or_insert(store);
+    }
+
+    /// Removes a store from memory, and its file too if `with_file`. Subscriptions to it end.
+    ///
+    /// What's waiting to be flushed is written first under the store's flush lock only, then
+    /// the store map is held just to remove the entry, so other stores aren't held up by the
+    /// disk. The files are removed under the flush lock again, once the store is gone.
+    pub fn drop_db(&mut self, store_name: &str, with_file: bool) -> Result<(), String> {
+        if store_name == "default" {
+            return Err("Cannot drop the default store.".to_owned());
+        }
+        let (flusher, shared_vecs) = {
+            let stores = self.store.read().unwrap();
+            let store = stores.get(store_name).ok_or_else(|| format!("DB {} not found.", store_name))?;
+            (store.flusher(), store.vecs.clone())
+        };
+        let lock = shared_vecs.read().unwrap().flushing.lock.clone();
+        if with_file {
+            let _writing = lock.lock().unwrap();
+            drop(flusher.settle(&shared_vecs));
+        }
+
         {
+            let mut stores = self.store.write().unwrap();
+            match stores.get(store_name) {
+                Some(store) if Arc::ptr_eq(&store.vecs, &shared_vecs) => (),
+                _ => return Err(format!("DB {} not found.", store_name)),
+            }
+            stores.remove(store_name);
+            self.generation.fetch_add(1, Ordering::SeqCst);
             let mut global = self.global.write().unwrap();
-            global.vec_store.insert(
-                store_name.to_owned(),
-                (box Vec::new(), 0),
-            );
+            global.subs.remove(store_name);
+            if with_file {
+                global.policies.remove(store_name);
+            }
+            global.vec_store.remove(store_name);
         }
 
-        // insert a store into client state hashmap
-        let store_name = String::from(store_name);
-        self.store.write().unwrap().insert(
-            store_name.clone(),
-            Store {
-                name: store_name.clone().into(),
-                fname: store_name.into(),
-                in_memory: false,
-                global: self.global.clone(),
-            },
-        );
+        let _writing = lock.lock().unwrap();
+        let mut vecs = if with_file {
+            // flushes started before the store was gone
+            flusher.settle(&shared_vecs)
+        } else {
+            shared_vecs.write().unwrap()
+        };
+        let vecs = &mut *vecs;
+        // what's still being flushed is released by the flush
+        vecs.ups.clear();
+        vecs.track_memory();
+        vecs.account(&flusher.mem);
+        if with_file {
+            for seg in vecs.segments.clone() {
+                if Path::new(&seg.fname).exists() {
+                    fs::remove_file(&seg.fname)
+                        .map_err(|err| format!("Unable to remove {}: {}", seg.fname, err))?;
+                }
+                flusher.catalog.refresh(&mut vecs.segments, &seg.fname);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames a store along with its segment files. Subscriptions to it follow.
+    ///
+    /// The name changes at once under the store map. The files are then moved and their
+    /// headers rewritten in place under the store's flush lock only, so other stores aren't
+    /// held up. If a file can't be moved, those moved are put back along with the name.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), String> {
+        if from == "default" {
+            return Err("Cannot rename the default store.".to_owned());
+        }
+        self.swap_store_name(from, to)?;
+        if let Err(err) = self.move_segments(to, to) {
+            if let Err(back) = self.swap_store_name(to, from) {
+                error!("Unable to rename {} back to {}: {}", to, from, back);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Moves store `from`, and subscriptions to it, over to the name `to`.
+    fn swap_store_name(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let mut stores = self.store.write().unwrap();
+        if stores.contains_key(to) {
+            return Err(format!("DB {} already exists.", to));
+        }
+        let store = stores.remove(from).ok_or_else(|| format!("DB {} not found.", from))?;
+        {
+            let mut global = self.global.write().unwrap();
+            if let Some(vecs) = global.vec_store.remove(from) {
+                global.vec_store.insert(to.to_owned(), vecs);
+            }
+            global.subs.rename(from, to);
+            global.policies.rename(from, to);
+        }
+        let in_memory = store.in_memory.load(Ordering::SeqCst);
+        stores.insert(to.to_owned(), Store::new(to, to, in_memory, &self.global));
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Moves the segment files of `store_name` over to names after `to`, after writing what's
+    /// waiting to be flushed. Later flushes write files named after `to`.
+    fn move_segments(&self, store_name: &str, to: &str) -> Result<(), String> {
+        let (flusher, shared_vecs) = {
+            let stores = self.store.read().unwrap();
+            let store = stores.get(store_name).ok_or_else(|| format!("DB {} not found.", store_name))?;
+            (store.flusher(), store.vecs.clone())
+        };
+        let lock = shared_vecs.read().unwrap().flushing.lock.clone();
+        let _writing = lock.lock().unwrap();
+        let mut vecs = flusher.settle(&shared_vecs);
+        let vecs = &mut *vecs;
+        let files = vecs.flushing.files.clone();
+        let _moving = files.write().unwrap();
+
+        let from = vecs.flushing.name.clone();
+        let mut moved: Vec<(String, String)> = vec![];
+        for seg in &vecs.segments {
+            let dst = renamed_segment(&seg.fname, &from, to);
+            if let Err(err) = move_dtf(&seg.fname, &dst, to) {
+                for (src, dst) in moved.into_iter().rev() {
+                    if let Err(err) = move_dtf(&dst, &src, &from) {
+                        error!("Unable to move {} back: {}", dst, err);
+                    }
+                }
+                return Err(err);
+            }
+            moved.push((seg.fname.clone(), dst));
+        }
+        for (src, dst) in moved {
+            flusher.catalog.refresh(&mut vecs.segments, &src);
+            flusher.catalog.refresh(&mut vecs.segments, &dst);
+        }
+        vecs.flushing.name = to.to_owned();
+        Ok(())
+    }
+
+    /// Copies a store, both what's in memory and its segment files.
+    ///
+    /// The store is copied as of when what was waiting to be flushed is written, only its
+    /// flush lock is held while the files are copied.
+    pub fn copy(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let (flusher, shared_vecs, fname, in_memory) = {
+            let stores = self.store.read().unwrap();
+            if stores.contains_key(to) {
+                return Err(format!("DB {} already exists.", to));
+            }
+            let store = stores.get(from).ok_or_else(|| format!("DB {} not found.", from))?;
+            (store.flusher(), store.vecs.clone(), store.fname.to_string(), store.in_memory.load(Ordering::SeqCst))
+        };
+        let mut vecs = {
+            let lock = shared_vecs.read().unwrap().flushing.lock.clone();
+            let _writing = lock.lock().unwrap();
+            let vecs = flusher.settle(&shared_vecs).clone();
+            let files = vecs.flushing.files.clone();
+            let _reading = files.read().unwrap();
+            let mut copies: Vec<Segment> = vec![];
+            for seg in &vecs.segments {
+                let dst = renamed_segment(&seg.fname, &fname, to);
+                if let Err(err) = copy_dtf(&seg.fname, &dst, to) {
+                    remove_copies(&flusher.catalog, copies);
+                    return Err(err);
+                }
+                flusher.catalog.refresh(&mut copies, &dst);
+            }
+            VecStore { segments: copies, ..vecs }
+        };
+        vecs.flushing = Flushing { name: to.to_owned(), ..Default::default() };
+
+        let mut stores = self.store.write().unwrap();
+        if stores.contains_key(to) {
+            remove_copies(&flusher.catalog, vecs.segments);
+            return Err(format!("DB {} already exists.", to));
+        }
+        {
+            let mut wtr = self.global.write().unwrap();
+            vecs.mem_bytes = 0;
+            vecs.account(&wtr.mem);
+            wtr.vec_store.insert(to.to_owned(), Arc::new(RwLock::new(vecs)));
+            wtr.policies.copy(from, to);
+        }
+        stores.insert(to.to_owned(), Store::new(to, to, in_memory, &self.global));
+        Ok(())
+    }
+
+    /// Catches up with stores other clients dropped or renamed, once one was: switches back
+    /// to `default` if the current store is gone, and follows subscriptions to renamed stores.
+    pub fn follow_store_changes(&mut self) {
+        let generation = self.generation.load(Ordering::SeqCst);
+        if generation == self.seen_generation {
+            return;
+        }
+        self.seen_generation = generation;
+
+        let name: &str = self.current_store_name.borrow();
+        if !store!(self, contains_key, name) {
+            self.current_store_name = "default".into();
+        }
+
+        let subs = self.global.read().unwrap().subs.clone();
+        let subscriptions: Vec<usize> = self.subscriptions.drain().map(|(_, id)| id).collect();
+        for id in subscriptions {
+            match subs.pattern_of(id) {
+                // renamed onto a store this client is subscribed to already
+                Some(ref pattern) if self.subscriptions.contains_key(pattern) => subs.unsub(id),
+                Some(pattern) => {
+                    self.subscriptions.insert(pattern, id);
+                }
+                None => (),
+            }
+        }
     }
 
     /// load a datastore file into memory
@@ -454,7 +1015,7 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         let rdr = self.global.read().unwrap();
         rdr.vec_store.iter().fold(
             0,
-            |acc, (_name, tup)| acc + tup.0.len(),
+            |acc, (_name, tup)| acc + in_memory(&tup.read().unwrap()).iter().map(|ups| ups.len()).sum::<usize>(),
         ) as u64
     }
 
@@ -463,43 +1024,60 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         let rdr = self.global.read().unwrap();
         rdr.vec_store.iter().fold(
             0,
-            |acc, (_name, tup)| acc + tup.1,
+            |acc, (_name, tup)| acc + tup.read().unwrap().count,
         )
     }
 
-    pub fn sub(&mut self, dbname: &str) {
-        self.is_subscribed = true;
-        self.subscribed_db = Some(dbname.to_owned());
-        let glb = self.global.read().unwrap();
-        let (id, rx) = glb.subs.lock().unwrap()
-            .sub(dbname.to_owned(), self.subscription_tx.clone());
-        self.rx = Some(rx);
-        self.sub_id = Some(id);
-        info!("Subscribing to channel {}. id: {}", dbname, id);
+    pub fn is_subscribed(&self) -> bool {
+        !self.subscriptions.is_empty()
     }
 
-    pub fn unsub_all(&mut self) {
-        let glb = self.global.read().unwrap();
-        let _ = glb.subs.lock().unwrap().unsub_all();
+    /// Subscribes to a store or glob pattern. Subscribing to the same pattern again replaces
+    /// its filter.
+    pub fn sub(&mut self, pattern: &str, filter: Filter) {
+        self.unsub(pattern);
+
+        let subs = self.global.read().unwrap().subs.clone();
+        let id = subs.sub(pattern.to_owned(), filter, self.subscription_tx.clone());
+        self.subscriptions.insert(pattern.to_owned(), id);
+        info!("Subscribing to channel {}. id: {}", pattern, id);
     }
 
-    /// unsubscribe
-    pub fn unsub(&mut self) {
-        if !self.is_subscribed {
-            return;
+    /// Replays `store_name` from `from_ts` and then subscribes to it, see
+    /// `Store::replay_and_sub`. Returns None if the store doesn't exist.
+    pub fn sub_from(&mut self, store_name: &str, from_ts: u64, filter: Filter) -> Option<()> {
+        self.unsub(store_name);
+
+        let subs = self.global.read().unwrap().subs.clone();
+        let id = match store!(self, get, store_name) {
+            Some(store) => store.replay_and_sub(from_ts, filter, self.subscription_tx.clone(), &subs),
+            None => return None,
+        };
+        self.subscriptions.insert(store_name.to_owned(), id);
+        info!("Subscribing to channel {} from {}. id: {}", store_name, from_ts, id);
+        Some(())
+    }
+
+    /// Drops every subscription of this connection.
+    pub fn unsub_all(&mut self) {
+        let patterns: Vec<String> = self.subscriptions.keys().cloned().collect();
+        for pattern in patterns {
+            self.unsub(&pattern);
         }
-        let old_dbname = self.subscribed_db.clone().unwrap();
-        let sub_id = self.sub_id.unwrap();
+    }
 
-        let glb = self.global.read().unwrap();
-        let _ = glb.subs.lock().unwrap().unsub(sub_id, &old_dbname);
+    /// unsubscribe from a single store or pattern. Returns false if not subscribed to it.
+    pub fn unsub(&mut self, pattern: &str) -> bool {
+        let sub_id = match self.subscriptions.remove(pattern) {
+            Some(sub_id) => sub_id,
+            None => return false,
+        };
 
-        info!("Unsubscribing from channel {}. id: {}", old_dbname, sub_id);
+        let subs = self.global.read().unwrap().subs.clone();
+        subs.unsub(sub_id);
 
-        self.is_subscribed = false;
-        self.subscribed_db = None;
-        self.rx = None;
-        self.sub_id = None;
+        info!("Unsubscribing from channel {}. id: {}", pattern, sub_id);
+        true
     }
 
     /// remove everything in the current store
@@ -509,31 +1087,74 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
 
     /// remove everything in every store
     pub fn clearall(&mut self) {
-        for store in store!(self, values_mut) {
+        for store in store!(self, values) {
             store.clear();
         }
     }
 
     /// save current store to file
-    pub fn flush(&mut self) {
-        current_store!(self, flush);
+    ///
+    /// On failure what wasn't written stays in memory.
+    pub fn flush(&mut self) -> Result<(), String> {
+        let flushing = current_store!(self, flush);
         // self.get_current_store().flush();
+        flushing.map_or(Ok(()), PendingFlush::wait)
     }
 
-    /// save all stores to corresponding files
-    pub fn flushall(&mut self) {
-        for store in store!(self, values_mut) {
-            store.flush();
+    /// save all stores to corresponding files, as many at once as the `FlushPool` has threads
+    ///
+    /// On failure what wasn't written stays in memory, and the error names every store
+    /// that failed. The catalog's manifest is written once they're done.
+    pub fn flushall(&mut self) -> Result<(), String> {
+        let flushes: Vec<(String, PendingFlush)> = store!(self, values)
+            .filter_map(|store| store.flush().map(|flushing| (store.name.to_string(), flushing)))
+            .collect();
+        let mut errors = vec![];
+        for (name, flushing) in flushes {
+            if let Err(err) = flushing.wait() {
+                errors.push(format!("{}: {}", name, err));
+            }
         }
+        self.global.read().unwrap().catalog.sync();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+    }
+
+    /// Returns a JSON array with, for every store, how its last flush went
+    pub fn flush_status(&self) -> String {
+        let stores = self.store.read().unwrap();
+        let mut statuses: Vec<FlushStatusDescription> = stores
+            .values()
+            .map(|store| {
+                let vecs = store.vecs.read().unwrap();
+                let last = vecs.flushing.last.as_ref();
+                FlushStatusDescription {
+                    name: store.name.to_string(),
+                    last_flush: last.map(|last| {
+                        let t = last.at.duration_since(UNIX_EPOCH).expect("Time went backwards");
+                        t.as_secs() * 1000 + u64::from(t.subsec_nanos()) / 1_000_000
+                    }),
+                    duration_ms: last.map(|last| {
+                        last.duration.as_secs() * 1000 + u64::from(last.duration.subsec_nanos()) / 1_000_000
+                    }),
+                    updates: last.map(|last| last.updates),
+                    result: last.map(|last| match last.result {
+                        Ok(()) => "ok".to_owned(),
+                        Err(ref err) => err.clone(),
+                    }),
+                    in_flight: vecs.flushing.queue.iter().map(|buffer| buffer.ups.len()).sum(),
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        format!("{}\n", serde_json::to_string(&statuses).unwrap())
     }
 
     /// get `count` items from the current store
     ///
     /// return if request item,
     /// get from mem
-    /// if range, filter
-    /// if count <= len, return
-    /// need more, get from fs
+    /// if range, filter, and merge with fs
+    /// the first `count`, in (ts, seq) order
     ///
     pub fn get<'global, 'thread>(&'global mut self, count: ReqCount,
         format: GetFormat, range: Range, loc: Loc) -> Option<ReturnType<'thread>>
@@ -546,78 +1167,125 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         }
 
         // check for items in memory
-        let rdr = self.global.read().unwrap();
-        let name: &str = self.current_store_name.borrow();
-        let &(ref vecs, _) = rdr.vec_store.get(name)?;
-        let empty = box Vec::new();
-        // if range, filter mem
-        let acc = catch! {
-            let (min_ts, max_ts) = range?;
-            info!("min_ts, max_ts: {}, {}", min_ts, max_ts);
-            info!("mem_min_tx, mem_max_tx: {}, {}", vecs.first()?.ts, vecs.last()?.ts);
-            if !within_range(min_ts, max_ts, vecs.first()?.ts, vecs.last()?.ts) {
-                info!("out of range");
-                return None;
-            }
-            info!("in range");
-            box vecs.iter()
-                .filter(|up| up.ts < max_ts && up.ts > min_ts)
-                .map(|up| up.to_owned())
-                .collect::<Vec<_>>()
-        }.unwrap_or(empty);
-        // }.unwrap_or(vecs.to_owned());
-        // info!("vecs: {:?}", vecs.to_owned());
-        // info!("acc: {:?}", acc);
-        // if only requested items in memory
+        let shared_vecs = {
+            let rdr = self.global.read().unwrap();
+            let name: &str = self.current_store_name.borrow();
+            rdr.vec_store.get(name)?.clone()
+        };
+        let (mems, segments, files) = {
+            let rdr = shared_vecs.read().unwrap();
+            // if range, filter mem, including what's being flushed
+            let mems: Vec<Vec<Update>> = in_memory(&rdr).into_iter().map(|ups| mem_range(ups, range)).collect();
+            (mems, rdr.segments.clone(), rdr.flushing.files.clone())
+        };
         info!("loc: {:?}", loc);
-        if let Loc::Mem = loc {
-            info!("request only from memory");
-            return self._return_aux(&acc, format);
-        }
 
-        // if count <= len, return
-        if let ReqCount::Count(c) = count {
-            if (c as usize) <= acc.len() {
-                return self._return_aux(&acc[..c as usize], format);
+        // memory and the files in range are merged in (ts, seq) order, an update that was
+        // flushed and is still in memory is only returned once
+        let disk = match (range, loc) {
+            (Some((min_ts, max_ts)), Loc::Fs) => {
+                let _reading = files.read().unwrap();
+                read_segments(&segments, min_ts, max_ts).unwrap_or_else(|_| {
+                    error!("Unable to read segments for range.");
+                    vec![]
+                })
             }
-        }
-
-        // we need more items
-        // check dtf files in folder and collect updates in requested range
-        // and combine sequentially
-        let mut ups_from_fs = acc;
-        if let Some((min_ts, max_ts)) = range {
-            let folder = {
-                let rdr = self.global.read().unwrap();
-                rdr.settings.dtf_folder.clone()
-            };
-            let ups = scan_files_for_range(&folder, &self.current_store_name, min_ts, max_ts);
-            match ups {
-                Ok(ups) => {
-                    ups_from_fs.extend(ups);
-                }
-                Err(_) => {
-                    error!("Unable to scan files for range.");
-                }
+            (_, Loc::Mem) => {
+                info!("request only from memory");
+                vec![]
             }
-        }
-
-        let result = ups_from_fs;
+            _ => vec![],
+        };
+        let result = merge_sources(mems, disk);
 
         match count {
             ReqCount::Count(c) => {
                 if result.len() >= c as usize {
-                    return self._return_aux(&result[..(c as usize - 1)], format);
+                    self._return_aux(&result[..c as usize], format)
                 } else {
-                    return Some(ReturnType::Error(
+                    Some(ReturnType::Error(
                         format!("Requested {} but only have {}.", c, result.len()).into(),
-                    ));
+                    ))
                 }
             }
             ReqCount::All => self._return_aux(&result, format),
         }
     }
 
+    /// Like `get`, but the response is produced lazily, a frame at a time.
+    ///
+    /// What's in memory is copied at the time of the request, the segments overlapping the
+    /// range are then read a batch at a time as the client takes the frames, and merged with
+    /// it in (ts, seq) order. With a count, at most that many updates are sent. None if the
+    /// store is gone.
+    pub fn get_chunks(&self, count: ReqCount, format: GetFormat, range: Range, loc: Loc) -> Option<GetChunks> {
+        let (shared_vecs, mem) = {
+            let rdr = self.global.read().unwrap();
+            let name: &str = self.current_store_name.borrow();
+            (rdr.vec_store.get(name)?.clone(), rdr.mem.clone())
+        };
+        let cursor = match (range, loc) {
+            (Some((min_ts, max_ts)), Loc::Fs) => Cursor::open(&shared_vecs.read().unwrap(), min_ts, max_ts, true, &mem),
+            (Some((min_ts, max_ts)), Loc::Mem) => Cursor::open(&shared_vecs.read().unwrap(), min_ts, max_ts, false, &mem),
+            (None, _) => Cursor::open(&shared_vecs.read().unwrap(), 0, u64::max_value(), false, &mem),
+        };
+        Some(GetChunks {
+            format,
+            remaining: match count {
+                ReqCount::Count(c) => Some(c as usize),
+                ReqCount::All => None,
+            },
+            cursor,
+        })
+    }
+
+    /// Opens a cursor over `[min_ts, max_ts]` of `store_name`, returning its id.
+    ///
+    /// The segments overlapping the range as of now are read in order, a batch at a time, as
+    /// the client fetches, and merged with what's in memory now. That copy is held within the
+    /// memory budget until the cursor is closed, see `reserve_memory`.
+    pub fn open_cursor(&mut self, store_name: &str, min_ts: u64, max_ts: u64) -> Result<u64, String> {
+        if self.cursors.len() >= MAX_CURSORS {
+            return Err("Too many open cursors.".to_owned());
+        }
+        // once loaded, memory holds the segments as well
+        let loaded = self.store.read().unwrap()
+            .get(store_name)
+            .map_or(false, |store| store.in_memory.load(Ordering::SeqCst));
+        let (shared_vecs, mem) = {
+            let rdr = self.global.read().unwrap();
+            match rdr.vec_store.get(store_name) {
+                Some(vecs) => (vecs.clone(), rdr.mem.clone()),
+                None => return Err(format!("DB {} not found.", store_name)),
+            }
+        };
+        let in_range: usize = in_memory(&shared_vecs.read().unwrap())
+            .iter()
+            .map(|ups| ups.range(min_ts, max_ts).count())
+            .sum();
+        // the cursor charges its copy itself, this only makes room for it
+        let _room = self.reserve_memory(in_range)?;
+        let cursor = Cursor::open(&shared_vecs.read().unwrap(), min_ts, max_ts, !loaded, &mem);
+        let id = self.next_cursor_id;
+        self.next_cursor_id += 1;
+        self.cursors.insert(id, cursor);
+        Ok(id)
+    }
+
+    /// the next `n` updates of cursor `id`, at most `CHUNK_SIZE`
+    pub fn fetch<'thread>(&mut self, id: u64, n: u32, format: GetFormat) -> Result<ReturnType<'thread>, String> {
+        let ups = match self.cursors.get_mut(&id) {
+            Some(cursor) => cursor.fetch((n as usize).min(CHUNK_SIZE))?,
+            None => return Err(format!("No cursor {}.", id)),
+        };
+        Ok(self._return_aux(&ups, format).unwrap())
+    }
+
+    /// false if there's no cursor `id`
+    pub fn close_cursor(&mut self, id: u64) -> bool {
+        self.cursors.remove(&id).is_some()
+    }
+
     fn _return_aux<'thread, 'global>(&'global self, result: &[Update], format: GetFormat) -> Option<ReturnType<'thread>> {
         let ret = match format {
             GetFormat::Dtf => {
@@ -646,82 +1314,2012 @@ impl<'thr, 'store> ThreadState<'thr, 'store> {
         store: HashMapStore<'b>,
         subscription_tx: SubscriptionTX,
     ) -> ThreadState<'a, 'b> {
-        let dtf_folder: &str = &global.read().unwrap().settings.dtf_folder;
+        let generation = global.read().unwrap().generation.clone();
+        let seen_generation = generation.load(Ordering::SeqCst);
         let state = ThreadState {
             current_store_name: "default".into(),
             bulkadd_db: None,
-            is_subscribed: false,
-            subscribed_db: None,
-            sub_id: None,
-            rx: None,
+            bulkadd_buf: vec![],
+            subscriptions: HashMap::new(),
             subscription_tx,
             store,
+            cursors: HashMap::new(),
+            next_cursor_id: 1,
+            generation,
+            seen_generation,
             global: global.clone(),
         };
 
-        // insert default first, if there is a copy in memory this will be replaced
-        let default_file = format!("{}/default.dtf", dtf_folder);
-        let default_in_memory = !Path::new(&default_file).exists();
-        state.store.write().unwrap().insert(
-            "default".to_owned(),
-            Store {
-                name: "default".into(),
-                fname: "default".into(),
-                in_memory: default_in_memory,
-                global: global.clone(),
-            },
-        );
-
-        let rdr = global.read().unwrap();
-        for (store_name, _vec) in &rdr.vec_store {
-            let fname = format!("{}/{}.dtf", dtf_folder, store_name);
-            let in_memory = !Path::new(&fname).exists();
-            state.store.write().unwrap().insert(
-                store_name.to_owned(),
-                Store {
-                    name: store_name.to_owned().into(),
-                    fname: store_name.clone().into(),
-                    in_memory: in_memory,
-                    global: global.clone(),
-                },
-            );
+        // the registry is shared, so only stores it doesn't hold yet are added, without
+        // segments they have never been flushed
+        let missing: Vec<(String, bool)> = {
+            let stores = state.store.read().unwrap();
+            global.read().unwrap().vec_store
+                .iter()
+                .filter(|&(name, _)| !stores.contains_key(name))
+                .map(|(name, vecs)| (name.clone(), vecs.read().unwrap().segments.is_empty()))
+                .collect()
+        };
+        for (store_name, in_memory) in missing {
+            let store = Store::new(&store_name, &store_name, in_memory, &global);
+            state.store.write().unwrap().entry(store_name).or_insert(store);
         }
         state
     }
 }
 
-/// (updates, count)
-pub type VecStore = (Box<Vec<Update>>, u64);
-
-/// key: btc_neo
-///      btc_eth
-///      ..
-///      total
-pub type History = HashMap<String, CircularQueue<(SystemTime, u64)>>;
+/// updates per frame of a streamed `GET`
+pub const CHUNK_SIZE: usize = 10_000;
 
-#[derive(Debug)]
-pub struct SharedState {
-    pub n_cxns: u16,
-    /// Is inside a BULKADD operation?
-    pub is_bulkadding: bool,
-    pub settings: Settings,
-    pub vec_store: HashMap<String, VecStore>,
-    pub history: History,
-    pub subs: Arc<Mutex<Subscriptions>>,
+/// The frames of a streamed `GET`, see `ThreadState::get_chunks`
+pub struct GetChunks {
+    format: GetFormat,
+    /// None for `GET ALL`
+    remaining: Option<usize>,
+    cursor: Cursor,
+}
+
+impl Iterator for GetChunks {
+    type Item = Result<Vec<u8>, String>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, String>> {
+        let n = self.remaining.map_or(CHUNK_SIZE, |remaining| remaining.min(CHUNK_SIZE));
+        if n == 0 {
+            return None;
+        }
+        match self.cursor.fetch(n) {
+            Ok(ref chunk) if chunk.is_empty() => None,
+            Ok(chunk) => {
+                if let Some(ref mut remaining) = self.remaining {
+                    *remaining -= chunk.len();
+                }
+                Some(Ok(encode_updates(&chunk, &self.format)))
+            }
+            Err(err) => {
+                self.remaining = Some(0);
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// cursors a connection may keep open at once
+pub const MAX_CURSORS: usize = 64;
+
+/// A cursor opened by `OPEN CURSOR`, see `ThreadState::open_cursor`
+pub struct Cursor {
+    min_ts: u64,
+    max_ts: u64,
+    /// segments not opened yet
+    segments: VecDeque<Segment>,
+    /// the segment being read, with its max_ts as of when the cursor was opened
+    reader: Option<(DTFBufReader, u64)>,
+    /// updates read from the segments but not merged yet
+    disk: VecDeque<Update>,
+    /// what was in memory when the cursor was opened and isn't merged yet
+    mem: VecDeque<Update>,
+    /// updates merged but not fetched yet
+    pending: VecDeque<Update>,
+    /// `Flushing::files`
+    files: Arc<RwLock<()>>,
+    /// charges the copy of memory to `MemoryStats` until the cursor is dropped
+    _reserved: Reservation,
+}
+
+impl Cursor {
+    fn open(vecs: &VecStore, min_ts: u64, max_ts: u64, with_segments: bool, mem: &Arc<MemoryStats>) -> Cursor {
+        let segments = if with_segments {
+            vecs.segments
+                .iter()
+                .filter(|seg| within_range(min_ts, max_ts, seg.min_ts, seg.max_ts))
+                .cloned()
+                .collect()
+        } else {
+            VecDeque::new()
+        };
+        let mems: Vec<Vec<Update>> = in_memory(vecs).into_iter().map(|ups| mem_range(ups, Some((min_ts, max_ts)))).collect();
+        // charged whatever the budget, `open_cursor` makes room for it first
+        let bytes = mems.iter().map(Vec::len).sum::<usize>() * UPDATE_SIZE;
+        mem.try_reserve(bytes, 0);
+        Cursor {
+            min_ts,
+            max_ts,
+            segments,
+            reader: None,
+            disk: VecDeque::new(),
+            mem: merge_sources(mems, vec![]).into(),
+            pending: VecDeque::new(),
+            files: vecs.flushing.files.clone(),
+            _reserved: Reservation { mem: mem.clone(), bytes },
+        }
+    }
+
+    /// the next `n` updates, fewer once the range is exhausted
+    pub fn fetch(&mut self, n: usize) -> Result<Vec<Update>, String> {
+        while self.pending.len() < n && self.fill()? {}
+        let n = n.min(self.pending.len());
+        Ok(self.pending.drain(..n).collect())
+    }
+
+    /// Reads on and merges into `pending`, false once there's nothing left.
+    fn fill(&mut self) -> Result<bool, String> {
+        // more updates at the ts of the last one read may follow on disk, they wait for the
+        // next batch
+        let upto = match self.read_batch()? {
+            Some(batch) => {
+                self.disk.extend(batch);
+                match self.disk.back() {
+                    Some(up) => up.ts,
+                    None => return Ok(true),
+                }
+            }
+            None if self.disk.is_empty() && self.mem.is_empty() => return Ok(false),
+            None => u64::max_value(),
+        };
+        let before = |ups: &mut VecDeque<Update>| -> Vec<Update> {
+            let n = ups.iter().take_while(|up| up.ts < upto || upto == u64::max_value()).count();
+            ups.drain(..n).collect()
+        };
+        let (mem, disk) = (before(&mut self.mem), before(&mut self.disk));
+        self.pending.extend(merge_sources(vec![mem], disk));
+        Ok(true)
+    }
+
+    /// The next batch in range of the segments, None once they're all read.
+    fn read_batch(&mut self) -> Result<Option<Vec<Update>>, String> {
+        let files = self.files.clone();
+        let _reading = files.read().unwrap();
+        loop {
+            if let Some((mut reader, seg_max_ts)) = self.reader.take() {
+                if let Some(batch) = reader.next() {
+                    // stop at the end of the segment as of the open, a flush may append meanwhile
+                    let (min_ts, max_ts) = (self.min_ts, self.max_ts.min(seg_max_ts));
+                    let past_end = batch.first().map_or(false, |up| up.ts > max_ts);
+                    if !past_end {
+                        self.reader = Some((reader, seg_max_ts));
+                    }
+                    return Ok(Some(batch.into_iter().filter(|up| up.ts >= min_ts && up.ts <= max_ts).collect()));
+                }
+            }
+            let seg = match self.segments.pop_front() {
+                Some(seg) => seg,
+                None => return Ok(None),
+            };
+            let reader = match DTFBufReader::open(&seg.fname, 1) {
+                // gone since the cursor was opened
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                reader => reader,
+            };
+            let reader = reader
+                .and_then(|mut reader| reader.seek_ts(self.min_ts).map(|_| reader))
+                .map_err(|err| format!("Unable to read {}: {}", seg.fname, err))?;
+            self.reader = Some((reader, seg.max_ts));
+        }
+    }
+}
+
+/// The updates of a store `SUBSCRIBE [db] FROM` replays, see `Store::replay_and_sub`
+struct Replay {
+    name: String,
+    filter: Filter,
+    cursor: Cursor,
+    /// fetched from the cursor but not taken yet
+    batch: VecDeque<Update>,
+}
+
+impl Iterator for Replay {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            while let Some(up) = self.batch.pop_front() {
+                if self.filter.matches(&up) {
+                    return Some((self.name.clone(), up));
+                }
+            }
+            match self.cursor.fetch(CHUNK_SIZE) {
+                Ok(ups) => {
+                    if ups.is_empty() {
+                        return None;
+                    }
+                    self.batch = ups.into();
+                }
+                Err(err) => {
+                    error!("Unable to replay {}: {}", self.name, err);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// the payload of a response holding `ups`
+fn encode_updates(ups: &[Update], format: &GetFormat) -> Vec<u8> {
+    match *format {
+        GetFormat::Dtf => {
+            let mut bytes: Vec<u8> = Vec::new();
+            let _ = dtf::write_batches(&mut bytes, ups);
+            bytes
+        }
+        GetFormat::Json => format!("[{}]\n", ups.into_json()).into_bytes(),
+        GetFormat::Csv => format!("{}\n", ups.into_csv()).into_bytes(),
+    }
+}
+
+/// FLUSH STATUS. Timestamps are in ms.
+#[derive(Serialize)]
+struct FlushStatusDescription {
+    name: String,
+    last_flush: Option<u64>,
+    duration_ms: Option<u64>,
+    updates: Option<usize>,
+    /// "ok" or the error
+    result: Option<String>,
+    /// updates waiting to be written
+    in_flight: usize,
+}
+
+/// DESCRIBE [db]. Timestamps are in ms.
+#[derive(Serialize)]
+struct DbDescription {
+    name: String,
+    count: u64,
+    in_memory_count: u64,
+    on_disk_count: u64,
+    min_ts: Option<u64>,
+    max_ts: Option<u64>,
+    files: Vec<FileDescription>,
+    symbol: Option<SymbolDescription>,
+    last_insert: Option<u64>,
+    subscribers: usize,
+    dedupe: bool,
+    late_updates: String,
+    duplicates: u64,
+    late: u64,
+    invalid: u64,
+    dropped: u64,
+    validation: ValidationDescription,
+}
+
+#[derive(Serialize)]
+struct ValidationDescription {
+    reject_malformed: bool,
+    max_future_secs: Option<u64>,
+    max_past_secs: Option<u64>,
+    max_seq_regression: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct FileDescription {
+    path: String,
+    size: u64,
+    count: u64,
+    min_ts: u64,
+    max_ts: u64,
+}
+
+#[derive(Serialize)]
+struct SymbolDescription {
+    exchange: String,
+    currency: String,
+    asset: String,
+}
+
+/// copies the dtf file `src` to `dst`, which must not exist, under a new symbol
+fn copy_dtf(src: &str, dst: &str, symbol: &str) -> Result<(), String> {
+    if Path::new(dst).exists() {
+        return Err(format!("Unable to write {}: it already exists.", dst));
+    }
+    fs::copy(src, dst).map_err(|err| format!("Unable to copy {}: {}", src, err))?;
+    dtf::set_symbol(dst, symbol).map_err(|err| {
+        let _ = fs::remove_file(dst);
+        format!("Unable to write {}: {}", dst, err)
+    })
+}
+
+/// moves the dtf file `src` to `dst`, which must not exist, under a new symbol
+fn move_dtf(src: &str, dst: &str, symbol: &str) -> Result<(), String> {
+    if Path::new(dst).exists() {
+        return Err(format!("Unable to write {}: it already exists.", dst));
+    }
+    fs::rename(src, dst).map_err(|err| format!("Unable to move {}: {}", src, err))?;
+    dtf::set_symbol(dst, symbol).map_err(|err| {
+        let _ = fs::rename(dst, src);
+        format!("Unable to write {}: {}", dst, err)
+    })
+}
+
+/// removes the files of a copy that's given up on
+fn remove_copies(catalog: &Catalog, mut copies: Vec<Segment>) {
+    for seg in copies.clone() {
+        if let Err(err) = fs::remove_file(&seg.fname) {
+            error!("Unable to remove {}: {}", seg.fname, err);
+        }
+        catalog.refresh(&mut copies, &seg.fname);
+    }
+}
+
+/// `db/bnc_eth_btc-2018010112.dtf` saved as `bnc_xrp_btc` is `db/bnc_xrp_btc-2018010112.dtf`
+fn renamed_segment(path: &str, fname: &str, to: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let suffix = if stem.starts_with(fname) {
+        stem[fname.len()..].to_owned()
+    } else {
+        format!("-{}", stem)
+    };
+    path.with_file_name(format!("{}{}.dtf", to, suffix)).to_string_lossy().into_owned()
+}
+
+/// Updates swapped out of memory by `Store::flush`, not written yet
+#[derive(Debug)]
+pub struct Buffer {
+    pub ups: MemStore,
+    /// whether it holds a copy of the files, read by `USE`
+    loaded: bool,
+    late_policy: LatePolicy,
+    dedupe: bool,
+    /// set once written, by whichever flush got to it first
+    result: Mutex<Option<Result<(), String>>>,
+    done: Condvar,
+}
+
+/// A flush handed to the `FlushPool`
+#[derive(Debug)]
+pub struct PendingFlush {
+    buffer: Arc<Buffer>,
+}
+
+impl PendingFlush {
+    /// Waits until the buffer is written, returning the error if it couldn't be.
+    pub fn wait(self) -> Result<(), String> {
+        let mut result = self.buffer.result.lock().unwrap();
+        loop {
+            if let Some(ref result) = *result {
+                return result.clone();
+            }
+            result = self.buffer.done.wait(result).unwrap();
+        }
+    }
+}
+
+/// How the last flush of a store went
+#[derive(Debug, Clone)]
+pub struct FlushStatus {
+    pub at: SystemTime,
+    pub duration: Duration,
+    /// updates written
+    pub updates: usize,
+    pub result: Result<(), String>,
+}
+
+/// The flushes of a store in progress
+#[derive(Debug, Clone, Default)]
+pub struct Flushing {
+    /// the store's name, which its files are named after and hold as their symbol. It only
+    /// changes, on `RENAME`, under `lock`
+    pub name: String,
+    /// buffers not written yet, oldest first
+    pub queue: VecDeque<Arc<Buffer>>,
+    /// held by whoever writes the queue, so buffers are written in order
+    pub lock: Arc<Mutex<()>>,
+    /// held for write while a segment is written, readers of the segments take it for read
+    pub files: Arc<RwLock<()>>,
+    pub last: Option<FlushStatus>,
+}
+
+/// What a background flush needs of its `Store`
+struct Flusher {
+    folder: String,
+    partition: Partition,
+    vecs: SharedVecStore,
+    mem: Arc<MemoryStats>,
+    catalog: Arc<Catalog>,
+}
+
+impl Flusher {
+    fn run(self) {
+        let lock = self.vecs.read().unwrap().flushing.lock.clone();
+        let _writing = lock.lock().unwrap();
+        self.drain();
+    }
+
+    /// Writes what's waiting to be flushed, returning `vecs`, the flusher's own store, locked
+    /// with nothing left. The caller must hold `Flushing::lock`.
+    fn settle<'a>(&self, vecs: &'a SharedVecStore) -> RwLockWriteGuard<'a, VecStore> {
+        loop {
+            self.drain();
+            let vecs = vecs.write().unwrap();
+            if vecs.flushing.queue.is_empty() {
+                return vecs;
+            }
+        }
+    }
+
+    /// Writes the queued buffers, oldest first. A buffer stays visible to queries until it's
+    /// in the files, what fails to be written goes back into memory.
+    fn drain(&self) {
+        let files = self.vecs.read().unwrap().flushing.files.clone();
+        loop {
+            let (buffer, name) = {
+                let vecs = self.vecs.read().unwrap();
+                match vecs.flushing.queue.front() {
+                    Some(buffer) => (buffer.clone(), vecs.flushing.name.clone()),
+                    None => return,
+                }
+            };
+            let (at, started) = (SystemTime::now(), Instant::now());
+            utils::create_dir_if_not_exist(&self.folder);
+
+            let mut segments: BTreeMap<String, Vec<Update>> = BTreeMap::new();
+            for up in buffer.ups.iter() {
+                segments.entry(self.partition.suffix(up.ts)).or_insert_with(Vec::new).push(*up);
+            }
+            let mut fnames = vec![];
+            let (mut dropped, mut duplicates) = (0, 0);
+            let mut unwritten: Vec<Update> = vec![];
+            let mut errors: Vec<String> = vec![];
+            for (suffix, ups) in segments {
+                let fname = format!("{}/{}{}.dtf", &self.folder, name, suffix);
+                let result = {
+                    let _writing = files.write().unwrap();
+                    self.write_segment(&buffer, &name, &fname, &ups)
+                };
+                match result {
+                    Ok((n_dropped, n_duplicates)) => {
+                        debug!("Successfully flushed {}.", fname);
+                        dropped += n_dropped;
+                        duplicates += n_duplicates;
+                    }
+                    Err(err) => {
+                        error!("Error flushing file: {:?}", err);
+                        errors.push(format!("Unable to write {}: {}", fname, err));
+                        unwritten.extend(ups);
+                    }
+                };
+                fnames.push(fname);
+            }
+            let result = if errors.is_empty() { Ok(()) } else { Err(errors.join(" ")) };
+
+            let mut vecs = self.vecs.write().unwrap();
+            let vecs = &mut *vecs;
+            for fname in &fnames {
+                self.catalog.refresh(&mut vecs.segments, fname);
+            }
+            vecs.inserts.dropped += dropped + duplicates;
+            vecs.inserts.duplicates += duplicates;
+            vecs.count = vecs.count.saturating_sub(dropped + duplicates);
+            vecs.flushing.last = Some(FlushStatus {
+                at,
+                duration: started.elapsed(),
+                updates: buffer.ups.len() - unwritten.len(),
+                result: result.clone(),
+            });
+            vecs.ups.append(&mut unwritten);
+            vecs.flushing.queue.pop_front();
+            vecs.track_memory();
+            vecs.account(&self.mem);
+            *buffer.result.lock().unwrap() = Some(result);
+            buffer.done.notify_all();
+        }
+    }
+
+    /// Writes `ups` into the segment `fname` of the store `symbol`, returning how many updates
+    /// were dropped as not newer than the file, and as duplicates.
+    fn write_segment(&self, buffer: &Buffer, symbol: &str, fname: &str, ups: &[Update]) -> Result<(u64, u64), io::Error> {
+        if buffer.late_policy == LatePolicy::Accept {
+            return self.merge_into_file(buffer, symbol, fname, ups).map(|duplicates| (0, duplicates));
+        }
+        if !Path::new(fname).exists() {
+            return dtf::encode(fname, symbol, ups).map(|_| (0, 0));
+        }
+        // append only keeps updates newer than the file, after `USE` memory holds the file too
+        let mut dropped = 0;
+        if let Ok(meta) = dtf::read_meta(fname) {
+            dropped = ups.iter().filter(|up| up.ts <= meta.max_ts).count() as u64;
+            if buffer.loaded {
+                dropped = dropped.saturating_sub(meta.nums);
+            }
+        }
+        dtf::append(fname, ups).map(|_| (dropped, 0))
+    }
+
+    /// Writes `ups` sorted into the file, rewriting it when late updates belong before its end.
+    /// Returns how many duplicates were dropped.
+    fn merge_into_file(&self, buffer: &Buffer, symbol: &str, fname: &str, ups: &[Update]) -> Result<u64, io::Error> {
+        let mut ups = ups.to_vec();
+        ups.sort_by_key(update_key);
+        let disk_max_ts = match dtf::read_meta(fname) {
+            Ok(meta) => meta.max_ts,
+            Err(_) => return dtf::encode(fname, symbol, &ups).map(|_| 0),
+        };
+        if ups.first().map_or(true, |up| up.ts > disk_max_ts) {
+            return dtf::append(fname, &ups).map(|_| 0);
+        }
+
+        // after `USE` memory already holds the file
+        if !buffer.loaded {
+            ups.extend(dtf::decode(fname, None)?);
+            ups.sort_by_key(update_key);
+        }
+        let mut duplicates = 0;
+        if buffer.dedupe {
+            let len = ups.len();
+            ups.dedup_by_key(|up| update_key(up));
+            duplicates = (len - ups.len()) as u64;
+        }
+        dtf::rewrite(fname, symbol, &ups).map(|_| duplicates)
+    }
+}
+
+/// The threads writing the flushes of every store, so that a flush, autoflushes included,
+/// doesn't start a thread of its own.
+#[derive(Debug)]
+pub struct FlushPool {
+    tx: Mutex<mpsc::Sender<Flusher>>,
+}
+
+impl FlushPool {
+    /// Starts `threads` flusher threads, at least one. They stop once the pool is dropped.
+    pub fn new(threads: usize) -> FlushPool {
+        let (tx, rx) = mpsc::channel::<Flusher>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..cmp::max(threads, 1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("flusher-{}", i))
+                .spawn(move || loop {
+                    let flusher = match rx.lock().unwrap().recv() {
+                        Ok(flusher) => flusher,
+                        Err(_) => return,
+                    };
+                    flusher.run();
+                })
+                .expect("Unable to start a flusher thread");
+        }
+        FlushPool { tx: Mutex::new(tx) }
+    }
+
+    fn submit(&self, flusher: Flusher) {
+        let _ = self.tx.lock().unwrap().send(flusher);
+    }
+}
+
+/// What's in memory: the buffers being flushed, oldest first, then the store's own.
+fn in_memory(vecs: &VecStore) -> Vec<&MemStore> {
+    vecs.flushing.queue.iter().map(|buffer| &buffer.ups).chain(Some(&vecs.ups)).collect()
+}
+
+/// `ups` within `range`, or all of them
+fn mem_range(ups: &MemStore, range: Range) -> Vec<Update> {
+    match range {
+        Some((min_ts, max_ts)) => ups.range(min_ts, max_ts).cloned().collect(),
+        None => ups.to_vec(),
+    }
+}
+
+/// Reads `[min_ts, max_ts]` of the segments overlapping it. A segment whose file is gone since,
+/// e.g. moved away for upload, is skipped.
+fn read_segments(segments: &[Segment], min_ts: u64, max_ts: u64) -> Result<Vec<Update>, io::Error> {
+    let fnames: Vec<String> = segments
+        .iter()
+        .filter(|seg| within_range(min_ts, max_ts, seg.min_ts, seg.max_ts))
+        .filter(|seg| Path::new(&seg.fname).exists())
+        .map(|seg| seg.fname.clone())
+        .collect();
+    read_files_for_range(&fnames, min_ts, max_ts)
+}
+
+/// the updates of `seg` with `ts >= from_ts`, none if it can't be read
+fn read_segment_from(files: &RwLock<()>, seg: &Segment, from_ts: u64) -> Vec<Update> {
+    let _reading = files.read().unwrap();
+    let ups = DTFBufReader::open(&seg.fname, 1).and_then(|mut rdr| {
+        rdr.seek_ts(from_ts)?;
+        Ok(rdr.flat_map(|batch| batch).filter(|up| up.ts >= from_ts).collect())
+    });
+    match ups {
+        Ok(ups) => ups,
+        // moved away since, e.g. for upload
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => vec![],
+        Err(err) => {
+            error!("Unable to read {}: {}", seg.fname, err);
+            vec![]
+        }
+    }
+}
+
+/// Merges what's in memory, `mems`, with what was read from disk in (ts, seq) order.
+///
+/// Memory may hold copies of updates on disk, after a `USE` or while they're being flushed, so
+/// an update found in both is returned as many times as it is in either, not both. Repeats
+/// within memory or within disk are kept, they were inserted with DEDUPE off.
+fn merge_sources(mut mems: Vec<Vec<Update>>, mut disk: Vec<Update>) -> Vec<Update> {
+    for source in &mut mems {
+        source.sort_by_key(update_identity);
+    }
+    disk.sort_by_key(update_identity);
+
+    // (identity of the next update of a source, source, position)
+    let mut heap: BinaryHeap<Reverse<(UpdateIdentity, usize, usize)>> = mems
+        .iter()
+        .enumerate()
+        .filter_map(|(i, source)| source.first().map(|up| Reverse((update_identity(up), i, 0))))
+        .collect();
+    let mut mem: Vec<Update> = Vec::with_capacity(mems.iter().map(Vec::len).sum());
+    while let Some(Reverse((_, i, pos))) = heap.pop() {
+        mem.push(mems[i][pos]);
+        if let Some(next) = mems[i].get(pos + 1) {
+            heap.push(Reverse((update_identity(next), i, pos + 1)));
+        }
+    }
+
+    // identical updates are next to each other, one in memory and one on disk make a pair
+    let mut merged = Vec::with_capacity(mem.len().max(disk.len()));
+    let (mut mem, mut disk) = (mem.into_iter().peekable(), disk.into_iter().peekable());
+    loop {
+        let order = match (mem.peek(), disk.peek()) {
+            (Some(m), Some(d)) => update_identity(m).cmp(&update_identity(d)),
+            (Some(_), None) => cmp::Ordering::Less,
+            (None, Some(_)) => cmp::Ordering::Greater,
+            (None, None) => break,
+        };
+        match order {
+            cmp::Ordering::Less => merged.extend(mem.next()),
+            cmp::Ordering::Greater => merged.extend(disk.next()),
+            cmp::Ordering::Equal => {
+                merged.extend(mem.next());
+                disk.next();
+            }
+        }
+    }
+    merged
+}
+
+/// What a store does with an update older than the newest one it holds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LatePolicy {
+    /// keep it in memory, a flush only appends what's newer than the file and drops the rest
+    Keep,
+    /// discard it
+    Drop,
+    /// keep it, memory is sorted and merged into the file on flush
+    Accept,
+    /// fail the insert
+    Reject,
+}
+
+impl Default for LatePolicy {
+    fn default() -> LatePolicy {
+        LatePolicy::Accept
+    }
+}
+
+impl FromStr for LatePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<LatePolicy, String> {
+        match s {
+            "keep" => Ok(LatePolicy::Keep),
+            "drop" => Ok(LatePolicy::Drop),
+            "accept" => Ok(LatePolicy::Accept),
+            "reject" => Ok(LatePolicy::Reject),
+            _ => Err(format!("Unknown late update policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            LatePolicy::Keep => "keep",
+            LatePolicy::Drop => "drop",
+            LatePolicy::Accept => "accept",
+            LatePolicy::Reject => "reject",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// (ts, seq, is_bid, price bits)
+type UpdateKey = (u64, u32, bool, u32);
+
+fn update_key(up: &Update) -> UpdateKey {
+    (up.ts, up.seq, up.is_bid, up.price.to_bits())
+}
+
+/// every field of an update, (ts, seq) first so that sorting by it keeps (ts, seq) order
+type UpdateIdentity = (u64, u32, bool, u32, bool, u32);
+
+fn update_identity(up: &Update) -> UpdateIdentity {
+    (up.ts, up.seq, up.is_bid, up.price.to_bits(), up.is_trade, up.size.to_bits())
+}
+
+/// Limits an update must be within to be stored, `None` is unchecked.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Validation {
+    /// reject zero timestamps and prices or sizes that are negative, NaN or infinite
+    pub reject_malformed: bool,
+    /// how far ahead of the server clock ts may be, in ms
+    pub max_future: Option<u64>,
+    /// how far behind the server clock ts may be, in ms
+    pub max_past: Option<u64>,
+    /// how far seq may fall below the previous update's
+    pub max_seq_regression: Option<u32>,
+}
+
+/// The limits of `Validation`, as named in `SET [db] [rule] [value]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationRule {
+    MaxFuture,
+    MaxPast,
+    MaxSeqRegression,
+}
+
+impl FromStr for ValidationRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ValidationRule, String> {
+        match s {
+            "MAX_FUTURE" => Ok(ValidationRule::MaxFuture),
+            "MAX_PAST" => Ok(ValidationRule::MaxPast),
+            "MAX_SEQ_REGRESSION" => Ok(ValidationRule::MaxSeqRegression),
+            _ => Err(format!("Unknown validation rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ValidationRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            ValidationRule::MaxFuture => "MAX_FUTURE",
+            ValidationRule::MaxPast => "MAX_PAST",
+            ValidationRule::MaxSeqRegression => "MAX_SEQ_REGRESSION",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Validation {
+    /// Sets a limit, in seconds for MAX_FUTURE and MAX_PAST
+    pub fn set(&mut self, rule: ValidationRule, value: Option<u64>) {
+        match rule {
+            ValidationRule::MaxFuture => self.max_future = value.map(|secs| secs * 1000),
+            ValidationRule::MaxPast => self.max_past = value.map(|secs| secs * 1000),
+            ValidationRule::MaxSeqRegression => {
+                self.max_seq_regression = value.map(|n| n.min(u64::from(u32::max_value())) as u32)
+            }
+        }
+    }
+
+    /// Checks `up` against the limits. `now` is in ms.
+    fn check(&self, up: &Update, now: u64, last_seq: Option<u32>) -> Result<(), String> {
+        if self.reject_malformed {
+            if up.ts == 0 {
+                return Err("Invalid update: ts is 0.".to_owned());
+            }
+            if !up.price.is_finite() || up.price < 0. {
+                return Err(format!("Invalid update: price {} is negative or not finite.", up.price));
+            }
+            if !up.size.is_finite() || up.size < 0. {
+                return Err(format!("Invalid update: size {} is negative or not finite.", up.size));
+            }
+        }
+        if let Some(max_future) = self.max_future {
+            if up.ts > now + max_future {
+                return Err(format!("Invalid update: ts {} is more than {}s in the future.", up.ts, max_future / 1000));
+            }
+        }
+        if let Some(max_past) = self.max_past {
+            if up.ts < now.saturating_sub(max_past) {
+                return Err(format!("Invalid update: ts {} is more than {}s in the past.", up.ts, max_past / 1000));
+            }
+        }
+        if let (Some(max_seq_regression), Some(last_seq)) = (self.max_seq_regression, last_seq) {
+            if up.seq < last_seq && last_seq - up.seq > max_seq_regression {
+                return Err(format!(
+                    "Invalid update: seq {} regressed more than {} from {}.",
+                    up.seq, max_seq_regression, last_seq
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A store's insert policy and counters of what it did
+#[derive(Debug, Clone, Default)]
+pub struct Inserts {
+    /// drop updates with the same (ts, seq, side, price) as one in memory or on disk
+    pub dedupe: bool,
+    pub late_policy: LatePolicy,
+    pub validation: Validation,
+    /// newest ts in memory or on disk, 0 until known
+    max_ts: u64,
+    /// seq of the last stored update
+    last_seq: Option<u32>,
+    /// keys of the updates in memory, and of those on disk from `disk_from` on, only kept
+    /// while deduping
+    seen: HashSet<UpdateKey>,
+    /// oldest ts of the updates on disk in `seen`, `None` if there are none
+    disk_from: Option<u64>,
+    pub duplicates: u64,
+    pub late: u64,
+    pub invalid: u64,
+    /// duplicates, late updates that were dropped and rejected updates
+    pub dropped: u64,
+}
+
+impl Inserts {
+    pub fn new(settings: &Settings) -> Inserts {
+        Inserts {
+            dedupe: settings.dedupe,
+            late_policy: settings.late_updates,
+            validation: settings.validation,
+            ..Default::default()
+        }
+    }
+
+    /// remembers updates that went into memory without `admit`
+    fn track<'a, I: IntoIterator<Item = &'a Update>>(&mut self, ups: I) {
+        if self.dedupe {
+            self.seen.extend(ups.into_iter().map(update_key));
+        }
+    }
+
+    /// Forgets what's on disk, e.g. once a flush wrote it, and remembers only `in_memory`.
+    fn forget<'a, I: IntoIterator<Item = &'a Update>>(&mut self, in_memory: I) {
+        self.seen = HashSet::new();
+        self.disk_from = None;
+        self.track(in_memory);
+    }
+
+    /// Remembers the updates on disk that some of `ups` could be a duplicate of, reading
+    /// `segments` back from the oldest such ts up to what's remembered already.
+    ///
+    /// Only updates at most as new as the files could be, and under `Drop` and `Reject` only
+    /// those at the newest ts, the others are late.
+    fn track_disk(&mut self, segments: &[Segment], files: &RwLock<()>, ups: &[Update]) {
+        let disk_max_ts = match segments.iter().map(|seg| seg.max_ts).max() {
+            Some(max_ts) => max_ts,
+            None => return,
+        };
+        let oldest = if self.late_policy == LatePolicy::Drop || self.late_policy == LatePolicy::Reject {
+            self.max_ts
+        } else {
+            0
+        };
+        let from_ts = match ups.iter().map(|up| up.ts).filter(|&ts| ts >= oldest && ts <= disk_max_ts).min() {
+            Some(from_ts) if self.disk_from.map_or(true, |disk_from| from_ts < disk_from) => from_ts,
+            _ => return,
+        };
+        let to_ts = self.disk_from.unwrap_or(u64::max_value());
+        for seg in segments.iter().filter(|seg| seg.max_ts >= from_ts && seg.min_ts < to_ts) {
+            let seg_ups = read_segment_from(files, seg, from_ts);
+            self.seen.extend(seg_ups.iter().filter(|up| up.ts < to_ts).map(update_key));
+        }
+        self.disk_from = Some(from_ts);
+    }
+
+    /// forgets the newest ts, e.g. after it may have been removed
+    fn reset(&mut self) {
+        self.max_ts = 0;
+    }
+
+    /// Applies the policy to a batch, returning the updates to store.
+    ///
+    /// A batch with any invalid update, or any late update under `LatePolicy::Reject`, is
+    /// rejected as a whole.
+    fn admit(&mut self, ups: &[Update]) -> Result<Vec<Update>, String> {
+        let now = {
+            let t = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+            t.as_secs() * 1000 + u64::from(t.subsec_nanos()) / 1_000_000
+        };
+        let mut last_seq = self.last_seq;
+        for up in ups {
+            if let Err(err) = self.validation.check(up, now, last_seq) {
+                self.invalid += 1;
+                self.dropped += ups.len() as u64;
+                return Err(err);
+            }
+            last_seq = Some(up.seq);
+        }
+
+        if self.late_policy == LatePolicy::Reject {
+            let mut max_ts = self.max_ts;
+            let n_late = ups.iter().filter(|up| {
+                let is_late = up.ts < max_ts;
+                max_ts = max_ts.max(up.ts);
+                is_late
+            }).count() as u64;
+            if n_late > 0 {
+                self.late += n_late;
+                self.dropped += ups.len() as u64;
+                return Err(format!("Rejected {} late updates older than {}.", n_late, self.max_ts));
+            }
+        }
+
+        let mut admitted = Vec::with_capacity(ups.len());
+        for up in ups {
+            if up.ts < self.max_ts {
+                self.late += 1;
+                if self.late_policy == LatePolicy::Drop {
+                    self.dropped += 1;
+                    continue;
+                }
+            }
+            if self.dedupe && !self.seen.insert(update_key(up)) {
+                self.duplicates += 1;
+                self.dropped += 1;
+                continue;
+            }
+            self.max_ts = self.max_ts.max(up.ts);
+            self.last_seq = Some(up.seq);
+            admitted.push(*up);
+        }
+        Ok(admitted)
+    }
+}
+
+/// What happens to an insert that doesn't fit in the memory budget
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryPolicy {
+    /// flush the stores holding the most updates until it fits
+    Flush,
+    /// fail the insert
+    Reject,
+}
+
+impl Default for MemoryPolicy {
+    fn default() -> MemoryPolicy {
+        MemoryPolicy::Flush
+    }
+}
+
+impl FromStr for MemoryPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<MemoryPolicy, String> {
+        match s {
+            "flush" => Ok(MemoryPolicy::Flush),
+            "reject" => Ok(MemoryPolicy::Reject),
+            _ => Err(format!("Unknown memory policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            MemoryPolicy::Flush => "flush",
+            MemoryPolicy::Reject => "reject",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// bytes an update takes in memory
+pub const UPDATE_SIZE: usize = ::std::mem::size_of::<Update>();
+
+/// bytes an entry of the dedupe set takes, with its control byte
+const KEY_SIZE: usize = ::std::mem::size_of::<UpdateKey>() + 1;
+
+/// memory held by the updates of all stores, reported in INFO
+#[derive(Debug, Default)]
+pub struct MemoryStats {
+    used: AtomicUsize,
+    /// flushes forced by the memory budget
+    pub forced_flushes: AtomicUsize,
+    /// updates rejected for not fitting in the memory budget
+    pub rejected: AtomicUsize,
+}
+
+impl MemoryStats {
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Holds `bytes` if they fit in `budget`, 0 being unlimited.
+    fn try_reserve(&self, bytes: usize, budget: usize) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if budget != 0 && used > budget {
+            self.used.fetch_sub(bytes, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// a store went from holding `old` to `new` bytes
+    fn charge(&self, old: usize, new: usize) {
+        if new > old {
+            self.used.fetch_add(new - old, Ordering::SeqCst);
+        } else {
+            self.used.fetch_sub(old - new, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Memory held for an insert by `ThreadState::reserve_memory`, given back when dropped, by
+/// which time the store charged what it actually took.
+#[derive(Debug)]
+pub struct Reservation {
+    mem: Arc<MemoryStats>,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.mem.used.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// How a store's updates are split into segment files, by timestamp
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Partition {
+    /// a single `<fname>.dtf`
+    None,
+    /// `<fname>-YYYYMMDDHH.dtf`
+    Hourly,
+    /// `<fname>-YYYYMMDD.dtf`
+    Daily,
+}
+
+impl Default for Partition {
+    fn default() -> Partition {
+        Partition::None
+    }
+}
+
+impl FromStr for Partition {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Partition, String> {
+        match s {
+            "none" => Ok(Partition::None),
+            "hourly" => Ok(Partition::Hourly),
+            "daily" => Ok(Partition::Daily),
+            _ => Err(format!("Unknown partition: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Partition::None => "none",
+            Partition::Hourly => "hourly",
+            Partition::Daily => "daily",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Partition {
+    /// file name suffix of the segment holding an update at `ts` (ms), in UTC
+    pub fn suffix(self, ts: u64) -> String {
+        let format = match self {
+            Partition::None => return String::new(),
+            Partition::Hourly => "-%Y%m%d%H",
+            Partition::Daily => "-%Y%m%d",
+        };
+        Utc.timestamp_opt((ts / 1000) as i64, 0)
+            .single()
+            .map_or_else(String::new, |t| t.format(format).to_string())
+    }
+}
+
+/// What the server holds of a store, shared by every client
+#[derive(Debug, Clone, Default)]
+pub struct VecStore {
+    /// updates in memory, sorted by (ts, seq)
+    pub ups: MemStore,
+    /// updates in memory and on disk
+    pub count: u64,
+    pub last_insert: Option<SystemTime>,
+    pub inserts: Inserts,
+    /// segment files, sorted by min_ts
+    pub segments: Vec<Segment>,
+    pub flushing: Flushing,
+    /// bytes held in memory as last charged to `MemoryStats`
+    pub mem_bytes: usize,
+}
+
+impl VecStore {
+    pub fn new(name: &str, inserts: Inserts) -> VecStore {
+        VecStore {
+            inserts,
+            flushing: Flushing { name: name.to_owned(), ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    /// Has `inserts` forget what's on disk and remember what's in memory.
+    fn track_memory(&mut self) {
+        let VecStore { ref mut inserts, ref flushing, ref ups, .. } = *self;
+        inserts.forget(flushing.queue.iter().flat_map(|buffer| buffer.ups.iter()).chain(ups.iter()));
+    }
+
+    /// Charges `mem` with what memory held, including spare capacity and the keys kept for
+    /// dedupe, grew or shrank by since the last call.
+    fn account(&mut self, mem: &MemoryStats) {
+        let bytes = in_memory(self).iter().map(|ups| ups.heap_size()).sum::<usize>()
+            + self.inserts.seen.capacity() * KEY_SIZE;
+        mem.charge(self.mem_bytes, bytes);
+        self.mem_bytes = bytes;
+    }
+}
+
+/// key: btc_neo
+///      btc_eth
+///      ..
+///      total
+pub type History = HashMap<String, CircularQueue<(SystemTime, u64)>>;
+
+#[derive(Debug)]
+pub struct SharedState {
+    pub n_cxns: u16,
+    pub settings: Settings,
+    /// every store is locked on its own so that one hot store doesn't block the others
+    pub vec_store: HashMap<String, SharedVecStore>,
+    pub history: History,
+    pub subs: Arc<Subscriptions>,
+    pub mem: Arc<MemoryStats>,
+    /// the dtf files in `dtf_folder`
+    pub catalog: Arc<Catalog>,
+    /// policies set on stores, kept in `dtf_folder`
+    pub policies: Policies,
+    pub flush_pool: Arc<FlushPool>,
+    /// bumped whenever a store is dropped or renamed, so clients only look for it then
+    pub generation: Arc<AtomicUsize>,
 }
 
 impl SharedState {
     pub fn new(settings: Settings) -> SharedState {
         let mut hashmap = HashMap::new();
-        hashmap.insert("default".to_owned(), (box Vec::new(), 0));
-        let subs = Arc::new(Mutex::new(Subscriptions::new()));
+        let policies = Policies::open(&settings.dtf_folder);
+        let mut inserts = Inserts::new(&settings);
+        policies.apply("default", &mut inserts);
+        hashmap.insert("default".to_owned(), Arc::new(RwLock::new(VecStore::new("default", inserts))));
+        let subs = Arc::new(Subscriptions::new(settings.sub_queue_capacity, settings.sub_queue_policy));
+        let catalog = Arc::new(Catalog::open(&settings.dtf_folder));
+        let flush_threads = settings.flush_threads;
         SharedState {
             n_cxns: 0,
-            is_bulkadding: false,
             settings,
             vec_store: hashmap,
             history: HashMap::new(),
             subs,
+            mem: Arc::new(MemoryStats::default()),
+            catalog,
+            policies,
+            flush_pool: Arc::new(FlushPool::new(flush_threads)),
+            generation: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the shared `VecStore` for `name`, inserting an empty one if it doesn't exist.
+    pub fn get_or_create_vec_store(&mut self, name: &str) -> SharedVecStore {
+        let mut inserts = Inserts::new(&self.settings);
+        self.policies.apply(name, &mut inserts);
+        self.vec_store
+            .entry(name.to_owned())
+            .or_insert_with(|| Arc::new(RwLock::new(VecStore::new(name, inserts))))
+            .clone()
+    }
+}
+
+/// fixtures shared by the tests of the server's modules
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+
+    /// A client of a server whose `dtf_folder` is `name`, emptied, in the temp dir
+    pub fn test_state(name: &str, settings: Settings) -> (String, Global, HashMapStore<'static>, ThreadState<'static, 'static>) {
+        let folder = ::std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let folder = folder.to_str().unwrap().to_owned();
+        let global = Arc::new(RwLock::new(SharedState::new(Settings { dtf_folder: folder.clone(), ..settings })));
+        let store = Arc::new(RwLock::new(HashMap::new()));
+        let (tx, _) = global.read().unwrap().subs.queue();
+        let state = ThreadState::new(global.clone(), store.clone(), tx);
+        (folder, global, store, state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_utils::test_state;
+    use std::thread;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: 0, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_insert_into_different_stores_concurrently() {
+        let global = Arc::new(RwLock::new(SharedState::new(Default::default())));
+        let store = Arc::new(RwLock::new(HashMap::new()));
+
+        let handles: Vec<_> = (0..4).map(|i| {
+            let global = global.clone();
+            let store = store.clone();
+            thread::spawn(move || {
+                let (tx, _) = global.read().unwrap().subs.queue();
+                let mut state = ThreadState::new(global, store, tx);
+                let name = format!("bnc_{}_btc", i);
+                state.create(&name);
+                for ts in 0..1000 {
+                    state.insert(up(ts), &name).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let rdr = global.read().unwrap();
+        for i in 0..4 {
+            let vecs = rdr.vec_store.get(&format!("bnc_{}_btc", i)).unwrap();
+            assert_eq!(1000, vecs.read().unwrap().ups.len());
+        }
+    }
+
+    #[test]
+    fn should_not_deadlock_with_concurrent_subscribers_and_inserters() {
+        use futures::Stream;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let global = Arc::new(RwLock::new(SharedState::new(Default::default())));
+        let store = Arc::new(RwLock::new(HashMap::new()));
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // a subscriber that stays subscribed must see every insert into its store
+        let (watch_tx, watch_rx) = global.read().unwrap().subs.queue();
+        let mut watcher = ThreadState::new(global.clone(), store.clone(), watch_tx);
+        watcher.create("bnc_0_btc");
+        watcher.sub("bnc_0_btc", Filter::default());
+
+        for i in 0..4 {
+            let (global, store, done_tx) = (global.clone(), store.clone(), done_tx.clone());
+            thread::spawn(move || {
+                let (tx, _) = global.read().unwrap().subs.queue();
+                let mut state = ThreadState::new(global, store, tx);
+                let name = format!("bnc_{}_btc", i);
+                state.create(&name);
+                for ts in 0..1000 {
+                    state.insert(up(ts), &name).unwrap();
+                }
+                done_tx.send(()).unwrap();
+            });
+        }
+        for i in 0..4 {
+            let (global, store, done_tx) = (global.clone(), store.clone(), done_tx.clone());
+            thread::spawn(move || {
+                let (tx, rx) = global.read().unwrap().subs.queue();
+                let mut state = ThreadState::new(global, store, tx);
+                for _ in 0..200 {
+                    state.sub(&format!("bnc_{}_btc", i), Filter::default());
+                    state.unsub(&format!("bnc_{}_btc", i));
+                }
+                drop(rx);
+                done_tx.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..8 {
+            done_rx.recv_timeout(Duration::from_secs(30)).expect("deadlocked");
+        }
+
+        let received: Vec<_> = watch_rx.wait().take(1000).map(|ev| ev.unwrap().1.ts).collect();
+        assert_eq!((0..1000).collect::<Vec<_>>(), received);
+    }
+
+    #[test]
+    fn should_replay_then_stream_without_gaps_or_duplicates() {
+        use futures::Stream;
+
+        let (_, global, store, mut state) = test_state("tectonic-replay-test", Settings { flush_interval: 1000, ..Default::default() });
+        let (tx, rx) = global.read().unwrap().subs.queue();
+        state.subscription_tx = tx;
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 0..100 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        // not flushed yet, at the ts of the last update on disk
+        state.insert(Update { size: 2., ..up(99) }, "bnc_eth_btc").unwrap();
+        for ts in 100..200 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        let inserter = {
+            let (global, store) = (global.clone(), store.clone());
+            thread::spawn(move || {
+                let (tx, _) = global.read().unwrap().subs.queue();
+                let mut state = ThreadState::new(global, store, tx);
+                for ts in 200..5000 {
+                    state.insert(up(ts), "bnc_eth_btc").unwrap();
+                }
+            })
+        };
+        state.sub_from("bnc_eth_btc", 50, Filter::default()).unwrap();
+        inserter.join().unwrap();
+
+        let received: Vec<_> = rx.wait().take(4951).map(|ev| ev.unwrap().1.ts).collect();
+        let mut expected: Vec<u64> = (50..5000).collect();
+        expected.insert(50, 99);
+        assert_eq!(expected, received);
+    }
+
+    #[test]
+    fn should_replay_past_the_queue_capacity() {
+        use futures::Stream;
+        use subscription::QueuePolicy;
+
+        for &policy in &[QueuePolicy::Disconnect, QueuePolicy::Conflate, QueuePolicy::DropOldest] {
+            let settings = Settings { sub_queue_capacity: 10, sub_queue_policy: policy, ..Default::default() };
+            let (_, global, _, mut state) = test_state("tectonic-replay-capacity-test", settings);
+            let (tx, rx) = global.read().unwrap().subs.queue();
+            state.subscription_tx = tx;
+            state.create("bnc_eth_btc");
+            state.use_db("bnc_eth_btc").unwrap();
+            for ts in 0..500 {
+                state.insert(up(ts), "bnc_eth_btc").unwrap();
+            }
+            state.flush().unwrap();
+            for ts in 500..1000 {
+                state.insert(up(ts), "bnc_eth_btc").unwrap();
+            }
+
+            // neither the replay nor what's pushed while it's read is held to the capacity
+            state.sub_from("bnc_eth_btc", 0, Filter::default()).unwrap();
+            for ts in 1000..1100 {
+                state.insert(up(ts), "bnc_eth_btc").unwrap();
+            }
+            // the fan-out thread pushes in order, once this is through so are the others
+            let subs = global.read().unwrap().subs.clone();
+            let (probe_tx, probe_rx) = subs.queue();
+            subs.sub("bnc_xrp_btc".to_owned(), Filter::default(), probe_tx);
+            state.create("bnc_xrp_btc");
+            state.insert(up(0), "bnc_xrp_btc").unwrap();
+            probe_rx.wait().next().unwrap().unwrap();
+
+            let received: Vec<_> = rx.wait().take(1100).map(|ev| ev.unwrap().1.ts).collect();
+            assert_eq!((0..1100).collect::<Vec<_>>(), received, "{}", policy);
+            assert_eq!(0, global.read().unwrap().subs.stats.dropped.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn should_rename_and_drop_files() {
+        let (folder, global, _, mut state) = test_state("tectonic-rename-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 0..10 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+
+        state.rename("bnc_eth_btc", "bnc_xrp_btc").unwrap();
+        assert!(!Path::new(&format!("{}/bnc_eth_btc.dtf", folder)).exists());
+        let fname = format!("{}/bnc_xrp_btc.dtf", folder);
+        assert_eq!("bnc_xrp_btc", dtf::read_meta(&fname).unwrap().symbol);
+        assert_eq!(10, dtf::decode(&fname, None).unwrap().len());
+
+        state.copy("bnc_xrp_btc", "bnc_ltc_btc").unwrap();
+        assert_eq!("bnc_ltc_btc", dtf::read_meta(&format!("{}/bnc_ltc_btc.dtf", folder)).unwrap().symbol);
+
+        state.drop_db("bnc_xrp_btc", true).unwrap();
+        assert!(!Path::new(&fname).exists());
+        assert!(!global.read().unwrap().vec_store.contains_key("bnc_xrp_btc"));
+        assert!(state.drop_db("bnc_xrp_btc", true).is_err());
+    }
+
+    #[test]
+    fn should_drop_without_holding_other_stores_up() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let (folder, global, store, mut state) = test_state("tectonic-drop-test", Default::default());
+        state.create("bnc_xrp_btc");
+        state.create("bnc_eth_btc");
+        for ts in 0..10 {
+            state.insert(up(ts), "bnc_xrp_btc").unwrap();
+        }
+
+        // a flush of the store being dropped is held back
+        let vecs = global.read().unwrap().vec_store["bnc_xrp_btc"].clone();
+        let lock = vecs.read().unwrap().flushing.lock.clone();
+        let writing = lock.lock().unwrap();
+        let _flushing = store.read().unwrap()["bnc_xrp_btc"].flush();
+        let (done_tx, done_rx) = mpsc::channel();
+        {
+            let (global, store) = (global.clone(), store.clone());
+            thread::spawn(move || {
+                let (tx, _) = global.read().unwrap().subs.queue();
+                let mut dropper = ThreadState::new(global, store, tx);
+                done_tx.send(dropper.drop_db("bnc_xrp_btc", true)).unwrap();
+            });
+        }
+
+        // other stores are used meanwhile
+        thread::sleep(Duration::from_millis(50));
+        state.use_db("bnc_eth_btc").unwrap();
+        state.insert(up(1), "bnc_eth_btc").unwrap();
+        assert_eq!(1, state.count());
+        assert!(done_rx.try_recv().is_err());
+
+        drop(writing);
+        done_rx.recv_timeout(Duration::from_secs(30)).expect("deadlocked").unwrap();
+        assert!(!store.read().unwrap().contains_key("bnc_xrp_btc"));
+        assert!(!Path::new(&format!("{}/bnc_xrp_btc.dtf", folder)).exists());
+    }
+
+    #[test]
+    fn should_follow_renamed_and_dropped_stores() {
+        use futures::Stream;
+
+        let (folder, global, store, mut state) = test_state("tectonic-follow-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        state.insert(up(1), "bnc_eth_btc").unwrap();
+        state.flush().unwrap();
+
+        let (tx, rx) = global.read().unwrap().subs.queue();
+        let mut other = ThreadState::new(global.clone(), store.clone(), tx);
+        other.use_db("bnc_eth_btc").unwrap();
+        other.sub("bnc_eth_btc", Filter::default());
+
+        // the files are put back when one can't be moved
+        let taken = format!("{}/bnc_xrp_btc.dtf", folder);
+        fs::write(&taken, b"taken").unwrap();
+        assert!(state.rename("bnc_eth_btc", "bnc_xrp_btc").is_err());
+        assert!(Path::new(&format!("{}/bnc_eth_btc.dtf", folder)).exists());
+        assert!(store.read().unwrap().contains_key("bnc_eth_btc"));
+        fs::remove_file(&taken).unwrap();
+
+        state.rename("bnc_eth_btc", "bnc_xrp_btc").unwrap();
+        other.follow_store_changes();
+        assert_eq!("default", other.current_store_name);
+        assert_eq!(vec!["bnc_xrp_btc"], other.subscriptions.keys().collect::<Vec<_>>());
+        state.insert(up(2), "bnc_xrp_btc").unwrap();
+        // flushes write files named after the new name
+        state.use_db("bnc_xrp_btc").unwrap();
+        state.flush().unwrap();
+        assert_eq!(2, dtf::decode(&taken, None).unwrap().len());
+
+        state.drop_db("bnc_xrp_btc", true).unwrap();
+        other.follow_store_changes();
+        assert!(other.subscriptions.is_empty());
+        drop((state, other, global, store));
+
+        let received: Vec<_> = rx.wait().map(|ev| ev.unwrap()).collect();
+        assert_eq!(vec![("bnc_xrp_btc".to_owned(), up(2))], received);
+    }
+
+    #[test]
+    fn should_rotate_segments_and_read_only_overlapping_ones() {
+        let (folder, global, _, mut state) = test_state("tectonic-partition-test", Settings { partition: Partition::Hourly, ..Default::default() });
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        let hour = 3_600_000;
+        let t0 = 1_514_764_800_000; // 2018-01-01T00:00:00Z
+        for i in 0..3 {
+            state.insert(up(t0 + i * hour / 2), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        for i in 3..6 {
+            state.insert(up(t0 + i * hour / 2), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+
+        let segments = global.read().unwrap().vec_store["bnc_eth_btc"].read().unwrap().segments.clone();
+        let fnames: Vec<String> = segments.iter().map(|seg| seg.fname.clone()).collect();
+        assert_eq!(vec![
+            format!("{}/bnc_eth_btc-2018010100.dtf", folder),
+            format!("{}/bnc_eth_btc-2018010101.dtf", folder),
+            format!("{}/bnc_eth_btc-2018010102.dtf", folder),
+        ], fnames);
+        assert_eq!(vec![2, 2, 2], segments.iter().map(|seg| seg.count).collect::<Vec<_>>());
+
+        state.rename("bnc_eth_btc", "bnc_xrp_btc").unwrap();
+        assert!(!Path::new(&fnames[0]).exists());
+        let fname = format!("{}/bnc_xrp_btc-2018010101.dtf", folder);
+        assert_eq!("bnc_xrp_btc", dtf::read_meta(&fname).unwrap().symbol);
+
+        // a segment outside of the range is never opened
+        fs::write(format!("{}/bnc_xrp_btc-2018010100.dtf", folder), b"garbage").unwrap();
+        state.current_store_name = "bnc_xrp_btc".into();
+        let range = Some((t0 + 2 * hour - 1, t0 + 3 * hour - 1));
+        match state.get(ReqCount::All, GetFormat::Json, range, Loc::Fs) {
+            Some(ReturnType::String(json)) => {
+                let ups: serde_json::Value = serde_json::from_str(&json).unwrap();
+                assert_eq!(2, ups.as_array().unwrap().len());
+            }
+            _ => panic!("expected JSON"),
+        }
+
+        // a segment moved away, e.g. for upload, is skipped
+        fs::remove_file(&fname).unwrap();
+        let range = Some((t0 + hour, t0 + 3 * hour - 1));
+        match state.get(ReqCount::All, GetFormat::Json, range, Loc::Fs) {
+            Some(ReturnType::String(json)) => {
+                let ups: serde_json::Value = serde_json::from_str(&json).unwrap();
+                assert_eq!(2, ups.as_array().unwrap().len());
+            }
+            _ => panic!("expected JSON"),
+        }
+        let chunks = state.get_chunks(ReqCount::All, GetFormat::Dtf, range, Loc::Fs).unwrap();
+        let n: usize = chunks.map(|chunk| dtf::decode_buffer(&mut chunk.unwrap().as_slice()).len()).sum();
+        assert_eq!(2, n);
+    }
+
+    #[test]
+    fn should_merge_sources_in_order_without_duplicates() {
+        let at = |ts, seq| Update { seq, ..up(ts) };
+        let mem = vec![at(5, 0), at(3, 1), at(3, 0), at(7, 0)];
+        let disk = vec![at(1, 0), at(3, 0), at(3, 2), at(5, 0)];
+        let merged = merge_sources(vec![mem], disk);
+        let keys: Vec<(u64, u32)> = merged.iter().map(|up| (up.ts, up.seq)).collect();
+        assert_eq!(vec![(1, 0), (3, 0), (3, 1), (3, 2), (5, 0), (7, 0)], keys);
+
+        // same key but a different size is another update
+        let merged = merge_sources(vec![vec![up(1)]], vec![Update { size: 2., ..up(1) }, up(1)]);
+        assert_eq!(2, merged.len());
+
+        // inserted twice with DEDUPE off, once on disk since
+        let merged = merge_sources(vec![vec![up(1)], vec![up(1)]], vec![up(1)]);
+        assert_eq!(2, merged.len());
+        let merged = merge_sources(vec![], vec![up(1), up(1)]);
+        assert_eq!(2, merged.len());
+    }
+
+    #[test]
+    fn should_merge_flushed_and_unflushed_in_get() {
+        let (_, _, _, mut state) = test_state("tectonic-merge-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in &[2, 4, 6] {
+            state.insert(up(*ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        state.set_late_policy("bnc_eth_btc", LatePolicy::Accept).unwrap();
+        for ts in &[5, 3, 4, 7] {
+            state.insert(up(*ts), "bnc_eth_btc").unwrap();
+        }
+
+        let mut get_ts = |count, loc| match state.get(count, GetFormat::Json, Some((1, 10)), loc) {
+            Some(ReturnType::String(json)) => {
+                let ups: serde_json::Value = serde_json::from_str(&json).unwrap();
+                // ts is in seconds
+                ups.as_array().unwrap()
+                    .iter()
+                    .map(|up| (up["ts"].as_f64().unwrap() * 1000.).round() as u64)
+                    .collect::<Vec<u64>>()
+            }
+            _ => panic!("expected JSON"),
+        };
+        assert_eq!(vec![2, 3, 4, 5, 6, 7], get_ts(ReqCount::All, Loc::Fs));
+        assert_eq!(vec![2, 3, 4], get_ts(ReqCount::Count(3), Loc::Fs));
+        assert_eq!(vec![3, 4, 5, 7], get_ts(ReqCount::All, Loc::Mem));
+    }
+
+    #[test]
+    fn should_flush_in_background_and_serve_updates_in_flight() {
+        let (_, global, store, mut state) = test_state("tectonic-background-flush-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 1..11 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        // hold the writer back
+        let vecs = global.read().unwrap().vec_store["bnc_eth_btc"].clone();
+        let lock = vecs.read().unwrap().flushing.lock.clone();
+        let writing = lock.lock().unwrap();
+        let flushing = store.read().unwrap()["bnc_eth_btc"].flush().unwrap();
+        assert_eq!(1, vecs.read().unwrap().flushing.queue.len());
+        assert!(vecs.read().unwrap().ups.is_empty());
+
+        // inserts go on and queries see what's being flushed
+        state.insert(up(11), "bnc_eth_btc").unwrap();
+        assert_eq!(11, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+        match state.get(ReqCount::All, GetFormat::Json, Some((1, 20)), Loc::Fs) {
+            Some(ReturnType::String(json)) => {
+                let ups: serde_json::Value = serde_json::from_str(&json).unwrap();
+                assert_eq!(11, ups.as_array().unwrap().len());
+            }
+            _ => panic!("expected JSON"),
+        }
+
+        drop(writing);
+        flushing.wait().unwrap();
+        assert!(vecs.read().unwrap().flushing.queue.is_empty());
+        assert_eq!(10, vecs.read().unwrap().segments[0].count);
+        assert_eq!(1, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+        assert_eq!(11, state.count());
+    }
+
+    #[test]
+    fn should_put_buffer_back_when_background_write_fails() {
+        let (folder, global, store, mut state) = test_state("tectonic-failed-write-test", Default::default());
+        fs::remove_dir_all(&folder).unwrap();
+        fs::write(&folder, b"").unwrap();
+        state.create("bnc_eth_btc");
+        for ts in 1..6 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        let vecs = global.read().unwrap().vec_store["bnc_eth_btc"].clone();
+        let flushing = store.read().unwrap()["bnc_eth_btc"].flush().unwrap();
+        assert!(flushing.wait().unwrap_err().starts_with("Unable to write "));
+        assert!(vecs.read().unwrap().flushing.queue.is_empty());
+        let ts: Vec<u64> = vecs.read().unwrap().ups.iter().map(|up| up.ts).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5], ts);
+        assert_eq!(5, state.countall());
+        assert_eq!(vecs.read().unwrap().mem_bytes, global.read().unwrap().mem.used());
+
+        fs::remove_file(&folder).unwrap();
+        store.read().unwrap()["bnc_eth_btc"].flush().unwrap().wait().unwrap();
+        assert!(vecs.read().unwrap().ups.is_empty());
+        assert_eq!(5, vecs.read().unwrap().segments[0].count);
+    }
+
+    #[test]
+    fn should_keep_updates_in_memory_when_flush_fails() {
+        let (folder, _, store, mut state) = test_state("tectonic-failed-flush-test", Default::default());
+        // a file where the folder should be
+        fs::remove_dir_all(&folder).unwrap();
+        fs::write(&folder, b"").unwrap();
+        state.create("bnc_eth_btc");
+        state.create("bnc_xrp_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 1..6 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+            state.insert(up(ts), "bnc_xrp_btc").unwrap();
+        }
+
+        assert!(state.flush().unwrap_err().starts_with("Unable to write "));
+        let err = state.flushall().unwrap_err();
+        assert!(err.contains("bnc_eth_btc: ") && err.contains("bnc_xrp_btc: "));
+        assert_eq!(5, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+        assert_eq!(10, state.countall_in_mem());
+
+        let status: serde_json::Value = serde_json::from_str(&state.flush_status()).unwrap();
+        let eth = status.as_array().unwrap().iter().find(|st| st["name"] == "bnc_eth_btc").unwrap();
+        assert_eq!(0, eth["updates"]);
+        assert!(eth["result"].as_str().unwrap().starts_with("Unable to write "));
+
+        // what was put back still counts for dedupe and late updates
+        state.set_dedupe("bnc_xrp_btc", true).unwrap();
+        state.set_late_policy("bnc_xrp_btc", LatePolicy::Drop).unwrap();
+        assert!(state.flushall().is_err());
+        state.insert(up(5), "bnc_xrp_btc").unwrap();
+        state.insert(up(3), "bnc_xrp_btc").unwrap();
+        let inserts = store.read().unwrap()["bnc_xrp_btc"].vecs.read().unwrap().inserts.clone();
+        assert_eq!((1, 1), (inserts.duplicates, inserts.late));
+        assert_eq!(10, state.countall_in_mem());
+
+        fs::remove_file(&folder).unwrap();
+        state.flushall().unwrap();
+        assert_eq!(0, state.countall_in_mem());
+        assert_eq!(5, state.count());
+        let status: serde_json::Value = serde_json::from_str(&state.flush_status()).unwrap();
+        let eth = status.as_array().unwrap().iter().find(|st| st["name"] == "bnc_eth_btc").unwrap();
+        assert_eq!(5, eth["updates"]);
+        assert_eq!("ok", eth["result"]);
+        assert!(eth["last_flush"].is_u64());
+    }
+
+    #[test]
+    fn should_stream_get_in_chunks() {
+        let (_, _, _, mut state) = test_state("tectonic-stream-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 1..15_001 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        for ts in 15_001..15_006 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        let lens = |chunks: GetChunks| -> Vec<usize> {
+            chunks
+                .map(|chunk| {
+                    let ups: serde_json::Value = serde_json::from_slice(&chunk.unwrap()).unwrap();
+                    ups.as_array().unwrap().len()
+                })
+                .collect()
+        };
+        let range = Some((1, 20_000));
+        let chunks = state.get_chunks(ReqCount::All, GetFormat::Json, range, Loc::Fs).unwrap();
+        assert_eq!(vec![CHUNK_SIZE, 15_005 - CHUNK_SIZE], lens(chunks));
+        let chunks = state.get_chunks(ReqCount::Count(7), GetFormat::Json, range, Loc::Fs).unwrap();
+        assert_eq!(vec![7], lens(chunks));
+
+        // memory and disk come out in ts order
+        let chunks = state.get_chunks(ReqCount::All, GetFormat::Dtf, Some((14_990, 15_003)), Loc::Fs).unwrap();
+        let ts: Vec<u64> = chunks
+            .flat_map(|chunk| dtf::decode_buffer(&mut chunk.unwrap().as_slice()))
+            .map(|up| up.ts)
+            .collect();
+        assert_eq!((14_990..15_004).collect::<Vec<u64>>(), ts);
+        let chunks = state.get_chunks(ReqCount::All, GetFormat::Json, range, Loc::Mem).unwrap();
+        assert_eq!(vec![5], lens(chunks));
+    }
+
+    #[test]
+    fn should_page_through_range_with_cursor() {
+        let (_, _, _, mut state) = test_state("tectonic-cursor-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 1..1_001 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        for ts in 1_001..1_006 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        let id = state.open_cursor("bnc_eth_btc", 10, 1_002).unwrap();
+        let mut cursor = state.cursors.remove(&id).unwrap();
+        let mut seen = vec![];
+        loop {
+            let ups = cursor.fetch(300).unwrap();
+            if ups.is_empty() {
+                break;
+            }
+            assert!(ups.len() <= 300);
+            seen.extend(ups.iter().map(|up| up.ts));
+        }
+        assert_eq!((10..1_003).collect::<Vec<u64>>(), seen);
+        state.cursors.insert(id, cursor);
+
+        assert!(state.close_cursor(id));
+        assert!(!state.close_cursor(id));
+        assert!(state.fetch(id, 1, GetFormat::Json).is_err());
+
+        // a client asking for more gets a chunk at most
+        for ts in 20_000..20_000 + CHUNK_SIZE as u64 + 10 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        let id = state.open_cursor("bnc_eth_btc", 20_000, 40_000).unwrap();
+        match state.fetch(id, u32::max_value(), GetFormat::Csv).unwrap() {
+            ReturnType::String(csv) => assert_eq!(CHUNK_SIZE, csv.trim().lines().count()),
+            _ => panic!("expected CSV"),
+        }
+        assert!(state.open_cursor("nope", 0, 1).is_err());
+    }
+
+    #[test]
+    fn should_describe_store() {
+        let (_, _, _, mut state) = test_state("tectonic-describe-test", Default::default());
+        state.create("bnc_eth_btc");
+        for ts in 0..10 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.use_db("bnc_eth_btc").unwrap();
+        state.flush().unwrap();
+        for ts in 10..15 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.sub("bnc_*", Filter::default());
+
+        let desc: serde_json::Value = serde_json::from_str(&state.describe("bnc_eth_btc").unwrap()).unwrap();
+        assert_eq!(15, desc["count"]);
+        assert_eq!(5, desc["in_memory_count"]);
+        assert_eq!(10, desc["on_disk_count"]);
+        assert_eq!(0, desc["min_ts"]);
+        assert_eq!(14, desc["max_ts"]);
+        assert_eq!(1, desc["files"].as_array().unwrap().len());
+        assert_eq!("eth", desc["symbol"]["currency"]);
+        assert!(desc["last_insert"].is_u64());
+        assert_eq!(1, desc["subscribers"]);
+
+        assert!(state.describe("bnc_xrp_btc").is_none());
+        assert_eq!("[\"bnc_eth_btc\",\"default\"]\n", state.show_databases());
+    }
+
+    #[test]
+    fn should_delete_range_from_memory_and_files() {
+        let (folder, _, store, mut state) = test_state("tectonic-delete-test", Default::default());
+        state.create("bnc_eth_btc");
+        state.use_db("bnc_eth_btc").unwrap();
+        for ts in 0..10 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        state.flush().unwrap();
+        for ts in 10..15 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+
+        assert_eq!(Ok(6), state.delete_range("bnc_eth_btc", 7, 12));
+        let fname = format!("{}/bnc_eth_btc.dtf", folder);
+        let meta = dtf::read_meta(&fname).unwrap();
+        assert_eq!(7, meta.nums);
+        assert_eq!(6, meta.max_ts);
+        assert_eq!((0..7).collect::<Vec<_>>(), dtf::decode(&fname, None).unwrap().iter().map(|up| up.ts).collect::<Vec<_>>());
+        assert_eq!(9, store.read().unwrap()["bnc_eth_btc"].count());
+        assert_eq!(2, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+
+        assert_eq!(Ok(7), state.delete_range("bnc_eth_btc", 0, 6));
+        assert!(!Path::new(&fname).exists());
+        assert!(state.delete_range("bnc_xrp_btc", 0, 6).is_err());
+    }
+
+    #[test]
+    fn should_apply_late_and_dedupe_policies() {
+        let (folder, _, store, mut state) = test_state("tectonic-late-test", Default::default());
+        let inserts = |name: &str| store.read().unwrap()[name].vecs.read().unwrap().inserts.clone();
+
+        // late updates and duplicates are kept by default
+        state.create("bnc_eth_btc");
+        for &ts in &[1, 3, 2, 3] {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        let rules = inserts("bnc_eth_btc");
+        assert_eq!((1, 0, 0), (rules.late, rules.dropped, rules.duplicates));
+        assert_eq!(4, store.read().unwrap()["bnc_eth_btc"].count());
+
+        // or dropped
+        state.create("bnc_neo_btc");
+        state.set_late_policy("bnc_neo_btc", LatePolicy::Drop).unwrap();
+        for &ts in &[1, 3, 2, 3] {
+            state.insert(up(ts), "bnc_neo_btc").unwrap();
+        }
+        let rules = inserts("bnc_neo_btc");
+        assert_eq!((1, 1, 0), (rules.late, rules.dropped, rules.duplicates));
+        assert_eq!(3, store.read().unwrap()["bnc_neo_btc"].count());
+
+        // rejecting fails the insert
+        state.create("bnc_xrp_btc");
+        state.set_late_policy("bnc_xrp_btc", LatePolicy::Reject).unwrap();
+        state.insert(up(5), "bnc_xrp_btc").unwrap();
+        assert!(state.insert(up(4), "bnc_xrp_btc").is_err());
+        assert_eq!(1, inserts("bnc_xrp_btc").late);
+
+        // kept late updates are in memory until a flush, which only appends what's newer
+        // than the file, memory read in by `USE` included
+        state.create("bnc_bch_btc");
+        state.set_late_policy("bnc_bch_btc", LatePolicy::Keep).unwrap();
+        state.use_db("bnc_bch_btc").unwrap();
+        for &ts in &[2, 4] {
+            state.insert(up(ts), "bnc_bch_btc").unwrap();
+        }
+        state.flush().unwrap();
+        state.use_db("bnc_bch_btc").unwrap();
+        for &ts in &[3, 4, 5] {
+            state.insert(up(ts), "bnc_bch_btc").unwrap();
+        }
+        assert_eq!(5, store.read().unwrap()["bnc_bch_btc"].count());
+        state.flush().unwrap();
+        let fname = format!("{}/bnc_bch_btc.dtf", folder);
+        let ts: Vec<u64> = dtf::decode(&fname, None).unwrap().iter().map(|up| up.ts).collect();
+        assert_eq!(vec![2, 4, 5], ts);
+        let rules = inserts("bnc_bch_btc");
+        assert_eq!((1, 2), (rules.late, rules.dropped));
+        assert_eq!(3, store.read().unwrap()["bnc_bch_btc"].count());
+
+        // accepted late updates are merged into the file, deduped
+        state.create("bnc_ltc_btc");
+        state.set_late_policy("bnc_ltc_btc", LatePolicy::Accept).unwrap();
+        state.set_dedupe("bnc_ltc_btc", true).unwrap();
+        state.use_db("bnc_ltc_btc").unwrap();
+        for &ts in &[2, 4, 6] {
+            state.insert(up(ts), "bnc_ltc_btc").unwrap();
+        }
+        state.flush().unwrap();
+        for &ts in &[5, 1, 5, 4] {
+            state.insert(up(ts), "bnc_ltc_btc").unwrap();
+        }
+        state.flush().unwrap();
+        let fname = format!("{}/bnc_ltc_btc.dtf", folder);
+        let ts: Vec<u64> = dtf::decode(&fname, None).unwrap().iter().map(|up| up.ts).collect();
+        assert_eq!(vec![1, 2, 4, 5, 6], ts);
+        let rules = inserts("bnc_ltc_btc");
+        assert_eq!((2, 2), (rules.duplicates, rules.dropped));
+        assert_eq!(5, store.read().unwrap()["bnc_ltc_btc"].count());
+
+        // duplicates of updates already flushed are dropped too
+        for &ts in &[6, 5, 1, 7] {
+            state.insert(up(ts), "bnc_ltc_btc").unwrap();
+        }
+        assert_eq!(5, inserts("bnc_ltc_btc").duplicates);
+        assert_eq!(6, store.read().unwrap()["bnc_ltc_btc"].count());
+
+        // the policies outlast a restart, and follow the store when renamed
+        state.set_reject_malformed("bnc_ltc_btc", true).unwrap();
+        state.set_validation("bnc_ltc_btc", ValidationRule::MaxFuture, Some(60)).unwrap();
+        state.set_validation("bnc_ltc_btc", ValidationRule::MaxSeqRegression, Some(10)).unwrap();
+        state.set_validation("bnc_ltc_btc", ValidationRule::MaxSeqRegression, None).unwrap();
+        state.rename("bnc_ltc_btc", "bnc_ltc_eth").unwrap();
+        let mut restarted = SharedState::new(Settings { dtf_folder: folder.clone(), ..Default::default() });
+        let mut policy = |name: &str| {
+            let rules = restarted.get_or_create_vec_store(name).read().unwrap().inserts.clone();
+            (rules.dedupe, rules.late_policy, rules.validation)
+        };
+        let validation = Validation { reject_malformed: true, max_future: Some(60_000), ..Default::default() };
+        assert_eq!((true, LatePolicy::Accept, validation), policy("bnc_ltc_eth"));
+        assert_eq!((false, LatePolicy::Reject, Validation::default()), policy("bnc_xrp_btc"));
+        assert_eq!((false, LatePolicy::Accept, Validation::default()), policy("bnc_eth_btc"));
+    }
+
+    #[test]
+    fn should_validate_updates() {
+        let validation = Validation {
+            reject_malformed: true,
+            max_future: Some(1000),
+            max_past: Some(1000),
+            max_seq_regression: Some(10),
+        };
+        let now = 1_000_000;
+        let ok = Update { ts: now, seq: 100, is_trade: false, is_bid: true, price: 1., size: 1. };
+        assert!(validation.check(&ok, now, Some(105)).is_ok());
+
+        assert!(validation.check(&Update { ts: 0, ..ok }, now, None).is_err());
+        assert!(validation.check(&Update { price: ::std::f32::NAN, ..ok }, now, None).is_err());
+        assert!(validation.check(&Update { size: ::std::f32::INFINITY, ..ok }, now, None).is_err());
+        assert!(validation.check(&Update { ts: now + 1001, ..ok }, now, None).is_err());
+        assert!(validation.check(&Update { ts: now - 1001, ..ok }, now, None).is_err());
+        assert!(validation.check(&ok, now, Some(111)).is_err());
+        assert!(Validation::default().check(&Update { ts: 0, price: -1., ..ok }, now, Some(111)).is_ok());
+    }
+
+    #[test]
+    fn should_keep_memory_within_budget() {
+        let (_, global, store, mut state) = test_state("tectonic-memory-test", Default::default());
+        let mem_bytes = |name: &str| store.read().unwrap()[name].vecs.read().unwrap().mem_bytes;
+        state.create("bnc_eth_btc");
+        state.create("bnc_xrp_btc");
+        for ts in 1..9 {
+            state.insert(up(ts), "bnc_eth_btc").unwrap();
+        }
+        for ts in 1..3 {
+            state.insert(up(ts), "bnc_xrp_btc").unwrap();
         }
+        let mem = global.read().unwrap().mem.clone();
+        assert!(mem_bytes("bnc_eth_btc") >= 8 * UPDATE_SIZE);
+        assert_eq!(mem_bytes("bnc_eth_btc") + mem_bytes("bnc_xrp_btc"), mem.used());
+
+        // the keys kept for dedupe count too
+        let before = mem_bytes("bnc_xrp_btc");
+        state.set_dedupe("bnc_xrp_btc", true).unwrap();
+        assert!(mem_bytes("bnc_xrp_btc") > before);
+        assert_eq!(mem_bytes("bnc_eth_btc") + mem_bytes("bnc_xrp_btc"), mem.used());
+
+        // the biggest store is flushed to make room
+        global.write().unwrap().settings.memory_budget = mem.used();
+        state.insert(up(3), "bnc_xrp_btc").unwrap();
+        assert_eq!(1, mem.forced_flushes.load(Ordering::SeqCst));
+        assert_eq!(0, store.read().unwrap()["bnc_eth_btc"].count_in_mem());
+        assert_eq!(8, store.read().unwrap()["bnc_eth_btc"].count());
+        assert_eq!(0, mem_bytes("bnc_eth_btc"));
+        assert_eq!(mem_bytes("bnc_xrp_btc"), mem.used());
+
+        // room is held until the reservation is dropped
+        global.write().unwrap().settings.memory_policy = MemoryPolicy::Reject;
+        global.write().unwrap().settings.memory_budget = mem.used() + UPDATE_SIZE;
+        let reserved = state.reserve_memory(1).unwrap();
+        assert!(state.insert(up(4), "bnc_xrp_btc").is_err());
+        assert_eq!(1, mem.rejected.load(Ordering::SeqCst));
+        drop(reserved);
+        assert_eq!(mem_bytes("bnc_xrp_btc"), mem.used());
+        global.write().unwrap().settings.memory_budget = 0;
+        state.insert(up(4), "bnc_xrp_btc").unwrap();
+
+        // so is the copy of memory a cursor holds, until it's closed
+        let used = mem.used();
+        let id = state.open_cursor("bnc_xrp_btc", 0, 10).unwrap();
+        assert_eq!(used + 4 * UPDATE_SIZE, mem.used());
+        assert!(state.close_cursor(id));
+        assert_eq!(used, mem.used());
+        global.write().unwrap().settings.memory_budget = used + UPDATE_SIZE;
+        assert!(state.open_cursor("bnc_xrp_btc", 0, 10).is_err());
+        assert_eq!(used, mem.used());
+        global.write().unwrap().settings.memory_budget = 0;
+
+        state.drop_db("bnc_xrp_btc", true).unwrap();
+        assert_eq!(0, mem.used());
     }
 }
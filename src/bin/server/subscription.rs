@@ -1,199 +1,569 @@
 use std::thread;
-use std::sync::{mpsc, Arc, Mutex};
-use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
 use libtectonic::dtf::update::Update;
 
-use futures;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc;
+use futures::task::AtomicTask;
 
-pub type Event = Arc<Mutex<(String, Update)>>;
+pub type Event = (String, Update);
 
-enum Message {
-    Msg(Event),
-    Terminate,
-}
-
-#[derive(Debug)]
-/// using SUBSCRIBE [db] command
-/// user can poll from the newly inserted updates
-/// server will responde to blank line "" query
-pub struct Subscriptions {
-    // /// a list of output receivers
-    // o_rxs: HashMap<String, Arc<Mutex<mpsc::Receiver<Update>>>>,
-    /// string -> Subscription multiplexer
-    subs: HashMap<String, Vec<Subscription>>,
+/// Stored updates replayed by `SUBSCRIBE [db] FROM`, read on as the client takes them
+pub type Replay = Box<Iterator<Item = Event> + Send>;
 
-    /// input receivers
-    i_txs: HashMap<String, Vec<mpsc::Sender<Message>>>,
+/// What a full subscriber queue does with a new update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// evict the oldest queued update
+    DropOldest,
+    /// disconnect the slow client
+    Disconnect,
+    /// replace the queued update of the same price level, evicting the oldest if there is none
+    Conflate,
+}
 
-    /// sub count
-    sub_count: HashMap<String, usize>,
+impl Default for QueuePolicy {
+    fn default() -> QueuePolicy {
+        QueuePolicy::DropOldest
+    }
 }
 
-impl Subscriptions {
-    pub fn new() -> Subscriptions {
-        // let o_rxs = HashMap::new();
-        let subs = HashMap::new();
-        let sub_count = HashMap::new();
-        let i_txs = HashMap::new();
-        Subscriptions {
-            // o_rxs,
-            sub_count,
-            subs,
-            i_txs,
+impl FromStr for QueuePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<QueuePolicy, String> {
+        match s {
+            "drop_oldest" => Ok(QueuePolicy::DropOldest),
+            "disconnect" => Ok(QueuePolicy::Disconnect),
+            "conflate" => Ok(QueuePolicy::Conflate),
+            _ => Err(format!("Unknown subscription queue policy: {}", s)),
         }
     }
+}
 
-    pub fn sub(
-        &mut self,
-        filter: String,
-        push_tx: futures::sync::mpsc::UnboundedSender<Update>,
-    ) -> (usize, Arc<Mutex<mpsc::Receiver<Update>>>) {
-
-        let (i_tx, i_rx) = mpsc::channel();
-        let (o_tx, o_rx) = mpsc::channel();
-
-        let i_rx = Arc::new(Mutex::new(i_rx));
-        let o_rx = Arc::new(Mutex::new(o_rx));
-        let o_tx = Arc::new(Mutex::new(o_tx));
-
-        // upsert
-        // if there is a subscription on dbname
-        let id = if self.subs.contains_key(&filter) {
-            let mut count = self.sub_count.get_mut(&filter).unwrap();
-            *count += 1;
-            let sub_v = self.subs.get_mut(&filter).unwrap();
-            sub_v.push(Subscription::new(filter.clone(), i_rx, o_tx, push_tx));
-            self.i_txs.get_mut(&filter).unwrap().push(i_tx);
-            *count
-        } else {
-            self.sub_count.insert(filter.clone(), 1);
-            self.subs.insert(
-                filter.clone(),
-                vec![Subscription::new(filter.clone().clone(), i_rx, o_tx, push_tx)],
-            );
-            self.i_txs.insert(filter, vec![i_tx]);
-            1
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            QueuePolicy::DropOldest => "drop_oldest",
+            QueuePolicy::Disconnect => "disconnect",
+            QueuePolicy::Conflate => "conflate",
         };
+        write!(f, "{}", s)
+    }
+}
+
+/// counters of every subscriber queue, reported in INFO
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// updates evicted or conflated away
+    pub dropped: AtomicUsize,
+    /// clients disconnected for falling behind
+    pub disconnected: AtomicUsize,
+}
+
+/// (dbname, is_trade, is_bid, price bits), a trade doesn't replace the book's level
+type LevelKey = (String, bool, bool, u32);
+
+fn level_key(&(ref dbname, ref up): &Event) -> LevelKey {
+    (dbname.clone(), up.is_trade, up.is_bid, up.price.to_bits())
+}
+
+struct Queue {
+    ups: VecDeque<Event>,
+    /// replays not read to the end yet, sent after what's in `ups` when the first was queued
+    replays: VecDeque<Replay>,
+    /// the front replay is being read, outside the lock
+    reading: bool,
+    /// updates pushed while replaying, sent once the replays are read. Neither they nor the
+    /// replays are held to the capacity or the policy.
+    tail: VecDeque<Event>,
+    /// price level -> absolute position in `ups`, only used when conflating
+    levels: HashMap<LevelKey, usize>,
+    /// absolute position of `ups[0]`
+    head: usize,
+    /// fell behind under `QueuePolicy::Disconnect`
+    overflowed: bool,
+    rx_dropped: bool,
+}
 
-        (id, o_rx)
+impl Queue {
+    fn is_replaying(&self) -> bool {
+        self.reading || !self.replays.is_empty()
     }
 
-    pub fn unsub_all(&mut self) {
-        let to_unsub = {
-            let mut temp = vec![];
-            for (symbol, v) in self.subs.iter() {
-                for id in 0..v.len() {
-                    temp.push((id, symbol.clone()));
-                }
+    fn pop_front(&mut self) -> Option<Event> {
+        let ev = self.ups.pop_front()?;
+        if !self.levels.is_empty() {
+            let key = level_key(&ev);
+            if self.levels.get(&key) == Some(&self.head) {
+                self.levels.remove(&key);
             }
-            temp
-        };
-        println!("{:?}", to_unsub);
-
-        for &(ref id, ref symbol) in to_unsub.iter() {
-            self.unsub(*id + 1, &symbol);
         }
+        self.head += 1;
+        Some(ev)
     }
+}
 
-    pub fn unsub(&mut self, id: usize, filter: &str) {
+struct Shared {
+    queue: Mutex<Queue>,
+    task: AtomicTask,
+    senders: AtomicUsize,
+    /// 0 is unbounded
+    capacity: usize,
+    policy: QueuePolicy,
+    stats: Arc<QueueStats>,
+}
 
-        // decrement count
-        let count = match self.sub_count.get_mut(filter) {
-            Some(count) => count,
-            None => return,
-        };
-        if *count > 0 {
-            *count -= 1;
-        }
+/// pushes `(dbname, update)` to a client connection
+pub struct PushTX {
+    shared: Arc<Shared>,
+}
 
-        let id = if id == 0 { 0 } else { id - 1 };
+/// the client connection's end of a subscriber queue. Errors once the client fell behind
+/// under `QueuePolicy::Disconnect`.
+pub struct PushRX {
+    shared: Arc<Shared>,
+}
 
-        // terminate the thread
+/// Creates the subscriber queue of a client connection, holding at most `capacity` updates
+/// (0 is unbounded).
+pub fn queue(capacity: usize, policy: QueuePolicy, stats: Arc<QueueStats>) -> (PushTX, PushRX) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            ups: VecDeque::new(),
+            replays: VecDeque::new(),
+            reading: false,
+            tail: VecDeque::new(),
+            levels: HashMap::new(),
+            head: 0,
+            overflowed: false,
+            rx_dropped: false,
+        }),
+        task: AtomicTask::new(),
+        senders: AtomicUsize::new(1),
+        capacity,
+        policy,
+        stats,
+    });
+    (PushTX { shared: shared.clone() }, PushRX { shared })
+}
+
+impl PushTX {
+    /// Queues an update, applying the queue policy if it is full. Never blocks.
+    pub fn send(&self, ev: Event) -> Result<(), Event> {
+        let shared = &*self.shared;
         {
-            let i_tx = &match self.i_txs.get_mut(filter).unwrap().get(id) {
-                Some(i_tx) => i_tx,
-                None => return,
+            let mut queue = shared.queue.lock().unwrap();
+            if queue.rx_dropped || queue.overflowed {
+                return Err(ev);
+            }
+            if queue.is_replaying() {
+                queue.tail.push_back(ev);
+                drop(queue);
+                shared.task.notify();
+                return Ok(());
+            }
+
+            let key = if shared.policy == QueuePolicy::Conflate {
+                let key = level_key(&ev);
+                if let Some(&pos) = queue.levels.get(&key) {
+                    let i = pos - queue.head;
+                    queue.ups[i] = ev;
+                    shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Some(key)
+            } else {
+                None
             };
-            i_tx.send(Message::Terminate).unwrap();
+
+            if shared.capacity != 0 && queue.ups.len() >= shared.capacity {
+                shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                if shared.policy == QueuePolicy::Disconnect {
+                    warn!("Subscriber fell behind by {} updates, disconnecting.", shared.capacity);
+                    shared.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+                    queue.overflowed = true;
+                    queue.ups.clear();
+                    queue.tail.clear();
+                    queue.levels.clear();
+                    drop(queue);
+                    shared.task.notify();
+                    return Err(ev);
+                }
+                queue.pop_front();
+            }
+
+            if let Some(key) = key {
+                let pos = queue.head + queue.ups.len();
+                queue.levels.insert(key, pos);
+            }
+            queue.ups.push_back(ev);
         }
+        shared.task.notify();
+        Ok(())
+    }
+}
 
-        // remove closed Sender from list
+impl PushTX {
+    /// Queues a replay to be read on as the client takes updates, ahead of what's pushed from
+    /// now on. The capacity and the policy don't apply until it's read to the end.
+    pub fn replay(&self, replay: Replay) {
         {
-            &self.i_txs.get_mut(filter).unwrap().remove(id);
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.rx_dropped || queue.overflowed {
+                return;
+            }
+            queue.replays.push_back(replay);
         }
+        self.shared.task.notify();
+    }
 
-        let sub = self.subs.get_mut(filter).unwrap().get_mut(id).unwrap();
-        if let Some(thread) = sub.thread.take() {
-            thread.join().unwrap();
-        }
+    /// Whether both push to the same client connection.
+    fn same_queue(&self, other: &PushTX) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
     }
+}
 
-    // pub fn get(&self, filter: &str) -> Arc<Mutex<mpsc::Receiver<Update>>> {
-    //     self.o_rxs.get(filter).unwrap().clone()
-    // }
+impl Clone for PushTX {
+    fn clone(&self) -> PushTX {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        PushTX { shared: self.shared.clone() }
+    }
+}
 
-    pub fn msg(&self, f: Event) {
-        for i_tx_v in self.i_txs.values() {
-            for i_tx in i_tx_v {
-                match i_tx.send(Message::Msg(f.clone())) {
-                    Err(_) => error!("Mux message failed!"),
-                    _ => (),
+impl Drop for PushTX {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.task.notify();
+        }
+    }
+}
+
+impl Stream for PushRX {
+    type Item = Event;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Event>, ()> {
+        self.shared.task.register();
+        loop {
+            let mut replay = {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.overflowed {
+                    return Err(());
+                }
+                if !queue.is_replaying() {
+                    if let Some(ev) = queue.tail.pop_front() {
+                        return Ok(Async::Ready(Some(ev)));
+                    }
+                }
+                if let Some(ev) = queue.pop_front() {
+                    return Ok(Async::Ready(Some(ev)));
+                }
+                match queue.replays.pop_front() {
+                    Some(replay) => {
+                        queue.reading = true;
+                        replay
+                    }
+                    None if self.shared.senders.load(Ordering::SeqCst) == 0 => return Ok(Async::Ready(None)),
+                    None => return Ok(Async::NotReady),
                 }
+            };
+            // may read the files, so without holding up the fan-out thread
+            let ev = replay.next();
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.reading = false;
+            if let Some(ev) = ev {
+                queue.replays.push_front(replay);
+                return Ok(Async::Ready(Some(ev)));
             }
         }
     }
-
 }
 
-impl Drop for Subscriptions {
+impl Drop for PushRX {
     fn drop(&mut self) {
-        for i_tx_v in self.i_txs.values() {
-            for i_tx in i_tx_v.iter() {
-                i_tx.send(Message::Terminate).unwrap();
-            }
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.rx_dropped = true;
+        queue.ups.clear();
+        queue.replays.clear();
+        queue.tail.clear();
+        queue.levels.clear();
+    }
+}
+
+type SubscriberTable = HashMap<String, Vec<(usize, Filter, PushTX)>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+}
+
+impl Cmp {
+    fn test(&self, lhs: f32, rhs: f32) -> bool {
+        match *self {
+            Cmp::Lt => lhs < rhs,
+            Cmp::Le => lhs <= rhs,
+            Cmp::Gt => lhs > rhs,
+            Cmp::Ge => lhs >= rhs,
+            Cmp::Eq => lhs == rhs,
         }
+    }
+}
 
-        for worker_v in &mut self.subs.values_mut() {
-            for worker in worker_v.iter_mut() {
-                if let Some(thread) = worker.thread.take() {
-                    thread.join().unwrap();
-                }
-            }
+/// A single condition of `SUBSCRIBE [db] WHERE ...`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    IsTrade(bool),
+    IsBid(bool),
+    Price(Cmp, f32),
+    Size(Cmp, f32),
+}
+
+/// Conditions that must all hold for an update to be pushed. Empty matches everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter(pub Vec<Cond>);
+
+impl Filter {
+    pub fn matches(&self, up: &Update) -> bool {
+        self.0.iter().all(|cond| match *cond {
+            Cond::IsTrade(is_trade) => up.is_trade == is_trade,
+            Cond::IsBid(is_bid) => up.is_bid == is_bid,
+            Cond::Price(ref cmp, price) => cmp.test(up.price, price),
+            Cond::Size(ref cmp, size) => cmp.test(up.size, size),
+        })
+    }
+}
+
+/// Matches `name` against a glob `pattern`, where `*` matches any run of characters and `?`
+/// any single one. A pattern without either only matches itself.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and the name position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the last `*` swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
         }
     }
+    pattern[p..].iter().all(|&c| c == b'*')
 }
 
-#[derive(Debug)]
-struct Subscription {
-    thread: Option<thread::JoinHandle<()>>,
+enum Message {
+    Msg(Event),
+    Sub(String, usize, Filter, PushTX),
+    Unsub(String, usize),
+    /// a store was renamed, (from, to)
+    Rename(String, String),
+    /// a store was dropped
+    Remove(String),
 }
 
-impl Subscription {
-    fn new(
-        filter: String,
-        i_rx: Arc<Mutex<mpsc::Receiver<Message>>>,
-        _o_tx: Arc<Mutex<mpsc::Sender<Update>>>,
-        push_tx: futures::sync::mpsc::UnboundedSender<Update>
-    ) -> Subscription {
+/// using SUBSCRIBE [db|pattern] ... command, user gets the newly inserted updates pushed to
+/// them, tagged with the store name
+///
+/// Inserts only push into an unbounded (lock-free) channel. A single fan-out thread drains
+/// it and forwards each update to the subscribers whose pattern matches its store and whose
+/// `WHERE` filter it passes, once per connection however many of its subscriptions match.
+/// The patterns a store matches are remembered until the set of patterns changes.
+/// (Un)subscribing goes through the same channel, so the subscriber
+/// table is owned by the fan-out thread, nothing on the write path can deadlock with it, and
+/// a subscription sees exactly the updates published after it was made.
+pub struct Subscriptions {
+    /// insertion firehose and subscription changes, drained by the fan-out thread
+    firehose: mpsc::UnboundedSender<Message>,
+    /// next subscription id
+    next_id: AtomicUsize,
+    /// capacity of every subscriber queue, 0 is unbounded
+    queue_capacity: usize,
+    queue_policy: QueuePolicy,
+    pub stats: Arc<QueueStats>,
+    /// subscription id -> pattern, for DESCRIBE and to follow renamed stores
+    patterns: Mutex<HashMap<usize, String>>,
+}
 
-        let thread = thread::spawn(move || loop {
-            let push_tx = push_tx.clone();
-            let message = i_rx.lock().unwrap().recv().unwrap();
+impl ::std::fmt::Debug for Subscriptions {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "Subscriptions {{ next_id: {:?}, queue_capacity: {}, queue_policy: {}, stats: {:?} }}",
+            self.next_id,
+            self.queue_capacity,
+            self.queue_policy,
+            self.stats
+        )
+    }
+}
 
-            match message {
-                Message::Msg(up) => {
-                    let (ref symbol, ref up) = *up.lock().unwrap();
-                    if symbol == &filter {
-                        let _ = push_tx.unbounded_send(*up);
+impl Subscriptions {
+    pub fn new(queue_capacity: usize, queue_policy: QueuePolicy) -> Subscriptions {
+        let (firehose, rx) = mpsc::unbounded::<Message>();
+
+        // exits once the firehose sender is dropped along with `Subscriptions`
+        thread::Builder::new()
+            .name("tectonic-fanout".to_owned())
+            .spawn(move || {
+                let mut table: SubscriberTable = HashMap::new();
+                // store name -> the patterns of `table` it matches, cleared whenever a pattern
+                // is added or removed
+                let mut matches: HashMap<String, Vec<String>> = HashMap::new();
+                for message in rx.wait().filter_map(Result::ok) {
+                    match message {
+                        Message::Msg((symbol, up)) => {
+                            if !matches.contains_key(&symbol) {
+                                let patterns = table
+                                    .keys()
+                                    .filter(|pattern| glob_match(pattern, &symbol))
+                                    .cloned()
+                                    .collect();
+                                matches.insert(symbol.clone(), patterns);
+                            }
+                            // a connection subscribed by name and by a pattern gets it once
+                            let mut sent: Vec<&PushTX> = Vec::new();
+                            let sub_vs = matches[&symbol].iter().flat_map(|pattern| table[pattern].iter());
+                            for &(_, ref filter, ref push_tx) in sub_vs {
+                                if !filter.matches(&up) || sent.iter().any(|tx| tx.same_queue(push_tx)) {
+                                    continue;
+                                }
+                                // the receiver is gone if the client disconnected; it is removed on unsub
+                                let _ = push_tx.send((symbol.clone(), up));
+                                sent.push(push_tx);
+                            }
+                        }
+                        Message::Sub(pattern, id, filter, push_tx) => {
+                            if !table.contains_key(&pattern) {
+                                matches.clear();
+                            }
+                            table.entry(pattern).or_insert_with(Vec::new).push((id, filter, push_tx));
+                        }
+                        Message::Unsub(pattern, id) => {
+                            let is_empty = match table.get_mut(&pattern) {
+                                Some(sub_v) => {
+                                    sub_v.retain(|&(sub_id, _, _)| sub_id != id);
+                                    sub_v.is_empty()
+                                }
+                                None => false,
+                            };
+                            if is_empty {
+                                table.remove(&pattern);
+                                matches.clear();
+                            }
+                        }
+                        Message::Rename(from, to) => {
+                            if let Some(sub_v) = table.remove(&from) {
+                                table.entry(to).or_insert_with(Vec::new).extend(sub_v);
+                            }
+                            matches.clear();
+                        }
+                        Message::Remove(name) => {
+                            table.remove(&name);
+                            matches.clear();
+                        }
                     }
                 }
-                Message::Terminate => {
-                    break;
-                }
+            })
+            .expect("Unable to spawn subscription fan-out thread");
+
+        Subscriptions {
+            firehose,
+            next_id: AtomicUsize::new(1),
+            queue_capacity,
+            queue_policy,
+            stats: Arc::new(QueueStats::default()),
+            patterns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates the subscriber queue of a client connection.
+    pub fn queue(&self) -> (PushTX, PushRX) {
+        queue(self.queue_capacity, self.queue_policy, self.stats.clone())
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    pub fn queue_policy(&self) -> QueuePolicy {
+        self.queue_policy
+    }
+
+    fn send(&self, message: Message) {
+        if self.firehose.unbounded_send(message).is_err() {
+            error!("Mux message failed!");
+        }
+    }
+
+    /// Registers `push_tx` for updates inserted into stores matching `pattern` that pass
+    /// `filter`. It takes effect for every update published after this call. Returns the
+    /// subscription id.
+    pub fn sub(&self, pattern: String, filter: Filter, push_tx: PushTX) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.patterns.lock().unwrap().insert(id, pattern.clone());
+        self.send(Message::Sub(pattern, id, filter, push_tx));
+        id
+    }
+
+    /// Ends subscription `id`, wherever its store was renamed to. Does nothing if it's gone.
+    pub fn unsub(&self, id: usize) {
+        if let Some(pattern) = self.patterns.lock().unwrap().remove(&id) {
+            self.send(Message::Unsub(pattern, id));
+        }
+    }
+
+    /// What subscription `id` is to now, None once its store was dropped.
+    pub fn pattern_of(&self, id: usize) -> Option<String> {
+        self.patterns.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Moves the subscriptions to store `from` over to `to`, subscriptions to patterns are
+    /// left alone. The caller must keep inserts out of both stores meanwhile.
+    pub fn rename(&self, from: &str, to: &str) {
+        for pattern in self.patterns.lock().unwrap().values_mut() {
+            if pattern == from {
+                *pattern = to.to_owned();
             }
-        });
+        }
+        self.send(Message::Rename(from.to_owned(), to.to_owned()));
+    }
+
+    /// Ends the subscriptions to store `name`, when it's dropped.
+    pub fn remove(&self, name: &str) {
+        self.patterns.lock().unwrap().retain(|_, pattern| pattern != name);
+        self.send(Message::Remove(name.to_owned()));
+    }
 
-        Subscription { thread: Some(thread) }
+    /// Number of subscriptions whose pattern matches `dbname`.
+    pub fn count_for(&self, dbname: &str) -> usize {
+        self.patterns
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|pattern| glob_match(pattern, dbname))
+            .count()
+    }
+
+    /// Hands an inserted update to the fan-out thread. Never blocks.
+    pub fn msg(&self, f: Event) {
+        self.send(Message::Msg(f));
     }
 }
 
@@ -215,20 +585,183 @@ mod tests {
             size: 0.,
         };
         let symbol = "bt_eth_btc".to_owned();
-        let event = Arc::new(Mutex::new((symbol.clone(), up)));
 
-        let mut subs = Subscriptions::new();
-        let (subscription_tx, subscription_rx) = futures::sync::mpsc::unbounded::<Update>();
-        let (_id, _) = subs.sub(symbol.clone(), subscription_tx);
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (subscription_tx, subscription_rx) = subs.queue();
+        let _id = subs.sub(symbol.clone(), Filter::default(), subscription_tx);
 
-        subs.msg(event);
+        subs.msg(("bt_other_btc".to_owned(), Update { ts: 1, ..up }));
+        subs.msg((symbol, up));
 
         let task = subscription_rx.take(1).collect().map(|x| {
-            assert_eq!(up, x[0]);
+            assert_eq!(("bt_eth_btc".to_owned(), up), x[0]);
         });
 
         let mut core = Core::new().unwrap();
         core.run(task).unwrap();
 
     }
+
+    #[test]
+    fn should_stop_pushing_after_unsub() {
+        let up = Update { ts: 0, seq: 0, is_bid: false, is_trade: false, price: 0., size: 0. };
+        let symbol = "bt_eth_btc".to_owned();
+
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (tx_a, rx_a) = subs.queue();
+        let (tx_b, rx_b) = subs.queue();
+        let id_a = subs.sub(symbol.clone(), Filter::default(), tx_a);
+        let _id_b = subs.sub(symbol.clone(), Filter::default(), tx_b);
+
+        subs.unsub(id_a);
+        subs.msg((symbol.clone(), up));
+        drop(subs);
+
+        // both channels close once the fan-out thread exits
+        assert_eq!(0, rx_a.wait().count());
+        assert_eq!(1, rx_b.wait().count());
+    }
+
+    #[test]
+    fn should_only_push_matching_updates() {
+        let up = Update { ts: 0, seq: 0, is_bid: false, is_trade: false, price: 1., size: 1. };
+        let symbol = "bt_eth_btc".to_owned();
+
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (tx, rx) = subs.queue();
+        let filter = Filter(vec![Cond::IsTrade(true), Cond::Price(Cmp::Ge, 2.)]);
+        subs.sub(symbol.clone(), filter, tx);
+
+        subs.msg((symbol.clone(), up));
+        subs.msg((symbol.clone(), Update { is_trade: true, ..up }));
+        subs.msg((symbol.clone(), Update { price: 2., ..up }));
+        subs.msg((symbol.clone(), Update { ts: 1, is_trade: true, price: 2., ..up }));
+        drop(subs);
+
+        let received: Vec<_> = rx.wait().map(|ev| ev.unwrap().1.ts).collect();
+        assert_eq!(vec![1], received);
+    }
+
+    #[test]
+    fn should_match_glob() {
+        assert!(glob_match("bnc_*_btc", "bnc_eth_btc"));
+        assert!(glob_match("bnc_*_btc", "bnc__btc"));
+        assert!(glob_match("*", "bnc_eth_btc"));
+        assert!(glob_match("bnc_et?_btc", "bnc_eth_btc"));
+        assert!(glob_match("bnc_eth_btc", "bnc_eth_btc"));
+        assert!(!glob_match("bnc_*_btc", "bnc_eth_usdt"));
+        assert!(!glob_match("bnc_eth_btc", "bnc_eth_btc2"));
+        assert!(!glob_match("bnc_?_btc", "bnc_eth_btc"));
+    }
+
+    #[test]
+    fn should_push_tagged_updates_for_patterns() {
+        let up = Update { ts: 0, seq: 0, is_bid: false, is_trade: false, price: 1., size: 1. };
+
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (tx, rx) = subs.queue();
+        subs.sub("bnc_*_btc".to_owned(), Filter::default(), tx.clone());
+        subs.sub("gdx_eth_usd".to_owned(), Filter::default(), tx);
+
+        subs.msg(("bnc_eth_btc".to_owned(), up));
+        subs.msg(("bnc_eth_usdt".to_owned(), up));
+        subs.msg(("gdx_eth_usd".to_owned(), up));
+        subs.msg(("bnc_xrp_btc".to_owned(), up));
+        drop(subs);
+
+        let received: Vec<_> = rx.wait().map(|ev| ev.unwrap().0).collect();
+        assert_eq!(vec!["bnc_eth_btc", "gdx_eth_usd", "bnc_xrp_btc"], received);
+    }
+
+    #[test]
+    fn should_push_once_to_overlapping_subscriptions() {
+        let up = Update { ts: 0, seq: 0, is_bid: false, is_trade: false, price: 1., size: 1. };
+
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (tx_a, rx_a) = subs.queue();
+        let (tx_b, rx_b) = subs.queue();
+        subs.sub("bnc_eth_btc".to_owned(), Filter::default(), tx_a.clone());
+        subs.sub("bnc_*_btc".to_owned(), Filter::default(), tx_a.clone());
+        subs.sub("*".to_owned(), Filter::default(), tx_a);
+        subs.sub("bnc_*_btc".to_owned(), Filter::default(), tx_b);
+
+        subs.msg(("bnc_eth_btc".to_owned(), up));
+        // a new pattern is matched against stores seen before it
+        let (tx_c, rx_c) = subs.queue();
+        subs.sub("bnc_eth_*".to_owned(), Filter::default(), tx_c);
+        subs.msg(("bnc_eth_btc".to_owned(), Update { ts: 1, ..up }));
+        subs.msg(("gdx_eth_usd".to_owned(), Update { ts: 2, ..up }));
+        drop(subs);
+
+        let received = |rx: PushRX| rx.wait().map(|ev| ev.unwrap().1.ts).collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2], received(rx_a));
+        assert_eq!(vec![0, 1], received(rx_b));
+        assert_eq!(vec![1], received(rx_c));
+    }
+
+    fn ev(price: f32, size: f32) -> Event {
+        let up = Update { ts: 0, seq: 0, is_bid: true, is_trade: false, price, size };
+        ("bt_eth_btc".to_owned(), up)
+    }
+
+    #[test]
+    fn should_drop_oldest_when_full() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, rx) = queue(2, QueuePolicy::DropOldest, stats.clone());
+        for price in 1..4 {
+            tx.send(ev(price as f32, 1.)).unwrap();
+        }
+        drop(tx);
+
+        let received: Vec<_> = rx.wait().map(|ev| ev.unwrap().1.price).collect();
+        assert_eq!(vec![2., 3.], received);
+        assert_eq!(1, stats.dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn should_disconnect_when_full() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, rx) = queue(2, QueuePolicy::Disconnect, stats.clone());
+        assert!(tx.send(ev(1., 1.)).is_ok());
+        assert!(tx.send(ev(2., 1.)).is_ok());
+        assert!(tx.send(ev(3., 1.)).is_err());
+        assert!(tx.send(ev(4., 1.)).is_err());
+
+        assert_eq!(Some(Err(())), rx.wait().next());
+        assert_eq!(1, stats.disconnected.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn should_conflate_price_levels() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, rx) = queue(2, QueuePolicy::Conflate, stats.clone());
+        tx.send(ev(1., 1.)).unwrap();
+        tx.send(ev(2., 1.)).unwrap();
+        tx.send(ev(1., 5.)).unwrap();
+        // a new level still evicts the oldest
+        tx.send(ev(3., 1.)).unwrap();
+        tx.send(ev(3., 2.)).unwrap();
+        // a trade at the level is queued on its own
+        let (dbname, up) = ev(3., 7.);
+        tx.send((dbname, Update { is_trade: true, ..up })).unwrap();
+        drop(tx);
+
+        let received: Vec<_> = rx.wait().map(|ev| ev.unwrap().1).map(|up| (up.price, up.size)).collect();
+        assert_eq!(vec![(3., 2.), (3., 7.)], received);
+        assert_eq!(4, stats.dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn should_count_subscribers_per_store() {
+        let subs = Subscriptions::new(0, QueuePolicy::DropOldest);
+        let (tx, _rx) = subs.queue();
+        let id = subs.sub("bnc_*_btc".to_owned(), Filter::default(), tx.clone());
+        subs.sub("bnc_eth_btc".to_owned(), Filter::default(), tx);
+        assert_eq!(2, subs.count_for("bnc_eth_btc"));
+        assert_eq!(1, subs.count_for("bnc_xrp_btc"));
+
+        subs.unsub(id);
+        assert_eq!(1, subs.count_for("bnc_eth_btc"));
+        assert_eq!(0, subs.count_for("bnc_xrp_btc"));
+    }
 }
@@ -0,0 +1,117 @@
+//! TLS termination for the listener.
+//!
+//! The certificate and private key are read from PEM files and bundled into
+//! an in-memory PKCS#12 archive, which is what `native_tls` expects.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+use native_tls::{self, Identity};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use tokio_tls::TlsAcceptor;
+
+use settings::Settings;
+
+fn read_file(path: &str) -> Result<Vec<u8>, Box<Error>> {
+    let mut buf = vec![];
+    File::open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Builds an acceptor from a PEM certificate (chain) and a PEM private key.
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, Box<Error>> {
+    let mut certs = X509::stack_from_pem(&read_file(cert_path)?)?.into_iter();
+    let cert = certs.next()
+        .ok_or_else(|| format!("No certificate found in {}", cert_path))?;
+    let pkey = PKey::private_key_from_pem(&read_file(key_path)?)?;
+
+    let mut builder = Pkcs12::builder();
+    let mut chain = Stack::new()?;
+    for ca in certs {
+        chain.push(ca)?;
+    }
+    builder.ca(chain);
+    let pkcs12 = builder.build("", "tectonicdb", &pkey, &cert)?;
+
+    let identity = Identity::from_pkcs12(&pkcs12.to_der()?, "")?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+/// Returns an acceptor if both `tls_cert` and `tls_key` are configured.
+///
+/// Panics if the files cannot be loaded, since silently falling back to
+/// plaintext would be worse than refusing to start.
+pub fn acceptor_from_settings(settings: &Settings) -> Option<TlsAcceptor> {
+    match (&settings.tls_cert, &settings.tls_key) {
+        (&Some(ref cert), &Some(ref key)) => {
+            match load_acceptor(cert, key) {
+                Ok(acceptor) => Some(acceptor),
+                Err(err) => panic!("Unable to load TLS certificate {} / key {}: {}", cert, key, err),
+            }
+        }
+        (&None, &None) => None,
+        _ => panic!("Both tls_cert and tls_key must be set to enable TLS."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, thread, time};
+    use std::io::Write;
+    use std::net::TcpStream;
+    use libtectonic::client::{Cxn, TlsConfig};
+    use server;
+
+    #[test]
+    fn should_load_self_signed_cert() {
+        assert!(load_acceptor("test/tls/cert.pem", "test/tls/key.pem").is_ok());
+    }
+
+    #[test]
+    fn should_fail_on_missing_key() {
+        assert!(load_acceptor("test/tls/cert.pem", "test/tls/missing.pem").is_err());
+    }
+
+    #[test]
+    fn should_serve_over_tls() {
+        let settings = Settings {
+            dtf_folder: env::temp_dir().join("tectonic-tls-test").to_str().unwrap().to_owned(),
+            flush_interval: 1000,
+            hist_granularity: 30,
+            hist_q_capacity: 300,
+            tls_cert: Some("test/tls/cert.pem".to_owned()),
+            tls_key: Some("test/tls/key.pem".to_owned()),
+            ..Default::default()
+        };
+        thread::spawn(move || server::run_server("127.0.0.1", "19443", &settings));
+
+        let tls = TlsConfig { ca_file: Some("test/tls/cert.pem".to_owned()) };
+        let mut cxn = None;
+        for _ in 0..50 {
+            if let Ok(c) = Cxn::new_tls("localhost", "19443", &tls) {
+                cxn = Some(c);
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        let mut cxn = cxn.expect("Unable to connect over TLS");
+        assert_eq!("PONG", cxn.cmd("PING\n").unwrap());
+
+        // a client that doesn't trust the certificate fails the handshake
+        assert!(Cxn::new_tls("localhost", "19443", &TlsConfig::default()).is_err());
+
+        // a plaintext client doesn't get a response from the TLS listener
+        let mut plain = TcpStream::connect("127.0.0.1:19443").unwrap();
+        plain.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        plain.write_all(b"PING\n").unwrap();
+        let mut resp = vec![];
+        let _ = plain.read_to_end(&mut resp);
+        assert!(!String::from_utf8_lossy(&resp).contains("PONG"));
+    }
+}
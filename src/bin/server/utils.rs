@@ -1,7 +1,6 @@
 use std::path::Path;
 use std::fs;
 use state::{Store, ThreadState};
-use libtectonic::dtf;
 
 pub fn create_dir_if_not_exist(dtf_folder: &str) {
     if !Path::new(dtf_folder).exists() {
@@ -9,55 +8,57 @@ pub fn create_dir_if_not_exist(dtf_folder: &str) {
     }
 }
 
-/// Iterate through the dtf files in the folder and load some metadata into memory.
+/// Load the metadata of the dtf files in the catalog into memory.
 /// Create corresponding Store objects in State.
+///
+/// Run once at startup, before any client connects: the on-disk count of a store is the
+/// sum of the counts of its files.
 pub fn init_dbs(state: &mut ThreadState) {
-    let dtf_folder = {
-        let rdr = state.global.read().unwrap();
-        rdr.settings.dtf_folder.clone()
-    };
-    for dtf_file in fs::read_dir(&dtf_folder).unwrap() {
-        let fname_os = dtf_file.unwrap().file_name();
-        let stem = fname_os.to_str().unwrap(); // sldjf-lks-djflk-sfsd--something.dtf
-        if stem.ends_with(".dtf") {
-            let basename = Path::new(&fname_os).file_stem().unwrap().to_str().unwrap(); // sldjf-lks-djflk-sfsd--something
-            let full_path = &format!("{}/{}", dtf_folder, stem);
-            let header_size = match dtf::get_size(full_path) {
-                Ok(size) => size,
-                Err(err) => {
-                    warn!("Error while retrieving size of DTF file {}: {:?}", full_path, err);
-                    continue;
-                }
-            };
-            let symbol = match dtf::read_meta(full_path) {
-                Ok(meta) => meta.symbol,
-                Err(err) => {
-                    warn!("Error parsing metadata for DTF file {}: {:?}", full_path, err);
-                    continue;
-                }
-            };
+    let catalog = state.global.read().unwrap().catalog.clone();
+    for symbol in catalog.symbols() {
+        let segments = catalog.segments(&symbol);
+        {
+            let vecs = state.global.write().unwrap().get_or_create_vec_store(&symbol);
+            let mut vecs = vecs.write().unwrap();
+            vecs.count = segments.iter().map(|seg| seg.count).sum::<u64>() + vecs.ups.len() as u64;
+            vecs.segments = segments;
+        }
 
-            {
-                let mut wtr = state.global.write().unwrap();
-                // if symbol is in vec_store, append to store
-                // TODO: this is not accurate at all!
-                // XXX: need to keep track of file names :(
-                wtr.vec_store
-                    .entry(symbol.clone())
-                    .and_modify(|e| if e.1 < header_size {e.1 += header_size})
-                    .or_insert((box Vec::new(), header_size));
-            }
+        // insert a db store into user state, new segments are named after the symbol
+        let store = Store::new(&symbol, &symbol, false, &state.global);
+        state.store.write().unwrap().insert(symbol, store);
+    }
+}
 
-            // insert a db store into user state
-            state.store.write().unwrap().insert(
-                symbol.to_owned(),
-                Store {
-                    name: symbol.into(),
-                    fname: basename.to_owned().into(),
-                    in_memory: false,
-                    global: state.global.clone(),
-                },
-            );
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use libtectonic::dtf::{self, Update};
+    use catalog::Catalog;
+    use state::test_utils::test_state;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: 0, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_count_every_file_once() {
+        let (folder, global, store, mut state) = test_state("tectonic-init-test", Default::default());
+        dtf::encode(&format!("{}/bnc_eth_btc-2018010100.dtf", folder), "bnc_eth_btc", &[up(1), up(2)]).unwrap();
+        dtf::encode(&format!("{}/bnc_eth_btc-2018010101.dtf", folder), "bnc_eth_btc", &[up(3)]).unwrap();
+        // catalogued as at startup
+        global.write().unwrap().catalog = Arc::new(Catalog::open(&folder));
+        init_dbs(&mut state);
+        assert_eq!(3, store.read().unwrap()["bnc_eth_btc"].count());
+
+        // connecting clients share the registry as is
+        state.use_db("bnc_eth_btc").unwrap();
+        let mut client = ThreadState::new(global.clone(), store.clone(), state.subscription_tx.clone());
+        assert!(store.read().unwrap()["bnc_eth_btc"].in_memory.load(Ordering::SeqCst));
+        client.use_db("bnc_eth_btc").unwrap();
+        assert_eq!(3, client.count());
+        assert_eq!(3, client.count_in_mem());
     }
 }
@@ -1,9 +1,12 @@
 use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::str;
 
+use native_tls::{Certificate, TlsConnector, TlsStream};
+
 use byteorder::{BigEndian, ReadBytesExt};
-use dtf;
+use dtf::{self, Update};
 use dtf::file_format::{read_one_batch, UpdateVecInto};
 
 use client::insert_command::InsertCommand;
@@ -16,13 +19,16 @@ struct CxnStream {
 impl CxnStream {
     fn cmd(&mut self, command: &str) -> Result<String, TectonicError> {
         let _ = self.stream.write(command.as_bytes());
+        if is_stream(command) {
+            return collect_chunks(&mut self.stream, command);
+        }
 
         let success = match self.stream.read_u8() {
             Ok(re) => re == 0x1,
             Err(_) => return Err(TectonicError::ConnectionError),
         };
 
-        if command.starts_with("GET")
+        if (command.starts_with("GET") || command.starts_with("FETCH"))
             && !command.contains("AS CSV")
             && !command.contains("AS JSON")
             && success
@@ -57,12 +63,80 @@ impl CxnStream {
 }
 
 
+/// TLS options used when the server terminates TLS itself.
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// PEM file with an extra trusted root, e.g. a self-signed server certificate
+    pub ca_file: Option<String>,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn tls_connector(tls: &TlsConfig) -> Result<TlsConnector, TectonicError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ref ca_file) = tls.ca_file {
+        let mut pem = vec![];
+        let cert = File::open(ca_file)
+            .and_then(|mut f| f.read_to_end(&mut pem))
+            .ok()
+            .and_then(|_| Certificate::from_pem(&pem).ok());
+        match cert {
+            Some(cert) => { builder.add_root_certificate(cert); }
+            None => {
+                error!("Unable to load CA certificate {}", ca_file);
+                return Err(TectonicError::ConnectionError);
+            }
+        }
+    }
+    builder.build().map_err(|err| {
+        error!("Unable to build TLS connector: {:?}", err);
+        TectonicError::ConnectionError
+    })
+}
+
 pub struct Cxn {
-    stream: TcpStream,
+    stream: Stream,
 }
 
 impl Cxn {
     pub fn new(host: &str, port: &str) -> Result<Cxn, TectonicError> {
+        Cxn::connect(host, port, None)
+    }
+
+    /// Connects to a server with TLS enabled. `host` is also used to verify the certificate.
+    pub fn new_tls(host: &str, port: &str, tls: &TlsConfig) -> Result<Cxn, TectonicError> {
+        Cxn::connect(host, port, Some(tls))
+    }
+
+    pub fn connect(host: &str, port: &str, tls: Option<&TlsConfig>) -> Result<Cxn, TectonicError> {
         let addr = format!("{}:{}", host, port);
 
         info!("Connecting to {}", addr);
@@ -72,6 +146,20 @@ impl Cxn {
             Err(_) => return Err(TectonicError::ConnectionError)
         };
 
+        let stream = match tls {
+            Some(tls) => {
+                let connector = tls_connector(tls)?;
+                match connector.connect(host, stream) {
+                    Ok(stm) => Stream::Tls(stm),
+                    Err(err) => {
+                        error!("TLS handshake with {} failed: {:?}", addr, err);
+                        return Err(TectonicError::ConnectionError);
+                    }
+                }
+            }
+            None => Stream::Plain(stream),
+        };
+
         Ok(Cxn {
             stream,
         })
@@ -88,12 +176,15 @@ impl Cxn {
 
     pub fn cmd(&mut self, command : &str) -> Result<String, TectonicError> {
         let _ = self.stream.write(command.as_bytes());
+        if is_stream(command) {
+            return collect_chunks(&mut self.stream, command);
+        }
         let success = match self.stream.read_u8() {
             Ok(re) => re == 0x1,
             Err(_) => return Err(TectonicError::ConnectionError),
         };
 
-        if command.starts_with("GET")
+        if (command.starts_with("GET") || command.starts_with("FETCH"))
             && !command.contains("AS CSV")
             && !command.contains("AS JSON")
             && success
@@ -116,6 +207,17 @@ impl Cxn {
         }
     }
 
+    /// Sends a `GET` in dtf format with `STREAM` appended, such as
+    /// `GET ALL FROM 1514764800 TO 1517443200`, and returns the updates as the server sends
+    /// them, a chunk at a time.
+    pub fn get_stream<'a>(&'a mut self, query: &str) -> impl Iterator<Item = Result<Vec<Update>, TectonicError>> + 'a {
+        let _ = self.stream.write(format!("{} STREAM\n", query.trim()).as_bytes());
+        Chunks::new(&mut self.stream).map(|chunk| chunk.map(|bytes| {
+            let mut buf = bytes.as_slice();
+            dtf::decode_buffer(&mut buf)
+        }))
+    }
+
     #[allow(dead_code)]
     pub fn insert(&mut self, cmd: InsertCommand) -> Result<(), TectonicError> {
         for cmd in &cmd.into_string() {
@@ -124,3 +226,108 @@ impl Cxn {
         Ok(())
     }
 }
+
+fn is_stream(command: &str) -> bool {
+    command.starts_with("GET") && command.trim().ends_with(" STREAM")
+}
+
+/// The payloads of a streamed `GET`, until the empty frame ending it.
+///
+/// Every frame is `[status u8][len u64][payload]`. An error frame ends the stream too.
+struct Chunks<'a> {
+    rdr: &'a mut Read,
+    done: bool,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(rdr: &'a mut Read) -> Chunks<'a> {
+        Chunks { rdr, done: false }
+    }
+
+    fn read_frame(&mut self) -> io::Result<(bool, Vec<u8>)> {
+        let success = self.rdr.read_u8()? == 0x1;
+        let size = self.rdr.read_u64::<BigEndian>()?;
+        let mut buf = vec![0; size as usize];
+        self.rdr.read_exact(&mut buf)?;
+        Ok((success, buf))
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Vec<u8>, TectonicError>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, TectonicError>> {
+        if self.done {
+            return None;
+        }
+        match self.read_frame() {
+            Ok((true, ref buf)) if buf.is_empty() => {
+                self.done = true;
+                None
+            }
+            Ok((true, buf)) => Some(Ok(buf)),
+            Ok((false, buf)) => {
+                self.done = true;
+                Some(Err(TectonicError::ServerError(String::from_utf8_lossy(&buf).into_owned())))
+            }
+            Err(_) => {
+                self.done = true;
+                Some(Err(TectonicError::ConnectionError))
+            }
+        }
+    }
+}
+
+/// Reads a streamed `GET` whole, the way `cmd` returns any other response: dtf chunks are
+/// decoded into one JSON array, JSON and CSV chunks are concatenated.
+fn collect_chunks(rdr: &mut Read, command: &str) -> Result<String, TectonicError> {
+    let is_dtf = !command.contains("AS CSV") && !command.contains("AS JSON");
+    let mut ups: Vec<Update> = vec![];
+    let mut res = String::new();
+    for chunk in Chunks::new(rdr) {
+        let chunk = chunk?;
+        if is_dtf {
+            let mut buf = chunk.as_slice();
+            ups.extend(dtf::decode_buffer(&mut buf));
+        } else {
+            res.push_str(&String::from_utf8_lossy(&chunk));
+        }
+    }
+    if is_dtf {
+        Ok(format!("[{}]\n", ups.into_json()))
+    } else {
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn frame(status: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![status];
+        buf.write_u64::<BigEndian>(payload.len() as u64).unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn should_read_chunks_until_empty_frame() {
+        let mut bytes = frame(0x1, b"[1]\n");
+        bytes.extend(frame(0x1, b"[2]\n"));
+        bytes.extend(frame(0x1, b""));
+        bytes.extend(frame(0x1, b"PONG"));
+        let mut rdr = io::Cursor::new(bytes);
+        assert_eq!("[1]\n[2]\n", collect_chunks(&mut rdr, "GET ALL AS JSON STREAM").unwrap());
+        // the next response is left alone
+        assert_eq!(3 * 9 + 8, rdr.position());
+
+        let mut bytes = frame(0x1, b"[1]\n");
+        bytes.extend(frame(0x0, b"ERR: Unable to read db/x.dtf\n"));
+        let mut rdr = io::Cursor::new(bytes);
+        let chunks: Vec<_> = Chunks::new(&mut rdr).collect();
+        assert_eq!(2, chunks.len());
+        assert!(chunks[1].is_err());
+    }
+}
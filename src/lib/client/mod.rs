@@ -9,7 +9,7 @@ mod cxn;
 mod pool;
 
 pub use self::error::TectonicError;
-pub use self::cxn::Cxn;
+pub use self::cxn::{Cxn, TlsConfig};
 pub use self::pool::CxnPool;
 pub use self::insert_command::InsertCommand;
 
@@ -20,20 +20,26 @@ fn key_or_default(key: &str, default: &str) -> String {
     }
 }
 
-fn get_tectonic_conf_from_env() -> (String, String, usize) {
+fn get_tectonic_conf_from_env() -> (String, String, usize, Option<TlsConfig>) {
     let tectonic_hostname: String = key_or_default("TECTONICDB_HOSTNAME", "localhost");
     let tectonic_port: String     = key_or_default("TECTONICDB_PORT", "9001");
     let q_capacity: usize         = key_or_default("QUEUE_CAPACITY", "70000000")
                                     .parse().unwrap(); // 70mm
-
-    (tectonic_hostname, tectonic_port, q_capacity)
+    let tls = match key_or_default("TECTONICDB_TLS", "false").as_ref() {
+        "true" | "1" => Some(TlsConfig {
+            ca_file: env::var("TECTONICDB_TLS_CA_FILE").ok(),
+        }),
+        _ => None,
+    };
+
+    (tectonic_hostname, tectonic_port, q_capacity, tls)
 }
 
 /// Creates a new connection to TectonicDB, using configuration values from environment values
 /// or defaults to localhost:9001 if none are set.
 pub fn get_cxn() -> Cxn {
-    let (tectonic_hostname, tectonic_port, _capacity) = get_tectonic_conf_from_env();
-    match Cxn::new(&tectonic_hostname, &tectonic_port) {
+    let (tectonic_hostname, tectonic_port, _capacity, tls) = get_tectonic_conf_from_env();
+    match Cxn::connect(&tectonic_hostname, &tectonic_port, tls.as_ref()) {
         Ok(cxn) => cxn,
         Err(TectonicError::ConnectionError) => {
             panic!("DB cannot be connected!");
@@ -43,9 +49,9 @@ pub fn get_cxn() -> Cxn {
 }
 
 pub fn get_cxn_pool() -> CxnPool {
-    let (tectonic_hostname, tectonic_port, capacity) = get_tectonic_conf_from_env();
+    let (tectonic_hostname, tectonic_port, capacity, tls) = get_tectonic_conf_from_env();
 
-    match CxnPool::new(1, &tectonic_hostname, &tectonic_port, capacity) {
+    match CxnPool::connect(1, &tectonic_hostname, &tectonic_port, capacity, tls) {
         Ok(pool) => pool,
         Err(TectonicError::ConnectionError) => {
             panic!("Connection Pool cannot be established!");
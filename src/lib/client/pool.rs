@@ -2,7 +2,7 @@ use std::{thread, time};
 use std::collections::VecDeque;
 
 use client::circular_queue::CircularQueue;
-use super::{Cxn, InsertCommand, TectonicError};
+use super::{Cxn, InsertCommand, TectonicError, TlsConfig};
 
 pub type InsertQueue = CircularQueue<InsertCommand>;
 
@@ -10,19 +10,33 @@ pub struct CxnPool {
     cxns: Vec<Cxn>,
     host: String,
     port: String,
+    tls: Option<TlsConfig>,
     available_workers: VecDeque<usize>,
     queue: InsertQueue,
 }
 
 impl CxnPool {
     pub fn new(n_workers: usize, host: &str, port: &str, capacity: usize) -> Result<Self, TectonicError> {
+        CxnPool::connect(n_workers, host, port, capacity, None)
+    }
+
+    /// Creates a pool whose connections (including replacements) all use TLS.
+    pub fn new_tls(n_workers: usize, host: &str, port: &str, capacity: usize, tls: TlsConfig)
+        -> Result<Self, TectonicError>
+    {
+        CxnPool::connect(n_workers, host, port, capacity, Some(tls))
+    }
+
+    pub fn connect(n_workers: usize, host: &str, port: &str, capacity: usize, tls: Option<TlsConfig>)
+        -> Result<Self, TectonicError>
+    {
         let mut cxns = vec![];
         let mut workers = VecDeque::new();
 
         let queue = CircularQueue::with_capacity(capacity);
 
         for i in 0..n_workers {
-            let cxn = Cxn::new(host, port)?;
+            let cxn = Cxn::connect(host, port, tls.as_ref())?;
             cxns.push(cxn);
             workers.push_back(i);
         }
@@ -31,11 +45,16 @@ impl CxnPool {
             cxns,
             host: host.to_owned(),
             port: port.to_owned(),
+            tls,
             available_workers: workers,
             queue: queue,
         })
     }
 
+    fn new_cxn(&self) -> Result<Cxn, TectonicError> {
+        Cxn::connect(&self.host, &self.port, self.tls.as_ref())
+    }
+
     pub fn create_db(&mut self, dbname: &str) -> Result<String, TectonicError> {
         info!("Creating db {}", dbname);
         self.cmd(&format!("CREATE {}\n", dbname))
@@ -48,7 +67,8 @@ impl CxnPool {
             None => {
                 // grow avail cxns
                 warn!("Growing CxnPool to {}", self.cxns.len());
-                self.cxns.push(Cxn::new(&self.host, &self.port)?);
+                let cxn = self.new_cxn()?;
+                self.cxns.push(cxn);
                 self.cxns.len() - 1
             }
         };
@@ -59,7 +79,8 @@ impl CxnPool {
             Err(TectonicError::ConnectionError) => {
                 thread::sleep(time::Duration::from_secs(1));
                 // replace current cxn
-                self.cxns[n] = Cxn::new(&self.host, &self.port)?;
+                let cxn = self.new_cxn()?;
+                self.cxns[n] = cxn;
                 error!("REPLACING CXN");
                 result
             }
@@ -79,7 +100,8 @@ impl CxnPool {
             Some(n) => n,
             None => {
                 // grow avail cxns
-                self.cxns.push(Cxn::new(&self.host, &self.port)?);
+                let cxn = self.new_cxn()?;
+                self.cxns.push(cxn);
                 warn!("Growing CxnPool to {}", self.cxns.len());
                 self.cxns.len() - 1
             }
@@ -93,7 +115,8 @@ impl CxnPool {
                     {
                         self.queue.push(cmd.clone());
                     }
-                    self.cxns[n] = Cxn::new(&self.host, &self.port)?;
+                    let cxn = self.new_cxn()?;
+                    self.cxns[n] = cxn;
                     error!("Replacing cxn.");
                     self.available_workers.push_back(n);
                     return Err(TectonicError::ConnectionError);
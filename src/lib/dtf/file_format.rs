@@ -232,6 +232,29 @@ pub fn encode(fname: &str, symbol: &str, ups: &[Update]) -> Result<(), io::Error
     wtr.flush()
 }
 
+/// Replaces the content of `fname` with `ups`. The file is encoded next to the
+/// original and renamed over it, so a failed write leaves the original intact and
+/// readers never see a half written file.
+pub fn rewrite(fname: &str, symbol: &str, ups: &[Update]) -> Result<(), io::Error> {
+    let tmp_fname = format!("{}.tmp", fname);
+    if let Err(err) = encode(&tmp_fname, symbol, ups) {
+        let _ = fs::remove_file(&tmp_fname);
+        return Err(err);
+    }
+    fs::rename(&tmp_fname, fname)
+}
+
+/// Replaces the symbol in the header of `fname`, leaving the updates as they are.
+pub fn set_symbol(fname: &str, symbol: &str) -> Result<(), io::Error> {
+    if !is_dtf(fname)? {
+        return Err(io::Error::new(InvalidData, "MAGIC VALUE INCORRECT"));
+    }
+    let mut wtr = file_writer(fname, false)?;
+    wtr.seek(SeekFrom::Start(SYMBOL_OFFSET))?;
+    write_symbol(&mut wtr, symbol)?;
+    wtr.flush()
+}
+
 pub fn is_dtf(fname: &str) -> Result<bool, io::Error> {
     let file = File::open(fname)?;
     let mut rdr = BufReader::new(file);
@@ -328,7 +351,7 @@ pub fn range<T: BufRead + Seek>(rdr: &mut T, min_ts: u64, max_ts: u64) -> Result
         let current_count = current_meta.count;
 
         // skip a few bytes and read the next metadata
-        let bytes_to_skip = current_count * 12 /* 12 bytes per row */;
+        let bytes_to_skip = u64::from(current_count) * 12 /* 12 bytes per row */;
         rdr.seek(SeekFrom::Current(bytes_to_skip as i64)).expect(
             &format!(
                 "Skipping {} rows",
@@ -336,17 +359,19 @@ pub fn range<T: BufRead + Seek>(rdr: &mut T, min_ts: u64, max_ts: u64) -> Result
             ),
         );
 
-        // must be a batch
-        match rdr.read_u8() {
-            Ok(byte) => {
-                if byte != 0x1 {
-                    return Ok(v);
-                }
-            }   // is a batch
-            Err(_e) => {
-                return Ok(v);
-            }                        // EOF
+        // must be a batch, otherwise the current one is the last
+        let marker_len = match rdr.read_u8() {
+            Ok(0x1) => None,
+            Ok(_) => Some(1),
+            Err(_e) => Some(0), // EOF
         };
+        if let Some(marker_len) = marker_len {
+            //   |1*------|          <- the last batch, filter it and stop
+            rdr.seek(SeekFrom::Current(- (bytes_to_skip as i64) - marker_len))?;
+            let batch = read_one_batch_main(rdr, current_meta)?;
+            v.extend(batch.into_iter().filter(|up| up.ts <= max_ts && up.ts >= min_ts));
+            return Ok(v);
+        }
         let next_meta = read_one_batch_meta(rdr);
         let next_ref_ts = next_meta.ref_ts;
 
@@ -495,12 +520,40 @@ pub struct DTFBufReader {
 
 impl DTFBufReader {
     pub fn new(fname: &str, batch_size: u32) -> Self {
-        let mut rdr = file_reader(fname).expect("Cannot open file");
-        rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-        DTFBufReader {
+        DTFBufReader::open(fname, batch_size).expect("Cannot open file")
+    }
+
+    /// Like `new`, but a missing file or one that isn't a dtf file is an error
+    pub fn open(fname: &str, batch_size: u32) -> Result<Self, io::Error> {
+        let mut rdr = BufReader::new(File::open(fname)?);
+        if !read_magic_value(&mut rdr)? {
+            return Err(io::Error::new(InvalidData, "MAGIC VALUE INCORRECT"));
+        }
+        rdr.seek(SeekFrom::Start(MAIN_OFFSET))?;
+        Ok(DTFBufReader {
             rdr,
             batch_size,
+        })
+    }
+
+    /// Skips the batches ending before `ts` by their headers alone, the next one read is the
+    /// last batch starting before `ts`, which may still hold some updates at `ts` or later.
+    pub fn seek_ts(&mut self, ts: u64) -> Result<(), io::Error> {
+        let mut pos = self.rdr.seek(SeekFrom::Current(0))?;
+        let mut start = pos;
+        while let Ok(0x1) = self.rdr.read_u8() {
+            let ref_ts = self.rdr.read_u64::<BigEndian>()?;
+            let _ref_seq = self.rdr.read_u32::<BigEndian>()?;
+            let count = self.rdr.read_u16::<BigEndian>()?;
+            if ref_ts >= ts {
+                break;
+            }
+            start = pos;
+            pos += 1 + 14 /* metadata */ + u64::from(count) * 12 /* 12 bytes per row */;
+            self.rdr.seek(SeekFrom::Start(pos))?;
         }
+        self.rdr.seek(SeekFrom::Start(start))?;
+        Ok(())
     }
 
     pub fn as_chunks(self, chunk_size: u64) -> ChunkedDTFBufReader {
@@ -856,7 +909,7 @@ mod tests {
 
         let mut rdr = file_reader(fname).unwrap();
         assert_eq!(
-            (1..999)
+            (1..1000)
                 .map(|i| {
                     Update {
                         ts: i * 1000 as u64,
@@ -869,7 +922,7 @@ mod tests {
                 })
                 .collect::<Vec<Update>>(),
             range(&mut rdr, 1000, 999000).unwrap()
-        ); // ???
+        );
     }
 
     #[test]
@@ -1046,4 +1099,54 @@ mod tests {
             bytes
         );
     }
+
+    #[test]
+    fn should_rewrite_file() {
+        let fname = "test_rewrite.dtf";
+        let ts = sample_data();
+        encode(fname, "NEO_BTC", &ts).unwrap();
+
+        let kept = &ts[..ts.len() / 2];
+        rewrite(fname, "NEO_BTC", kept).unwrap();
+        let meta = read_meta(fname).unwrap();
+        assert_eq!(kept.len() as u64, meta.nums);
+        assert_eq!(get_max_ts(kept), meta.max_ts);
+        assert_eq!(kept.to_vec(), decode(fname, None).unwrap());
+        assert!(!::std::path::Path::new("test_rewrite.dtf.tmp").exists());
+        fs::remove_file(fname).unwrap();
+    }
+
+    #[test]
+    fn should_set_symbol_in_place() {
+        let fname = "test_set_symbol.dtf";
+        let ts = sample_data();
+        encode(fname, "NEO_BTC", &ts).unwrap();
+
+        set_symbol(fname, "XRP_BTC").unwrap();
+        assert_eq!("XRP_BTC", read_meta(fname).unwrap().symbol);
+        assert_eq!(ts, decode(fname, None).unwrap());
+        assert!(set_symbol(fname, "A_SYMBOL_LONGER_THAN_20").is_err());
+        fs::remove_file(fname).unwrap();
+    }
+
+    #[test]
+    fn should_seek_buf_reader_to_ts() {
+        let fname = "test_seek_ts.dtf";
+        // a batch every 0xFFFF ms
+        let ups: Vec<Update> = (0..10)
+            .map(|i| Update { ts: i * 0x8000, seq: 0, is_trade: false, is_bid: true, price: 1., size: 1. })
+            .collect();
+        encode(fname, "NEO_BTC", &ups).unwrap();
+
+        let first = |ts| {
+            let mut rdr = DTFBufReader::open(fname, 1).unwrap();
+            rdr.seek_ts(ts).unwrap();
+            rdr.next().map(|batch| batch[0].ts)
+        };
+        assert_eq!(Some(0), first(0));
+        assert_eq!(Some(0), first(0x10000));
+        assert_eq!(Some(0x10000), first(0x10001));
+        assert_eq!(Some(0x40000), first(0x100000));
+        fs::remove_file(fname).unwrap();
+    }
 }
@@ -8,10 +8,13 @@ extern crate serde_derive;
 extern crate time;
 extern crate uuid;
 extern crate byteorder;
+extern crate native_tls;
 #[macro_use]
 extern crate bitflags;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 
 pub mod postprocessing;
 pub mod storage;
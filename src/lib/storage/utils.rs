@@ -1,3 +1,5 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::error::Error;
 use std::fs::{self, DirEntry};
 use std::io;
@@ -18,6 +20,10 @@ fn parse_dtf_entry(
             return Ok(None);
         }
     }.to_owned();
+    // skip the server's catalog manifest and other non-dtf files
+    if !fname.ends_with(".dtf") {
+        return Ok(None);
+    }
     let fname = format!("{}/{}", folder, fname);
     let meta = dtf::read_meta(&fname)?;
 
@@ -76,6 +82,81 @@ pub fn scan_files_for_range(
     Ok(ret)
 }
 
+/// read the updates within range from every file, merged in (ts, seq) order
+///
+/// With the `parallel` feature the files are decoded concurrently.
+pub fn read_files_for_range(fnames: &[String], min_ts: u64, max_ts: u64) -> Result<Vec<dtf::Update>, io::Error> {
+    #[cfg(feature = "parallel")]
+    let read = read_files_for_range_par;
+    #[cfg(not(feature = "parallel"))]
+    let read = read_files_for_range_seq;
+    read(fnames, min_ts, max_ts)
+}
+
+/// read the files one after another
+pub fn read_files_for_range_seq(fnames: &[String], min_ts: u64, max_ts: u64) -> Result<Vec<dtf::Update>, io::Error> {
+    let mut chunks = Vec::with_capacity(fnames.len());
+    for fname in fnames {
+        chunks.push(dtf::get_range_in_file(fname, min_ts, max_ts)?);
+    }
+    Ok(merge_by_ts(chunks))
+}
+
+/// read the files on the rayon thread pool
+#[cfg(feature = "parallel")]
+pub fn read_files_for_range_par(fnames: &[String], min_ts: u64, max_ts: u64) -> Result<Vec<dtf::Update>, io::Error> {
+    use rayon::prelude::*;
+
+    let chunks = fnames
+        .par_iter()
+        .map(|fname| dtf::get_range_in_file(fname, min_ts, max_ts))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    Ok(merge_by_ts(chunks))
+}
+
+/// k-way merges the updates of each file, which are sorted already, in (ts, seq) order
+///
+/// Updates with the same (ts, seq) keep their file order.
+fn merge_by_ts(chunks: Vec<Vec<dtf::Update>>) -> Vec<dtf::Update> {
+    let len = chunks.iter().map(|chunk| chunk.len()).sum();
+    let mut ret = Vec::with_capacity(len);
+    // (ts, seq, file, position) of the next update of every file
+    let mut heap: BinaryHeap<Reverse<(u64, u32, usize, usize)>> = chunks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, chunk)| chunk.first().map(|up| Reverse((up.ts, up.seq, i, 0))))
+        .collect();
+    while let Some(Reverse((_, _, i, pos))) = heap.pop() {
+        ret.push(chunks[i][pos]);
+        if let Some(up) = chunks[i].get(pos + 1) {
+            heap.push(Reverse((up.ts, up.seq, i, pos + 1)));
+        }
+    }
+    ret
+}
+
+/// every dtf file under folder holding `symbol`, sorted by min_ts
+pub fn scan_files_for_symbol(folder: &str, symbol: &str) -> Result<Vec<(String, Metadata)>, io::Error> {
+    let entries = fs::read_dir(folder).map_err(|e| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Unable to read dir entries: {:?}", e),
+    ))?;
+    let mut v = entries
+        .filter_map(|entry| {
+            match parse_dtf_entry(folder, entry) {
+                Ok(res) => res,
+                Err(err) => {
+                    error!("Error while processing DTF entry: {:?}", err);
+                    None
+                }
+            }
+        })
+        .filter(|&(ref _fname, ref meta)| meta.symbol == symbol)
+        .collect::<Vec<_>>();
+    v.sort_by(|&(ref _f0, ref m0), &(ref _f1, ref m1)| m0.cmp(m1));
+    Ok(v)
+}
+
 pub fn total_folder_updates_len(folder: &str) -> Result<usize, io::Error> {
     match fs::read_dir(folder) {
         Err(e) => {
@@ -98,3 +179,47 @@ pub fn total_folder_updates_len(folder: &str) -> Result<usize, io::Error> {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn up(ts: u64) -> dtf::Update {
+        dtf::Update { ts, seq: 0, is_trade: false, is_bid: true, price: 1., size: 1. }
+    }
+
+    #[test]
+    fn should_merge_by_ts_and_seq() {
+        let at = |ts, seq| dtf::Update { seq, ..up(ts) };
+        let merged = merge_by_ts(vec![
+            vec![at(1, 0), at(3, 2), at(4, 0)],
+            vec![at(2, 0), at(3, 1), at(3, 3)],
+            vec![],
+            vec![at(3, 2), at(5, 0)],
+        ]);
+        let keys: Vec<(u64, u32)> = merged.iter().map(|up| (up.ts, up.seq)).collect();
+        assert_eq!(vec![(1, 0), (2, 0), (3, 1), (3, 2), (3, 2), (3, 3), (4, 0), (5, 0)], keys);
+    }
+
+    #[test]
+    fn should_read_files_in_ts_order() {
+        let folder = env::temp_dir().join("tectonic-read-range-test");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir(&folder).unwrap();
+        let folder = folder.to_str().unwrap().to_owned();
+        let fnames: Vec<String> = (0..3).map(|i| format!("{}/{}.dtf", folder, i)).collect();
+        dtf::encode(&fnames[0], "bnc_eth_btc", &[up(1), up(4), up(7)]).unwrap();
+        dtf::encode(&fnames[1], "bnc_eth_btc", &[up(2), up(5), up(8)]).unwrap();
+        dtf::encode(&fnames[2], "bnc_eth_btc", &[up(9), up(10)]).unwrap();
+
+        let ts = |ups: Vec<dtf::Update>| ups.iter().map(|up| up.ts).collect::<Vec<_>>();
+        assert_eq!(vec![2, 4, 5, 7, 8, 9], ts(read_files_for_range_seq(&fnames, 2, 9).unwrap()));
+        assert_eq!(vec![2, 4, 5, 7, 8, 9], ts(read_files_for_range(&fnames, 2, 9).unwrap()));
+        // the files one after another, by min_ts
+        assert_eq!(
+            vec![4, 7, 2, 5, 8, 9],
+            ts(scan_files_for_range(&folder, "bnc_eth_btc", 2, 9).unwrap())
+        );
+    }
+}